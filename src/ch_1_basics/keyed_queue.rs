@@ -0,0 +1,230 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+};
+
+use super::work_queue::Closed;
+
+/*
+  A queue where consumers only want some of the items: the apples and oranges from another_condvar_usage.
+
+  A single condvar for everything would wake the apple consumers for every orange (and then they go back to sleep).
+  So every key gets its own lane: its own VecDeque and its own Condvar, and push only notifies the lane it pushed to.
+  - The lanes are created on first use, by push or by a consumer waiting for that key.
+  - The condvars are behind an Arc, so a waiter can hold on to one while handing its MutexGuard to wait().
+  - pop_matching is for consumers that can't be described by a single key. They wait on the shared `any` condvar,
+    which push only notifies when `matching_waiters` says someone is there.
+*/
+
+struct Lane<T> {
+    items: VecDeque<T>,
+    not_empty: Arc<Condvar>,
+}
+
+struct State<K, T> {
+    lanes: HashMap<K, Lane<T>>,
+    matching_waiters: usize,
+    closed: bool,
+}
+
+pub struct KeyedQueue<K, T> {
+    state: Mutex<State<K, T>>,
+    any: Condvar,
+}
+
+impl<K: Eq + Hash + Clone, T> Default for KeyedQueue<K, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq + Hash + Clone, T> KeyedQueue<K, T> {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                lanes: HashMap::new(),
+                matching_waiters: 0,
+                closed: false,
+            }),
+            any: Condvar::new(),
+        }
+    }
+
+    pub fn push(&self, key: K, item: T) -> Result<(), Closed<T>> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(Closed(item));
+        }
+        let lane = lane(&mut state, key);
+        lane.items.push_back(item);
+        // only someone waiting for this key can use it
+        lane.not_empty.notify_one();
+        if state.matching_waiters > 0 {
+            // we can't tell whose predicate it matches
+            self.any.notify_all();
+        }
+        Ok(())
+    }
+
+    // Blocks until there's an item for `key`, or returns None once the queue is closed and has none left.
+    pub fn pop(&self, key: &K) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        let not_empty = lane(&mut state, key.clone()).not_empty.clone();
+        loop {
+            if let Some(item) = state.lanes.get_mut(key).unwrap().items.pop_front() {
+                return Some(item);
+            }
+            if state.closed {
+                return None;
+            }
+            state = not_empty.wait(state).unwrap();
+        }
+    }
+
+    // Blocks until some item matches `predicate`, oldest first within each key.
+    pub fn pop_matching(&self, mut predicate: impl FnMut(&K, &T) -> bool) -> Option<(K, T)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let found = state.lanes.iter_mut().find_map(|(key, lane)| {
+                let index = lane.items.iter().position(|item| predicate(key, item))?;
+                Some((key.clone(), lane.items.remove(index).unwrap()))
+            });
+            if found.is_some() || state.closed {
+                return found;
+            }
+            state.matching_waiters += 1;
+            state = self.any.wait(state).unwrap();
+            state.matching_waiters -= 1;
+        }
+    }
+
+    // No more pushes. Every waiting consumer gets what's left for it, then None.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        for lane in state.lanes.values() {
+            lane.not_empty.notify_all();
+        }
+        self.any.notify_all();
+    }
+
+    pub fn len(&self, key: &K) -> usize {
+        let state = self.state.lock().unwrap();
+        state.lanes.get(key).map_or(0, |lane| lane.items.len())
+    }
+}
+
+fn lane<'a, K: Eq + Hash, T>(
+    state: &'a mut MutexGuard<'_, State<K, T>>,
+    key: K,
+) -> &'a mut Lane<T> {
+    state.lanes.entry(key).or_insert_with(|| Lane {
+        items: VecDeque::new(),
+        not_empty: Arc::new(Condvar::new()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn each_consumer_only_gets_its_own_key() {
+        let queue = KeyedQueue::new();
+        let eaten = Mutex::new(Vec::new());
+        thread::scope(|s| {
+            for key in ["apple", "orange"] {
+                let (queue, eaten) = (&queue, &eaten);
+                s.spawn(move || {
+                    while let Some(item) = queue.pop(&key) {
+                        eaten.lock().unwrap().push((key, item));
+                    }
+                });
+            }
+            for i in 0..300 {
+                let key = if i % 3 == 0 { "apple" } else { "orange" };
+                queue.push(key, (key, i)).unwrap();
+            }
+            queue.close();
+        });
+        let eaten = eaten.into_inner().unwrap();
+        assert_eq!(eaten.len(), 300);
+        for (key, (pushed_as, _)) in &eaten {
+            assert_eq!(key, pushed_as);
+        }
+        // and in order within a key
+        let apples: Vec<_> = eaten
+            .iter()
+            .filter(|(k, _)| *k == "apple")
+            .map(|(_, (_, i))| *i)
+            .collect();
+        assert!(apples.is_sorted());
+        assert_eq!(apples.len(), 100);
+    }
+
+    #[test]
+    fn pop_matching_takes_the_oldest_match() {
+        let queue = KeyedQueue::new();
+        for i in 0..10 {
+            queue.push(i % 2 == 0, i).unwrap();
+        }
+        assert_eq!(
+            queue.pop_matching(|&even, &i| !even && i > 4),
+            Some((false, 5))
+        );
+        assert_eq!(queue.len(&false), 4);
+        assert_eq!(queue.len(&true), 5);
+        assert_eq!(queue.pop(&false), Some(1));
+    }
+
+    #[test]
+    fn pop_matching_waits_for_a_match() {
+        let queue = KeyedQueue::new();
+        thread::scope(|s| {
+            let picky = s.spawn(|| queue.pop_matching(|_, &i| i == 3));
+            while queue.state.lock().unwrap().matching_waiters == 0 {
+                thread::yield_now();
+            }
+            for i in 0..5 {
+                queue.push('k', i).unwrap();
+            }
+            assert_eq!(picky.join().unwrap(), Some(('k', 3)));
+        });
+        assert_eq!(queue.len(&'k'), 4);
+    }
+
+    #[test]
+    fn close_hands_out_the_rest_then_none() {
+        let queue = KeyedQueue::new();
+        queue.push(1, "a").unwrap();
+        queue.close();
+        assert_eq!(queue.push(1, "b"), Err(Closed("b")));
+        assert_eq!(queue.pop(&1), Some("a"));
+        assert_eq!(queue.pop(&1), None);
+        assert_eq!(queue.pop(&2), None);
+        assert_eq!(queue.pop_matching(|_, _| true), None);
+    }
+
+    #[test]
+    fn close_wakes_every_waiter() {
+        let queue = KeyedQueue::<u8, u8>::new();
+        thread::scope(|s| {
+            let by_key = s.spawn(|| queue.pop(&1));
+            let matching = s.spawn(|| queue.pop_matching(|_, _| true));
+            loop {
+                let state = queue.state.lock().unwrap();
+                if state.lanes.contains_key(&1) && state.matching_waiters == 1 {
+                    break;
+                }
+                drop(state);
+                thread::yield_now();
+            }
+            queue.close();
+            assert_eq!(by_key.join().unwrap(), None);
+            assert_eq!(matching.join().unwrap(), None);
+        });
+    }
+}
@@ -1,3 +1,7 @@
+pub mod keyed_queue;
+pub mod send_sync;
+pub mod work_queue;
+
 #[allow(unused)]
 use std::{
     cell::{Cell, RefCell, UnsafeCell},
@@ -56,6 +60,7 @@ pub fn basics() {
 
     t1.join().unwrap();
     t2.join().unwrap();
+    // join hands back the closure's return value (ch_5_channels::oneshot does the same without joining)
     let average = t3.join().unwrap();
 
     println!("average: {average}");
@@ -112,20 +117,24 @@ pub fn ref_cell_usage(v: &RefCell<Vec<i32>>) {
 // UnsafeCell<T>
 // primitive building block that all interior mutability containers are built upon
 // could be meaningfully used in unsafe block
+// verification::race_detector's RaceCell is one that checks the code using it for data races
 
 // https://marabos.nl/atomics/basics.html#thread-safety
 // Send and Sync -> See notes
 
 pub fn send_and_sync() {
     // X is not Sync here because Cell is not Sync
+    #[allow(dead_code)]
     struct X {
         handle: i32,
         not_sync: PhantomData<Cell<()>>,
     }
 
-    let a = Rc::new(123);
+    let _a = Rc::new(123);
     // Rc is not Send, therefore the compiler won't allow the following
-    // thread::spawn(move || dbg!(a));
+    // thread::spawn(move || dbg!(_a));
+    // (its thread safe sibling, with the atomic counters, is in ch_6_arc::our_arc)
+    // send_sync checks all of this at compile time, for every type in the crate
 }
 
 // Mutexes
@@ -136,28 +145,54 @@ pub fn send_and_sync() {
 - unlocking is done by dropping the `MutexGuard` which is returned from the lock()
  */
 
-pub fn mutex_use() {
-    let n = Mutex::new(0);
+// Anything that can be locked like a Mutex, so the same workload can run on std's Mutex and on our own locks.
+pub trait Lock<T>: Sync {
+    type Guard<'a>: DerefMut<Target = T>
+    where
+        Self: 'a;
+
+    fn lock(&self) -> Self::Guard<'_>;
+    fn into_inner(self) -> T;
+}
+
+// A panic while holding the lock poisons a std Mutex. We just unwrap, like everywhere else in here.
+impl<T: Send> Lock<T> for Mutex<T> {
+    type Guard<'a>
+        = MutexGuard<'a, T>
+    where
+        T: 'a;
+
+    fn lock(&self) -> MutexGuard<'_, T> {
+        Mutex::lock(self).unwrap()
+    }
+
+    fn into_inner(self) -> T {
+        Mutex::into_inner(self).unwrap()
+    }
+}
+
+// e.g. mutex_use(Mutex::new(0), ..) or mutex_use(SpinLock::new(0), ..)
+pub fn mutex_use<L: Lock<i32>>(n: L, hold: Duration) {
     thread::scope(|s| {
         for _ in 0..10 {
             s.spawn(|| {
                 println!("thread STARTED id: {:?}", thread::current().id());
                 // because of the mutex, the increments of 100, are single, indivisible atomic operations
-                let mut guard = n.lock().unwrap();
+                let mut guard = n.lock();
 
                 for _ in 0..100 {
                     *guard += 1;
                 }
                 drop(guard);
-                thread::sleep(Duration::from_secs(1));
+                thread::sleep(hold);
                 println!("thread DROPPED id: {:?}", thread::current().id());
             });
         }
     });
 
-    println!("mutex_use n: {}", n.lock().unwrap());
+    println!("mutex_use n: {}", *n.lock());
 
-    assert_eq!(n.into_inner().unwrap(), 1000);
+    assert_eq!(n.into_inner(), 1000);
 }
 
 // https://marabos.nl/atomics/basics.html#lifetime-of-mutexguard
@@ -183,8 +218,8 @@ pub fn mutex_guard_lifetime() {
 
     let item = list.lock().unwrap().pop();
     // The guard is dropped here before the if let statement
-    if let Some(item) = item {
-        // long_process_fn(item)
+    if let Some(_item) = item {
+        // long_process_fn(_item)
     }
 }
 
@@ -192,6 +227,7 @@ pub fn mutex_guard_lifetime() {
 // A mutex only allows exclusive access (&mut T)
 // An RwLock can allow for a shared reference (&T)
 // Essentialy the multi-threaded version of RefCell
+// (our own, writer-preferring one is in ch_9_locks/rwlock.rs)
 
 // https://marabos.nl/atomics/basics.html#waiting
 // Thread Parking
@@ -203,44 +239,57 @@ pub fn mutex_guard_lifetime() {
     - threads can have "spurious wakeups"
     - A call to "unpark" does not get lost, and rather causes the next "park" request to "unpark", but "unpark" requests do not stack.
  */
-pub fn thread_parking_queue() {
+pub fn thread_parking_queue(interval: Duration, items: isize) {
     let queue: Mutex<VecDeque<isize>> = Mutex::new(VecDeque::new());
+    let done = AtomicBool::new(false);
 
     thread::scope(|s| {
         // consuming thread
         let t = s.spawn(|| loop {
+            // check before popping: an empty queue after `done` really is the end
+            let done = done.load(Acquire);
             let item = queue.lock().unwrap().pop_front();
             if let Some(item) = item {
                 dbg!(item);
+            } else if done {
+                break;
             } else {
                 thread::park();
             }
         });
         // producing thread
-        for i in 0.. {
+        for i in 0..items {
             queue.lock().unwrap().push_back(i);
             t.thread().unpark();
-            thread::sleep(Duration::from_secs(1));
+            thread::sleep(interval);
         }
+        done.store(true, Release);
+        t.thread().unpark();
     })
 }
 
 // The above example begins to break down with multiple consumers
 // the producer thread has no way of knowing which consumer is actually waiting and which should be woken up.
 // a more sophisticated approach is required
+// (work_queue::WorkQueue keeps a list of the parked consumers, and wakes exactly one of them per item)
+// Both queues here are also unbounded: a producer faster than its consumer grows them forever.
+// ch_5_channels::bounded makes the producer wait instead.
 
 // https://marabos.nl/atomics/basics.html#condvar
 
-pub fn condvar_usage() {
-    let queue: Mutex<VecDeque<isize>> = Mutex::new(VecDeque::new());
+pub fn condvar_usage(interval: Duration, items: isize) {
+    // `.1` is set once the producer is done
+    let queue: Mutex<(VecDeque<isize>, bool)> = Mutex::new((VecDeque::new(), false));
     let not_empty = Condvar::new();
 
     thread::scope(|s| {
         s.spawn(|| loop {
             let mut q = queue.lock().unwrap();
             let item = loop {
-                if let Some(item) = q.pop_front() {
+                if let Some(item) = q.0.pop_front() {
                     break item;
+                } else if q.1 {
+                    return;
                 } else {
                     q = not_empty.wait(q).unwrap();
                 }
@@ -249,56 +298,60 @@ pub fn condvar_usage() {
             dbg!(item);
         });
 
-        for i in 0..25 {
-            queue.lock().unwrap().push_back(i);
+        for i in 0..items {
+            queue.lock().unwrap().0.push_back(i);
             not_empty.notify_one();
-            thread::sleep(Duration::from_secs(1));
+            thread::sleep(interval);
         }
+        queue.lock().unwrap().1 = true;
+        not_empty.notify_one();
     })
 }
 
-// NOTE This probably doesn't make sense. Better to have a hashmap of things, or something else.
-pub fn another_condvar_usage() {
-    let queue: Mutex<VecDeque<Foo>> = Mutex::new(VecDeque::new());
-
+// One queue, two kinds of consumers. Each kind only gets woken for its own fruit:
+// keyed_queue::KeyedQueue keeps a separate VecDeque and Condvar per key.
+pub fn another_condvar_usage(interval: Duration, items: usize) {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
     enum Foo {
-        Apple(String),
-        Orange(String),
+        Apple,
+        Orange,
     }
-    let containes_orange = Condvar::new();
-    let contains_apple = Condvar::new();
+    let queue = keyed_queue::KeyedQueue::new();
+    let eaten = Mutex::new(Vec::new());
 
     thread::scope(|s| {
-        // Orange thread
-        s.spawn(|| loop {
-            let mut q = queue.lock().unwrap();
-            for item in q.iter_mut() {}
-            // loop over the queue and pop all the oranges
-            // NOTE This probably doesn't make sense. Better to have a hashmap of things, or something else.
-        });
+        for fruit in [Foo::Orange, Foo::Apple] {
+            let (queue, eaten) = (&queue, &eaten);
+            s.spawn(move || {
+                while let Some(item) = queue.pop(&fruit) {
+                    println!("{fruit:?} consumer: {item}");
+                    eaten.lock().unwrap().push((fruit, item));
+                }
+            });
+        }
 
-        // Apple thread
-        s.spawn(|| loop {
-            let mut q = queue.lock().unwrap();
-            // loop over the queue and pop all the apples
-        });
+        for i in 0..items {
+            if i % 3 == 0 {
+                queue.push(Foo::Apple, format!("Yum apple! - {i}")).unwrap();
+            } else {
+                queue.push(Foo::Orange, format!("Orangy! - {i}")).unwrap();
+            }
+            thread::sleep(interval);
+        }
+        queue.close();
     });
 
-    for i in 0..25 {
-        if i % 3 == 0 {
-            queue
-                .lock()
-                .unwrap()
-                .push_back(Foo::Apple(format!("Yum apple! - {}", i).to_string()));
-            contains_apple.notify_one();
-            thread::sleep(Duration::from_secs(1));
-        } else {
-            queue
-                .lock()
-                .unwrap()
-                .push_back(Foo::Apple(format!("Orangy! - {}", i).to_string()));
-            containes_orange.notify_one();
-            thread::sleep(Duration::from_secs(1));
-        }
+    let eaten = eaten.into_inner().unwrap();
+    println!("{} of {items} fruits eaten", eaten.len());
+
+    // and a consumer that's picky beyond the key
+    let queue = keyed_queue::KeyedQueue::new();
+    for i in 0..10 {
+        queue.push(i % 2 == 0, i).unwrap();
     }
+    let picked = queue.pop_matching(|&even, &i| !even && i > 4);
+    println!(
+        "the first odd number over 4: {picked:?}, {} odd ones left",
+        queue.len(&false)
+    );
 }
@@ -0,0 +1,209 @@
+//! Send and Sync for every synchronization type the crate exports, checked at compile time
+//! by the assert_impl! and assert_not_impl! lines below.
+//!
+//! Those check the types, not the code using them. The other half is that misuse has to fail to compile.
+//! A static assertion can't check that (the whole crate would fail), so these are compile_fail doc tests,
+//! which `cargo test` runs offline with the rustc that builds the crate.
+//! The crate's own types have theirs on the types: our_arc::Arc, SpinLock and oneshot::channel.
+//!
+//! A compile_fail test only checks the error code. E0277 is any unsatisfied trait bound,
+//! so one failing for some other missing trait would pass too. Each snippet is kept to a single bound for that reason.
+//!
+//! ```compile_fail,E0277
+//! let a = std::rc::Rc::new(123_i32);
+//! std::thread::spawn(move || dbg!(a));
+//! ```
+//!
+//! ```compile_fail,E0277
+//! let c = std::cell::Cell::new(1_i32);
+//! std::thread::scope(|s| {
+//!     s.spawn(|| c.set(2));
+//! });
+//! ```
+//!
+//! ```compile_fail,E0277
+//! struct X {
+//!     handle: i32,
+//!     not_sync: std::marker::PhantomData<std::cell::Cell<()>>,
+//! }
+//! let x = X { handle: 1, not_sync: std::marker::PhantomData };
+//! std::thread::scope(|s| {
+//!     s.spawn(|| {
+//!         let x = &x;
+//!         x.handle
+//!     });
+//! });
+//! ```
+//!
+//! Must compile, or the failures could be failing for some other reason (like not finding the crate).
+//!
+//! ```
+//! use atomics_and_locks::{ch_4_spin_lock::SpinLock, ch_6_arc::our_arc::Arc};
+//! let lock = Arc::new(SpinLock::new(std::cell::Cell::new(1_i32)));
+//! std::thread::spawn(move || lock.lock().set(2)).join().unwrap();
+//! ```
+
+#[cfg(target_os = "linux")]
+use crate::ch_9_locks::{
+    condvar::Condvar,
+    mutex::{Mutex, MutexGuard},
+    rwlock::{ReadGuard, RwLock, UpgradableReadGuard, WriteGuard},
+};
+use crate::verification::{explorer, race_detector};
+use crate::{
+    ch_1_basics::{keyed_queue::KeyedQueue, work_queue::WorkQueue},
+    ch_2_atomics::{
+        cancellation::CancellationToken,
+        clock::{Clock, RealClock, Running, VirtualClock},
+        histogram::{Histogram, Percentiles},
+        id_allocator::IdAllocator,
+        id_recycler::IdRecycler,
+        lazy_cell::{Blocking, LazyCell, Racy},
+        progress::{ProgressTracker, Reporter},
+        race_once_box::RaceOnceBox,
+        statistics::StatsCollector,
+    },
+    ch_4_spin_lock::{Guard, SpinLock},
+    ch_5_channels::{bounded, oneshot},
+    ch_6_arc::our_arc::{Arc, Weak},
+};
+use std::{cell::Cell, rc::Rc, sync::atomic::AtomicU32};
+
+// https://marabos.nl/atomics/basics.html#thread-safety
+
+/*
+  The send_and_sync notes, checked by the compiler for every synchronization type the crate exports.
+
+  assert_impl! and assert_not_impl! expand to closures that are never called, only type checked,
+  so a wrong assertion is a compile error in this file, and nothing happens at runtime.
+  - assert_impl!(T: Send) calls a function with a `T: Send` bound.
+  - assert_not_impl!(T: Send) can't do that the other way around (there are no negative bounds),
+    so it uses the trick from the static_assertions crate: a trait with two blanket impls, one for every type
+    and one for types that are Send. Asking for "the" impl is then ambiguous, and an error, exactly when T is Send.
+
+  Generic types are checked with a few stand-in Ts: i32 (Send + Sync), Cell<i32> (Send, !Sync) and Rc<i32> (neither).
+*/
+
+macro_rules! assert_impl {
+    ($t:ty: $($tr:ident),+) => {
+        const _: fn() = || {
+            fn check<T: ?Sized $(+ $tr)+>() {}
+            check::<$t>();
+        };
+    };
+}
+
+macro_rules! assert_not_impl {
+    ($t:ty: $($tr:ident),+) => {
+        $(
+            const _: fn() = || {
+                trait AmbiguousIfImpl<A> {
+                    fn some_item() {}
+                }
+                impl<T: ?Sized> AmbiguousIfImpl<()> for T {}
+                #[allow(dead_code)]
+                struct Invalid;
+                impl<T: ?Sized + $tr> AmbiguousIfImpl<Invalid> for T {}
+                let _ = <$t as AmbiguousIfImpl<_>>::some_item;
+            };
+        )+
+    };
+}
+
+// the std types from the notes
+assert_impl!(std::sync::Arc<i32>: Send, Sync);
+assert_not_impl!(Rc<i32>: Send, Sync);
+assert_impl!(Cell<i32>: Send);
+assert_not_impl!(Cell<i32>: Sync);
+
+// ch_1_basics
+assert_impl!(WorkQueue<Cell<i32>>: Send, Sync);
+assert_not_impl!(WorkQueue<Rc<i32>>: Send, Sync);
+assert_impl!(KeyedQueue<u8, Cell<i32>>: Send, Sync);
+assert_not_impl!(KeyedQueue<u8, Rc<i32>>: Send, Sync);
+
+// ch_2_atomics
+assert_impl!(ProgressTracker: Send, Sync);
+assert_impl!(Reporter: Send, Sync);
+assert_impl!(StatsCollector: Send, Sync);
+assert_impl!(Histogram: Send, Sync);
+assert_impl!(Percentiles: Send, Sync);
+// every demo shares one clock between its threads
+assert_impl!(RealClock: Send, Sync);
+assert_impl!(VirtualClock: Send, Sync);
+assert_impl!(dyn Clock: Send, Sync);
+// moved into the thread it counts, never shared: the boxed FnOnce only has to be Send
+assert_impl!(Running: Send);
+assert_not_impl!(Running: Sync);
+assert_impl!(IdAllocator<AtomicU32>: Send, Sync);
+assert_impl!(IdRecycler: Send, Sync);
+assert_impl!(CancellationToken: Send, Sync);
+assert_impl!(RaceOnceBox<i32>: Send, Sync);
+// the value is handed out as &T to every thread, so it has to be Sync itself
+assert_impl!(RaceOnceBox<Cell<i32>>: Send);
+assert_not_impl!(RaceOnceBox<Cell<i32>>: Sync);
+assert_impl!(LazyCell<i32, Blocking>: Send, Sync);
+assert_impl!(LazyCell<i32, Racy>: Send, Sync);
+assert_not_impl!(LazyCell<Cell<i32>>: Sync);
+assert_not_impl!(LazyCell<Rc<i32>>: Send, Sync);
+
+// ch_4_spin_lock: like std's Mutex, only one thread at a time gets to the T, so Send is enough for Sync
+assert_impl!(SpinLock<Cell<i32>>: Send, Sync);
+assert_not_impl!(SpinLock<Rc<i32>>: Send, Sync);
+assert_impl!(Guard<'static, i32>: Send, Sync);
+// sharing the guard shares the &T
+assert_not_impl!(Guard<'static, Cell<i32>>: Sync);
+
+// ch_5_channels: values move from one thread to another, but are never shared
+assert_impl!(oneshot::Sender<Cell<i32>>: Send, Sync);
+assert_impl!(oneshot::Receiver<Cell<i32>>: Send, Sync);
+assert_not_impl!(oneshot::Sender<Rc<i32>>: Send, Sync);
+assert_not_impl!(oneshot::Receiver<Rc<i32>>: Send, Sync);
+assert_impl!(bounded::Sender<Cell<i32>>: Send, Sync);
+assert_impl!(bounded::Receiver<Cell<i32>>: Send, Sync);
+assert_not_impl!(bounded::Sender<Rc<i32>>: Send, Sync);
+assert_not_impl!(bounded::Receiver<Rc<i32>>: Send, Sync);
+
+// ch_6_arc: every clone hands out &T, so even sending one needs T: Sync
+assert_impl!(Arc<i32>: Send, Sync);
+assert_impl!(Weak<i32>: Send, Sync);
+assert_not_impl!(Arc<Cell<i32>>: Send, Sync);
+assert_not_impl!(Weak<Cell<i32>>: Send, Sync);
+
+// ch_9_locks
+#[cfg(target_os = "linux")]
+const _: () = {
+    assert_impl!(Mutex<Cell<i32>>: Send, Sync);
+    assert_not_impl!(Mutex<Rc<i32>>: Send, Sync);
+    assert_impl!(MutexGuard<'static, i32>: Send, Sync);
+    assert_not_impl!(MutexGuard<'static, Cell<i32>>: Sync);
+    assert_impl!(Condvar: Send, Sync);
+    // readers share the T
+    assert_impl!(RwLock<i32>: Send, Sync);
+    assert_not_impl!(RwLock<Cell<i32>>: Sync);
+    assert_impl!(ReadGuard<'static, i32>: Send, Sync);
+    assert_impl!(UpgradableReadGuard<'static, i32>: Send, Sync);
+    assert_impl!(WriteGuard<'static, i32>: Send, Sync);
+};
+
+// verification: the same rules as the types they stand in for
+assert_impl!(explorer::Mutex<Cell<i32>>: Send, Sync);
+assert_not_impl!(explorer::Mutex<Rc<i32>>: Send, Sync);
+assert_impl!(explorer::MutexGuard<'static, i32>: Sync);
+assert_not_impl!(explorer::MutexGuard<'static, Cell<i32>>: Sync);
+assert_impl!(explorer::AtomicU32: Send, Sync);
+assert_impl!(explorer::Once: Send, Sync);
+assert_impl!(race_detector::Mutex<Cell<i32>>: Send, Sync);
+assert_not_impl!(race_detector::Mutex<Rc<i32>>: Send, Sync);
+assert_impl!(race_detector::MutexGuard<'static, i32>: Sync);
+assert_not_impl!(race_detector::MutexGuard<'static, Cell<i32>>: Sync);
+// like UnsafeCell: it's the lock built on top that makes it Sync
+assert_impl!(race_detector::RaceCell<i32>: Send);
+assert_not_impl!(race_detector::RaceCell<i32>: Sync);
+assert_not_impl!(race_detector::RaceCell<Rc<i32>>: Send);
+assert_impl!(race_detector::AtomicBool: Send, Sync);
+assert_impl!(race_detector::AtomicU32: Send, Sync);
+assert_impl!(race_detector::AtomicU64: Send, Sync);
+assert_impl!(race_detector::AtomicUsize: Send, Sync);
+// like std's, whatever it points to
+assert_impl!(race_detector::AtomicPtr<Rc<i32>>: Send, Sync);
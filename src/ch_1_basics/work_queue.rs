@@ -0,0 +1,328 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+/*
+  thread_parking_queue with any number of consumers.
+
+  The problem with more than one consumer is that the producer doesn't know which of them is parked.
+  So here every consumer that finds the queue empty puts itself on a `waiters` list (under the same mutex as the items),
+  and push takes exactly one waiter off that list and unparks it. Nobody on the list: no unpark at all.
+  - A waiter is marked `woken` when it's taken off the list, so after park returns it knows whether
+    it was woken on purpose, or spuriously and is still on the list.
+  - A woken consumer can still find the queue empty, when another consumer that wasn't waiting got to the item first.
+    It just goes back on the list. No item is ever lost, and no push wakes more than one thread.
+  - close() wakes every waiter, and pop returns None once the queue is closed *and* empty,
+    so the consumers finish the remaining work before they stop.
+*/
+
+struct Waiter {
+    thread: Thread,
+    woken: AtomicBool,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    waiters: VecDeque<Arc<Waiter>>,
+    closed: bool,
+}
+
+pub struct WorkQueue<T> {
+    state: Mutex<State<T>>,
+    unparks: AtomicU64,
+}
+
+// push on a closed queue hands the item back
+#[derive(Debug, PartialEq, Eq)]
+pub struct Closed<T>(pub T);
+
+impl<T> fmt::Display for Closed<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("queue closed")
+    }
+}
+
+impl<T: fmt::Debug> Error for Closed<T> {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PopTimeoutError {
+    Timeout,
+    Closed,
+}
+
+impl fmt::Display for PopTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PopTimeoutError::Timeout => f.write_str("timed out waiting for an item"),
+            PopTimeoutError::Closed => f.write_str("queue closed"),
+        }
+    }
+}
+
+impl Error for PopTimeoutError {}
+
+impl<T> Default for WorkQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> WorkQueue<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                items: VecDeque::new(),
+                waiters: VecDeque::new(),
+                closed: false,
+            }),
+            unparks: AtomicU64::new(0),
+        }
+    }
+
+    pub fn push(&self, item: T) -> Result<(), Closed<T>> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(Closed(item));
+        }
+        state.items.push_back(item);
+        let waiter = state.waiters.pop_front();
+        if let Some(waiter) = &waiter {
+            waiter.woken.store(true, Ordering::Relaxed);
+        }
+        // unpark after unlocking, so the woken thread doesn't immediately block on the mutex
+        drop(state);
+        if let Some(waiter) = waiter {
+            self.unparks.fetch_add(1, Ordering::Relaxed);
+            waiter.thread.unpark();
+        }
+        Ok(())
+    }
+
+    // Blocks until there's an item, or returns None once the queue is closed and empty.
+    pub fn pop(&self) -> Option<T> {
+        self.pop_until(None).ok()
+    }
+
+    pub fn pop_timeout(&self, timeout: Duration) -> Result<T, PopTimeoutError> {
+        self.pop_until(Some(Instant::now() + timeout))
+    }
+
+    pub fn try_pop(&self) -> Option<T> {
+        self.state.lock().unwrap().items.pop_front()
+    }
+
+    // No more pushes. The consumers get what's left, then None.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        let waiters = std::mem::take(&mut state.waiters);
+        for waiter in &waiters {
+            waiter.woken.store(true, Ordering::Relaxed);
+        }
+        drop(state);
+        for waiter in waiters {
+            self.unparks.fetch_add(1, Ordering::Relaxed);
+            waiter.thread.unpark();
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // how many consumers push and close have unparked so far
+    pub fn unparks(&self) -> u64 {
+        self.unparks.load(Ordering::Relaxed)
+    }
+
+    fn pop_until(&self, deadline: Option<Instant>) -> Result<T, PopTimeoutError> {
+        let me = Arc::new(Waiter {
+            thread: thread::current(),
+            woken: AtomicBool::new(false),
+        });
+        let mut listed = false;
+        loop {
+            let mut state = self.state.lock().unwrap();
+            if me.woken.swap(false, Ordering::Relaxed) {
+                // push or close took us off the list
+                listed = false;
+            }
+
+            let result = if let Some(item) = state.items.pop_front() {
+                Ok(item)
+            } else if state.closed {
+                Err(PopTimeoutError::Closed)
+            } else if deadline.is_some_and(|d| Instant::now() >= d) {
+                Err(PopTimeoutError::Timeout)
+            } else {
+                if !listed {
+                    state.waiters.push_back(me.clone());
+                    listed = true;
+                }
+                drop(state);
+                match deadline {
+                    None => thread::park(),
+                    Some(d) => thread::park_timeout(d.saturating_duration_since(Instant::now())),
+                }
+                continue;
+            };
+
+            // leaving without being woken (spurious wakeup, or a timeout): get off the list,
+            // so a push doesn't waste its unpark on us
+            if listed {
+                state.waiters.retain(|w| !Arc::ptr_eq(w, &me));
+            }
+            return result;
+        }
+    }
+}
+
+/*
+  `consumers` threads pop until the queue is closed, while the producer pushes `items` with a pause now and then,
+  so the consumers actually run out of work and park.
+  Returns how many items the consumers popped, and their sum.
+*/
+fn consume(
+    queue: &WorkQueue<usize>,
+    consumers: usize,
+    items: usize,
+    interval: Duration,
+) -> (usize, usize) {
+    let popped = AtomicUsize::new(0);
+    let sum = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for _ in 0..consumers {
+            s.spawn(|| {
+                while let Some(item) = queue.pop() {
+                    popped.fetch_add(1, Ordering::Relaxed);
+                    sum.fetch_add(item, Ordering::Relaxed);
+                }
+            });
+        }
+
+        for i in 0..items {
+            queue.push(i).unwrap();
+            if i % 10 == 9 {
+                thread::sleep(interval);
+            }
+        }
+        queue.close();
+    });
+    (popped.into_inner(), sum.into_inner())
+}
+
+pub fn work_queue(consumers: usize, items: usize, interval: Duration) {
+    let queue = WorkQueue::new();
+    println!(
+        "pop on an empty queue: {:?}",
+        queue.pop_timeout(interval).map_err(|e| e.to_string())
+    );
+
+    let (popped, sum) = consume(&queue, consumers, items, interval);
+    println!(
+        "{popped} of {items} items over {consumers} consumers (sum {sum}), {} unparks",
+        queue.unparks()
+    );
+
+    println!(
+        "push after close: {:?}",
+        queue.push(0).map_err(|e| e.to_string())
+    );
+    println!(
+        "pop after close:  {:?}",
+        queue.pop_timeout(interval).map_err(|e| e.to_string())
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_item_popped_exactly_once() {
+        for consumers in [1, 4] {
+            let queue = WorkQueue::new();
+            let items = 200;
+            let (popped, sum) = consume(&queue, consumers, items, Duration::from_millis(1));
+            assert_eq!(popped, items);
+            assert_eq!(sum, items * (items - 1) / 2);
+            // one unpark per push at most, plus one for every consumer still waiting at close
+            assert!(queue.unparks() <= (items + consumers) as u64);
+            assert!(queue.is_empty());
+        }
+    }
+
+    #[test]
+    fn pop_timeout_on_an_empty_queue() {
+        let queue = WorkQueue::<u32>::new();
+        let start = Instant::now();
+        assert_eq!(
+            queue.pop_timeout(Duration::from_millis(20)),
+            Err(PopTimeoutError::Timeout)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        queue.push(1).unwrap();
+        assert_eq!(queue.pop_timeout(Duration::from_millis(20)), Ok(1));
+        // timing out took it off the waiters list, so that push didn't unpark anyone
+        assert_eq!(queue.unparks(), 0);
+    }
+
+    #[test]
+    fn no_waiters_no_unparks() {
+        let queue = WorkQueue::new();
+        for i in 0..10 {
+            queue.push(i).unwrap();
+        }
+        assert_eq!(queue.len(), 10);
+        assert_eq!(queue.try_pop(), Some(0));
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.unparks(), 0);
+    }
+
+    #[test]
+    fn close_hands_out_the_rest_then_none() {
+        let queue = WorkQueue::new();
+        queue.push(1).unwrap();
+        queue.close();
+        assert!(queue.is_closed());
+        assert_eq!(queue.push(2), Err(Closed(2)));
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.pop(), None);
+        assert_eq!(
+            queue.pop_timeout(Duration::from_secs(1)),
+            Err(PopTimeoutError::Closed)
+        );
+    }
+
+    #[test]
+    fn close_wakes_every_waiter() {
+        let queue = WorkQueue::<u32>::new();
+        thread::scope(|s| {
+            let consumers: Vec<_> = (0..3).map(|_| s.spawn(|| queue.pop())).collect();
+            while queue.state.lock().unwrap().waiters.len() < 3 {
+                thread::yield_now();
+            }
+            queue.close();
+            for consumer in consumers {
+                assert_eq!(consumer.join().unwrap(), None);
+            }
+        });
+        assert_eq!(queue.unparks(), 3);
+    }
+}
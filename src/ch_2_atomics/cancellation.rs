@@ -0,0 +1,217 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+use super::clock::{Clock, RealClock};
+
+/*
+  The STOP flag from stop_flag, as a value instead of a static, so it can be created per run and per worker.
+
+  - is_cancelled() is a single load, just like checking STOP.
+  - cancel() sets the flag, wakes every thread in wait_cancelled, runs the registered callbacks,
+    and cancels every child token. Cancelling a child doesn't affect its parent.
+  - wait_cancelled(timeout) parks instead of sleeping,
+    so a worker waiting between iterations wakes as soon as it's cancelled instead of up to a second later.
+
+  Waiting and waking go through the token's Clock, which its children share.
+
+  Clones share the same flag. Only the slow paths (waiting, cancelling, registering) take the Mutex.
+*/
+#[derive(Clone)]
+pub struct CancellationToken {
+    node: Arc<Node>,
+}
+
+struct Node {
+    cancelled: AtomicBool,
+    clock: Arc<dyn Clock>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    waiters: Vec<Thread>,
+    callbacks: Vec<Box<dyn FnOnce() + Send>>,
+    // Weak, so dropping a child token doesn't leave it hanging around in its parent
+    children: Vec<Weak<Node>>,
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::with_clock(Arc::new(RealClock))
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            node: Arc::new(Node {
+                cancelled: AtomicBool::new(false),
+                clock,
+                state: Mutex::new(State::default()),
+            }),
+        }
+    }
+
+    // A token that is cancelled along with this one, but can also be cancelled on its own.
+    pub fn child(&self) -> CancellationToken {
+        let child = CancellationToken::with_clock(self.node.clock.clone());
+        let mut state = self.node.state.lock().unwrap();
+        // checked under the lock, so a concurrent cancel() either sees the child or we see the flag
+        if self.is_cancelled() {
+            drop(state);
+            child.cancel();
+        } else {
+            state.children.retain(|c| c.strong_count() > 0);
+            state.children.push(Arc::downgrade(&child.node));
+        }
+        child
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        // Acquire pairs with the Release in cancel(), so anything done before cancelling is visible
+        self.node.cancelled.load(Ordering::Acquire)
+    }
+
+    pub fn cancel(&self) {
+        Node::cancel(&self.node);
+    }
+
+    // Runs `f` once the token is cancelled, on the thread that cancels it.
+    // Runs it right away if the token is already cancelled.
+    pub fn on_cancel(&self, f: impl FnOnce() + Send + 'static) {
+        let mut state = self.node.state.lock().unwrap();
+        if self.is_cancelled() {
+            drop(state);
+            f();
+        } else {
+            state.callbacks.push(Box::new(f));
+        }
+    }
+
+    // Parks until the token is cancelled or `timeout` has passed. Returns whether it was cancelled.
+    // A timeout too big to add to the current time (e.g. Duration::MAX) waits for the cancel alone.
+    pub fn wait_cancelled(&self, timeout: Duration) -> bool {
+        let clock = &self.node.clock;
+        let deadline = clock.now().checked_add(timeout);
+        let me = thread::current();
+        {
+            let mut state = self.node.state.lock().unwrap();
+            if self.is_cancelled() {
+                return true;
+            }
+            state.waiters.push(me.clone());
+        }
+
+        // park_timeout can wake up spuriously, so keep going until cancelled or out of time
+        while !self.is_cancelled() {
+            let Some(deadline) = deadline else {
+                clock.park();
+                continue;
+            };
+            let now = clock.now();
+            if now >= deadline {
+                break;
+            }
+            clock.park_timeout(deadline - now);
+        }
+
+        // if we timed out we're still on the list. Take ourselves off, so it doesn't grow with every wait
+        self.node
+            .state
+            .lock()
+            .unwrap()
+            .waiters
+            .retain(|t| t.id() != me.id());
+        self.is_cancelled()
+    }
+}
+
+impl Node {
+    fn cancel(node: &Arc<Node>) {
+        let state = {
+            let mut state = node.state.lock().unwrap();
+            // only the first cancel does the waking
+            if node.cancelled.swap(true, Ordering::Release) {
+                return;
+            }
+            std::mem::take(&mut *state)
+        };
+
+        for waiter in state.waiters {
+            node.clock.unpark(&waiter);
+        }
+        // outside the lock, so a callback can use the token itself
+        for callback in state.callbacks {
+            callback();
+        }
+        for child in state.children.iter().filter_map(Weak::upgrade) {
+            Node::cancel(&child);
+        }
+    }
+}
+
+/*
+  A parent token with a few workers, each waiting on its own child token.
+  One child is cancelled on its own first, then the parent takes the rest down with it.
+*/
+pub fn cancellation_demo(threads: usize, interval: Duration) {
+    let parent = CancellationToken::new();
+    let callbacks = Arc::new(AtomicUsize::new(0));
+    let children: Vec<CancellationToken> = (0..threads.max(2)).map(|_| parent.child()).collect();
+    for child in &children {
+        let callbacks = callbacks.clone();
+        child.on_cancel(move || {
+            callbacks.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    // nothing is cancelled yet, so this times out
+    assert!(!children[0].wait_cancelled(interval / 10));
+
+    thread::scope(|s| {
+        let workers: Vec<_> = children
+            .iter()
+            .enumerate()
+            .map(|(i, child)| {
+                s.spawn(move || {
+                    let start = Instant::now();
+                    // no timeout at all, so returning means we were woken
+                    assert!(child.wait_cancelled(Duration::MAX));
+                    println!("worker {i} woke after {:?}", start.elapsed());
+                })
+            })
+            .collect();
+
+        thread::sleep(interval);
+        children[0].cancel();
+        assert!(
+            !parent.is_cancelled(),
+            "cancelling a child cancelled its parent"
+        );
+
+        thread::sleep(interval);
+        parent.cancel();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+    });
+
+    assert_eq!(callbacks.load(Ordering::Relaxed), children.len());
+    // cancelled tokens make cancelled children, and run late callbacks straight away
+    assert!(parent.child().is_cancelled());
+    let late = Arc::new(AtomicBool::new(false));
+    let flag = late.clone();
+    parent.on_cancel(move || flag.store(true, Ordering::Relaxed));
+    assert!(late.load(Ordering::Relaxed));
+    println!("every child cancelled, every callback ran once");
+}
@@ -0,0 +1,463 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::Cursor,
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle, Scope, ScopedJoinHandle, Thread, ThreadId},
+    time::{Duration, Instant},
+};
+
+use super::{console, load_and_store, statistics};
+
+/*
+  Everything in the time based demos that reads the time or waits for it goes through a Clock,
+  so they can run on a VirtualClock instead of the real one.
+
+  - RealClock is Instant::now, thread::sleep and thread::park_timeout.
+  - VirtualClock only moves when it's told to. Either by calling advance(), or, for an automatic one,
+    by itself as soon as every thread using it is waiting on it: it jumps straight to the earliest deadline.
+    A demo that sleeps for seconds then finishes in milliseconds, with the same timings every run.
+
+  Parking goes through the clock too, since a thread in a virtual park_timeout isn't in a real park,
+  so unparking it has to be `clock.unpark(&thread)` instead of `thread.unpark()`.
+
+  An automatic clock has to know which threads are running, or it would jump ahead while one of them is still busy.
+  The thread that creates it counts as running, and every other thread that uses it has to be spawned through it,
+  with `clock.spawn(f)` or `clock.spawn_scoped(s, f)`, so it counts as running from before it starts until it's done.
+
+  Waiting on anything other than the clock (a join, a Mutex, stdin) still counts as running,
+  so time stands still until the thread gets back to sleeping or parking.
+*/
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+    fn park(&self);
+    fn park_timeout(&self, timeout: Duration);
+    fn unpark(&self, thread: &Thread);
+    // What spawn and spawn_scoped hold on to in the new thread. Use those instead.
+    fn running(&self) -> Running;
+}
+
+impl dyn Clock {
+    // thread::spawn, counting the new thread as running on the clock until `f` returns.
+    pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        // taken here, not in the new thread, so the clock can't move on before it gets going
+        let running = self.running();
+        thread::spawn(move || {
+            let _running = running;
+            f()
+        })
+    }
+
+    // Scope::spawn, counting the new thread as running on the clock until `f` returns.
+    pub fn spawn_scoped<'scope, 'env, F, T>(
+        &self,
+        s: &'scope Scope<'scope, 'env>,
+        f: F,
+    ) -> ScopedJoinHandle<'scope, T>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        let running = self.running();
+        s.spawn(move || {
+            let _running = running;
+            f()
+        })
+    }
+}
+
+// Counts a thread as running on a VirtualClock until it's dropped. Does nothing for a RealClock.
+#[must_use]
+pub struct Running(Option<Box<dyn FnOnce() + Send>>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if let Some(done) = self.0.take() {
+            done();
+        }
+    }
+}
+
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+
+    fn park(&self) {
+        thread::park();
+    }
+
+    fn park_timeout(&self, timeout: Duration) {
+        thread::park_timeout(timeout);
+    }
+
+    fn unpark(&self, thread: &Thread) {
+        thread.unpark();
+    }
+
+    fn running(&self) -> Running {
+        Running(None)
+    }
+}
+
+pub struct VirtualClock {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    start: Instant,
+    automatic: bool,
+    state: Mutex<State>,
+    wakeup: Condvar,
+}
+
+struct State {
+    elapsed: Duration,
+    // threads that aren't waiting on the clock, starting with the one that created it
+    running: usize,
+    next_seq: u64,
+    // by deadline, then by when they started waiting. A park without a timeout has Duration::MAX.
+    waiters: BTreeMap<(Duration, u64), Waiter>,
+    // seqs of the woken waiters that haven't noticed yet
+    woken: HashSet<u64>,
+    // unparks that came before their thread parked, like the token thread::park keeps
+    tokens: HashSet<ThreadId>,
+}
+
+struct Waiter {
+    thread: ThreadId,
+    // sleeps can't be cut short by unpark
+    parked: bool,
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtualClock {
+    // A clock that only moves on advance().
+    pub fn new() -> Self {
+        Self::with_mode(false)
+    }
+
+    // A clock that moves to the next deadline whenever every running thread is waiting on it.
+    pub fn automatic() -> Self {
+        Self::with_mode(true)
+    }
+
+    fn with_mode(automatic: bool) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                start: Instant::now(),
+                automatic,
+                state: Mutex::new(State {
+                    elapsed: Duration::ZERO,
+                    running: 1,
+                    next_seq: 0,
+                    waiters: BTreeMap::new(),
+                    woken: HashSet::new(),
+                    tokens: HashSet::new(),
+                }),
+                wakeup: Condvar::new(),
+            }),
+        }
+    }
+
+    // How much virtual time has passed since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        self.shared.state.lock().unwrap().elapsed
+    }
+
+    // Moves the clock forward, waking every thread whose sleep or park_timeout ran out on the way.
+    pub fn advance(&self, by: Duration) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.elapsed += by;
+        while let Some((&(deadline, seq), _)) = state.waiters.first_key_value() {
+            if deadline > state.elapsed {
+                break;
+            }
+            state.wake((deadline, seq));
+        }
+        self.shared.wakeup.notify_all();
+    }
+
+    fn wait(&self, timeout: Option<Duration>, parked: bool) {
+        let me = thread::current().id();
+        let mut state = self.shared.state.lock().unwrap();
+        if parked && state.tokens.remove(&me) {
+            return;
+        }
+        if timeout == Some(Duration::ZERO) {
+            return;
+        }
+        let deadline = timeout.map_or(Duration::MAX, |t| state.elapsed.saturating_add(t));
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state
+            .waiters
+            .insert((deadline, seq), Waiter { thread: me, parked });
+        state.running -= 1;
+        self.shared.advance_if_idle(&mut state);
+
+        while !state.woken.remove(&seq) {
+            state = self.shared.wakeup.wait(state).unwrap();
+        }
+    }
+}
+
+impl Shared {
+    fn advance_if_idle(&self, state: &mut State) {
+        if !self.automatic || state.running > 0 {
+            return;
+        }
+        // only the earliest waiter, so threads due at the same time still take turns in a fixed order
+        let Some((&(deadline, seq), _)) = state.waiters.first_key_value() else {
+            return;
+        };
+        assert!(
+            deadline != Duration::MAX,
+            "every thread on the virtual clock is parked without a timeout"
+        );
+        state.elapsed = state.elapsed.max(deadline);
+        state.wake((deadline, seq));
+        self.wakeup.notify_all();
+    }
+}
+
+impl State {
+    fn wake(&mut self, key: (Duration, u64)) {
+        self.waiters.remove(&key);
+        self.woken.insert(key.1);
+        self.running += 1;
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.shared.start + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.wait(Some(duration), false);
+    }
+
+    fn park(&self) {
+        self.wait(None, true);
+    }
+
+    fn park_timeout(&self, timeout: Duration) {
+        self.wait(Some(timeout), true);
+    }
+
+    fn unpark(&self, thread: &Thread) {
+        let mut state = self.shared.state.lock().unwrap();
+        let parked = state
+            .waiters
+            .iter()
+            .find(|(_, w)| w.parked && w.thread == thread.id())
+            .map(|(&key, _)| key);
+        match parked {
+            Some(key) => {
+                state.wake(key);
+                self.shared.wakeup.notify_all();
+            }
+            None => {
+                state.tokens.insert(thread.id());
+            }
+        }
+    }
+
+    fn running(&self) -> Running {
+        self.shared.state.lock().unwrap().running += 1;
+        let shared = self.shared.clone();
+        Running(Some(Box::new(move || {
+            let mut state = shared.state.lock().unwrap();
+            state.running -= 1;
+            shared.advance_if_idle(&mut state);
+        })))
+    }
+}
+
+/*
+  Every time based demo on a VirtualClock, printing how much virtual time each one took.
+  Together they sleep for well over ten seconds, but only take as long as the work in between.
+*/
+pub fn virtual_clock_demo(threads: usize, items: usize) {
+    let started = Instant::now();
+
+    println!(
+        "sleeper on a manual clock: {:?}",
+        manual_sleep(Duration::from_secs(10))
+    );
+
+    let work = Duration::from_millis(75);
+    let clock = Arc::new(VirtualClock::automatic());
+    load_and_store::progress_reporting(items, work, clock.clone());
+    println!("progress_reporting: {:?}", clock.elapsed());
+
+    let clock = Arc::new(VirtualClock::automatic());
+    load_and_store::progress_reporting_multiple_threads(threads, items, work, clock.clone());
+    println!("progress_reporting_multiple_threads: {:?}", clock.elapsed());
+
+    let clock = Arc::new(VirtualClock::automatic());
+    statistics::stats(threads, items, 1.0, clock.clone());
+    println!("stats: {:?}", clock.elapsed());
+
+    let clock = Arc::new(VirtualClock::automatic());
+    console::scripted_console(Duration::from_millis(10), clock.clone());
+    println!("scripted_console: {:?}", clock.elapsed());
+
+    let clock = Arc::new(VirtualClock::automatic());
+    let script = "sleep 2500\nstatus\nstop\n";
+    load_and_store::stop_flag(Cursor::new(script), Duration::from_secs(1), clock.clone());
+    println!("stop_flag: {:?}", clock.elapsed());
+
+    println!(
+        "every time based demo ran on a virtual clock, in {:?} of real time",
+        started.elapsed()
+    );
+}
+
+// Sleeps on a manual clock that's advanced a second at a time, returning how long the sleeper saw pass.
+fn manual_sleep(duration: Duration) -> Duration {
+    let clock = Arc::new(VirtualClock::new());
+    let sleeper = {
+        let clock = clock.clone();
+        thread::spawn(move || {
+            let start = clock.now();
+            clock.sleep(duration);
+            clock.now() - start
+        })
+    };
+    while !sleeper.is_finished() {
+        clock.advance(Duration::from_secs(1));
+        thread::yield_now();
+    }
+    sleeper.join().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THREADS: usize = 4;
+    const ITEMS: usize = 10;
+    const WORK: Duration = Duration::from_millis(75);
+
+    #[test]
+    fn manual_clock_wakes_exactly_on_time() {
+        // a manual clock only moves when told to, so the sleeper wakes exactly on the second it's due
+        assert_eq!(
+            manual_sleep(Duration::from_secs(10)),
+            Duration::from_secs(10)
+        );
+    }
+
+    #[test]
+    fn advance_wakes_only_the_due_sleepers() {
+        let clock = Arc::new(VirtualClock::new());
+        let sleeper = {
+            let clock = clock.clone();
+            thread::spawn(move || clock.sleep(Duration::from_secs(5)))
+        };
+        while clock.shared.state.lock().unwrap().waiters.is_empty() {
+            thread::yield_now();
+        }
+        clock.advance(Duration::from_secs(4));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!sleeper.is_finished());
+        clock.advance(Duration::from_secs(1));
+        sleeper.join().unwrap();
+        assert_eq!(clock.elapsed(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn unpark_before_park_is_not_lost() {
+        let clock = VirtualClock::new();
+        clock.unpark(&thread::current());
+        // would never return, since nothing advances the clock
+        clock.park_timeout(Duration::from_secs(3600));
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+    }
+
+    #[test]
+    fn unpark_cuts_a_park_short() {
+        let clock = Arc::new(VirtualClock::automatic());
+        let parked = {
+            let inner = clock.clone();
+            (&*clock as &dyn Clock).spawn(move || inner.park_timeout(Duration::from_secs(3600)))
+        };
+        clock.sleep(Duration::from_secs(1));
+        clock.unpark(parked.thread());
+        parked.join().unwrap();
+        assert_eq!(clock.elapsed(), Duration::from_secs(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "parked without a timeout")]
+    fn parking_forever_panics() {
+        VirtualClock::automatic().park();
+    }
+
+    #[test]
+    fn real_clock_does_not_count_running() {
+        assert!(RealClock.running().0.is_none());
+    }
+
+    #[test]
+    fn progress_reporting_takes_the_work() {
+        let clock = Arc::new(VirtualClock::automatic());
+        load_and_store::progress_reporting(ITEMS, WORK, clock.clone());
+        assert_eq!(clock.elapsed(), WORK * ITEMS as u32);
+    }
+
+    #[test]
+    fn progress_reporting_multiple_threads_takes_the_biggest_share() {
+        // every thread with a share sleeps once before it, and the biggest share finishes last
+        let share = ITEMS.div_ceil(THREADS) as u32;
+        let clock = Arc::new(VirtualClock::automatic());
+        load_and_store::progress_reporting_multiple_threads(THREADS, ITEMS, WORK, clock.clone());
+        assert_eq!(clock.elapsed(), WORK * (share + 1));
+    }
+
+    #[test]
+    fn stats_finish_on_a_reporting_second() {
+        // every item takes 201..=300ms, and the reporting loop only notices once a second
+        let share = ITEMS.div_ceil(THREADS) as u32;
+        let clock = Arc::new(VirtualClock::automatic());
+        statistics::stats(THREADS, ITEMS, 1.0, clock.clone());
+        let elapsed = clock.elapsed();
+        assert_eq!(elapsed.subsec_nanos(), 0);
+        assert!(elapsed >= Duration::from_millis(201) * share);
+        assert!(elapsed < Duration::from_millis(300) * share + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn scripted_console_takes_only_the_script_sleeps() {
+        // the script's four 50ms sleeps are the only time that passes
+        let interval = Duration::from_millis(10);
+        let clock = Arc::new(VirtualClock::automatic());
+        console::scripted_console(interval, clock.clone());
+        assert_eq!(clock.elapsed(), interval * 5 * 4);
+    }
+
+    #[test]
+    fn stop_flag_takes_the_script_sleep() {
+        let clock = Arc::new(VirtualClock::automatic());
+        let script = "sleep 2500\nstatus\nstop\n";
+        load_and_store::stop_flag(Cursor::new(script), Duration::from_secs(1), clock.clone());
+        assert_eq!(clock.elapsed(), Duration::from_millis(2500));
+    }
+}
@@ -0,0 +1,288 @@
+use std::{
+    io::BufRead,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use super::{
+    cancellation::CancellationToken,
+    clock::{Clock, RealClock},
+};
+
+/*
+  The command loop behind stop_flag.
+
+  Everything the workers need to know is shared through atomics, so commands take effect while they run:
+  - `paused` is checked every iteration. A paused worker parks until `resume` unparks it.
+  - `interval_us` is loaded before every wait, so `rate` changes the pace from the next iteration on.
+    It's in microseconds, so the scaled down intervals of a fast run don't round down to 0.
+  - every worker counts its iterations in its own AtomicU64 for `status`.
+  Stopping a worker goes through its own CancellationToken, all of them children of the console's.
+  Waiting, parking and the uptime all go through the console's Clock.
+
+  Commands are read line by line from any BufRead, so the same loop works on stdin, a file or an in-memory script.
+*/
+
+const HELP: &str = "commands:
+  help            this text
+  status          workers, iterations done and uptime
+  pause / resume  pause or resume every worker
+  rate <ms>       change the workers' sleep interval
+  spawn           start another worker
+  kill <n>        stop worker n
+  sleep <ms>      wait before reading the next command (for scripts)
+  stop            stop every worker and quit
+lines starting with # are ignored";
+
+struct Shared {
+    paused: AtomicBool,
+    interval_us: AtomicU64,
+    quiet: AtomicBool,
+    clock: Arc<dyn Clock>,
+}
+
+struct Worker {
+    id: usize,
+    iterations: Arc<AtomicU64>,
+    token: CancellationToken,
+    handle: JoinHandle<()>,
+}
+
+pub struct Console {
+    shared: Arc<Shared>,
+    stop: CancellationToken,
+    workers: Vec<Worker>,
+    next_id: usize,
+    started: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub workers: usize,
+    pub iterations: u64,
+    pub paused: bool,
+    pub interval: Duration,
+}
+
+impl Console {
+    // Starts with a single worker, like the original stop_flag.
+    pub fn new(interval: Duration) -> Self {
+        Self::with_clock(interval, Arc::new(RealClock))
+    }
+
+    pub fn with_clock(interval: Duration, clock: Arc<dyn Clock>) -> Self {
+        let mut console = Self {
+            shared: Arc::new(Shared {
+                paused: AtomicBool::new(false),
+                interval_us: AtomicU64::new(interval.as_micros() as u64),
+                quiet: AtomicBool::new(false),
+                clock: clock.clone(),
+            }),
+            stop: CancellationToken::with_clock(clock.clone()),
+            workers: Vec::new(),
+            next_id: 0,
+            started: clock.now(),
+        };
+        console.spawn();
+        console
+    }
+
+    // Keeps the workers from printing every iteration, for scripted runs.
+    pub fn quiet(self) -> Self {
+        self.shared.quiet.store(true, Ordering::Relaxed);
+        self
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            workers: self.workers.len(),
+            iterations: self
+                .workers
+                .iter()
+                .map(|w| w.iterations.load(Ordering::Relaxed))
+                .sum(),
+            paused: self.shared.paused.load(Ordering::Relaxed),
+            interval: Duration::from_micros(self.shared.interval_us.load(Ordering::Relaxed)),
+        }
+    }
+
+    // Runs every command from `input` until `stop` or the end of the input, then stops all workers.
+    pub fn run(mut self, input: impl BufRead) {
+        for line in input.lines() {
+            let line = line.unwrap();
+            match self.execute(&line) {
+                Some(reply) if reply.is_empty() => {}
+                Some(reply) => println!("{reply}"),
+                None => break,
+            }
+        }
+        self.shutdown();
+    }
+
+    // Returns the reply to print, or None for `stop`.
+    pub fn execute(&mut self, line: &str) -> Option<String> {
+        let mut words = line.split_whitespace();
+        let reply = match (words.next(), words.next()) {
+            (None, _) => String::new(),
+            (Some(comment), _) if comment.starts_with('#') => String::new(),
+            (Some("help"), None) => HELP.to_string(),
+            (Some("stop"), None) => return None,
+            (Some("status"), None) => {
+                let status = self.status();
+                let ids: Vec<usize> = self.workers.iter().map(|w| w.id).collect();
+                format!(
+                    "workers {ids:?}, {} iterations, up {:.1?}, every {:?}{}",
+                    status.iterations,
+                    self.shared.clock.now() - self.started,
+                    status.interval,
+                    if status.paused { ", paused" } else { "" },
+                )
+            }
+            (Some("pause"), None) => {
+                self.shared.paused.store(true, Ordering::Relaxed);
+                "paused".to_string()
+            }
+            (Some("resume"), None) => {
+                self.shared.paused.store(false, Ordering::Relaxed);
+                // wake the parked workers, instead of leaving them for a spurious wakeup
+                for worker in &self.workers {
+                    self.shared.clock.unpark(worker.handle.thread());
+                }
+                "resumed".to_string()
+            }
+            (Some("rate"), Some(ms)) => match ms.parse::<u64>() {
+                Ok(ms) => {
+                    self.shared
+                        .interval_us
+                        .store(ms.saturating_mul(1000), Ordering::Relaxed);
+                    format!("rate set to {ms}ms")
+                }
+                Err(_) => format!("invalid rate: {ms:?}"),
+            },
+            (Some("spawn"), None) => format!("spawned worker {}", self.spawn()),
+            (Some("kill"), Some(n)) => match n.parse::<usize>() {
+                Ok(id) if self.kill(id) => format!("killed worker {id}"),
+                Ok(id) => format!("no worker {id}"),
+                Err(_) => format!("invalid worker: {n:?}"),
+            },
+            (Some("sleep"), Some(ms)) => match ms.parse::<u64>() {
+                Ok(ms) => {
+                    self.shared.clock.sleep(Duration::from_millis(ms));
+                    String::new()
+                }
+                Err(_) => format!("invalid sleep: {ms:?}"),
+            },
+            _ => format!("unknown command: {line:?}"),
+        };
+        Some(reply)
+    }
+
+    fn spawn(&mut self) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let token = self.stop.child();
+        let iterations = Arc::new(AtomicU64::new(0));
+        let handle = {
+            let (token, iterations, shared) =
+                (token.clone(), iterations.clone(), self.shared.clone());
+            self.shared.clock.spawn(move || {
+                while !token.is_cancelled() {
+                    if shared.paused.load(Ordering::Relaxed) {
+                        // resume and kill both unpark us
+                        shared.clock.park();
+                        continue;
+                    }
+                    if !shared.quiet.load(Ordering::Relaxed) {
+                        println!("background thread {id} working");
+                    }
+                    iterations.fetch_add(1, Ordering::Relaxed);
+                    let interval =
+                        Duration::from_micros(shared.interval_us.load(Ordering::Relaxed));
+                    token.wait_cancelled(interval);
+                }
+            })
+        };
+
+        self.workers.push(Worker {
+            id,
+            iterations,
+            token,
+            handle,
+        });
+        id
+    }
+
+    fn kill(&mut self, id: usize) -> bool {
+        let Some(index) = self.workers.iter().position(|w| w.id == id) else {
+            return false;
+        };
+        let worker = self.workers.remove(index);
+        worker.token.cancel();
+        // in case it's parked because we're paused
+        self.shared.clock.unpark(worker.handle.thread());
+        worker.handle.join().unwrap();
+        true
+    }
+
+    fn shutdown(self) {
+        self.stop.cancel();
+        for worker in self.workers {
+            self.shared.clock.unpark(worker.handle.thread());
+            worker.handle.join().unwrap();
+        }
+    }
+}
+
+// However small the interval gets, the script waits at least this long before checking the iteration counts.
+const MIN_WAIT: Duration = Duration::from_millis(20);
+
+/*
+  Drives the console with a script instead of stdin, checking the workers respond to each command.
+*/
+pub fn scripted_console(interval: Duration, clock: Arc<dyn Clock>) {
+    let wait = || clock.sleep((interval * 5).max(MIN_WAIT));
+    let mut console = Console::with_clock(interval, clock.clone()).quiet();
+
+    for command in ["spawn", "spawn", "kill 1", "rate 1"] {
+        println!("> {command}\n{}", console.execute(command).unwrap());
+    }
+    assert_eq!(console.status().workers, 2);
+    assert_eq!(console.status().interval, Duration::from_millis(1));
+
+    wait();
+    let running = console.status().iterations;
+    assert!(running > 0, "workers did nothing");
+
+    console.execute("pause");
+    let paused = console.status().iterations;
+    wait();
+    wait();
+    // a worker that checked the flag just before we paused can finish that one iteration, but no more
+    assert!(
+        console.status().iterations - paused <= console.status().workers as u64,
+        "paused workers kept working"
+    );
+    let paused = console.status().iterations;
+
+    console.execute("resume");
+    wait();
+    assert!(
+        console.status().iterations > paused,
+        "resumed workers did nothing"
+    );
+    println!("> status\n{}", console.execute("status").unwrap());
+
+    assert_eq!(console.execute("stop"), None);
+    console.shutdown();
+
+    // the same thing from a script, the way it would be read from a file or a pipe
+    let script = "# a comment\nstatus\nspawn\nkill 0\nbogus\nstop\nnever reached\n";
+    Console::with_clock(interval, clock)
+        .quiet()
+        .run(script.as_bytes());
+}
@@ -1,5 +1,7 @@
 use std::sync::atomic::{AtomicU32, Ordering};
 
+use super::id_allocator::AtomicInteger;
+
 // one prob: The 4,294,967,296th call will overflow the 32-bit integer
 pub fn allocate_new_id() {
     static NEXT_ID: AtomicU32 = AtomicU32::new(0);
@@ -16,12 +18,18 @@ pub fn allocate_new_id_panic() -> u32 {
     id
 }
 
+// the counter still overshoots 1000 for a moment; verification::explorer shows where that goes wrong
 pub fn allocate_new_id_subtract() -> u32 {
     static NEXT_ID: AtomicU32 = AtomicU32::new(0);
-    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
-    if id >= 1000 {
-        NEXT_ID.fetch_sub(1, Ordering::Relaxed);
-        panic!("too many IDs");
+    subtract_on_overshoot(&NEXT_ID, 1000).expect("too many IDs")
+}
+
+// allocate_new_id_subtract for any counter and limit, so the explorer can run it on its own atomics
+pub fn subtract_on_overshoot<A: AtomicInteger>(next_id: &A, end: A::Value) -> Option<A::Value> {
+    let id = next_id.fetch_add_one(Ordering::Relaxed);
+    if id >= end {
+        next_id.fetch_sub_one(Ordering::Relaxed);
+        return None;
     }
-    id
+    Some(id)
 }
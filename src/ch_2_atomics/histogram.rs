@@ -0,0 +1,234 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/*
+  A lock-free latency histogram built only from fetch_add and fetch_max on AtomicU64s.
+
+  The buckets are log-linear: every power of two range [2^k, 2^(k+1)) is split into SUB_BUCKETS equal parts,
+  so a bucket is never wider than 1/SUB_BUCKETS (about 6%) of the values it holds,
+  and 976 buckets cover every possible u64 nanosecond value.
+
+  Every record is a handful of independent operations, almost all Relaxed.
+  Like the three separate atomics in the original stats(), a reader can see a record half-applied,
+  e.g. counted in its bucket but not yet in `total`. That's fine for reporting percentiles while work is still running.
+  The one exception is `max`: it's updated before the bucket, which is a Release increment,
+  so a quantile that Acquire loads a bucket count also sees a max at least as big as every value in it.
+
+  Each worker can also record into its own shard (no contention at all) and the reporting loop merges the shards.
+*/
+
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+const BUCKETS: usize = ((64 - SUB_BUCKET_BITS + 1) as usize) * SUB_BUCKETS as usize;
+
+pub struct Histogram {
+    buckets: Box<[AtomicU64]>,
+    // drop-in replacements for num_done, total_time and max_time, all in nanoseconds
+    count: AtomicU64,
+    total: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, time_taken: Duration) {
+        let nanos = u64::try_from(time_taken.as_nanos()).unwrap_or(u64::MAX);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+        self.buckets[bucket_index(nanos)].fetch_add(1, Ordering::Release);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    // Adds everything recorded in `other` into `self`. Both can keep being recorded into concurrently.
+    pub fn merge(&self, other: &Histogram) {
+        // the same order as quantile reads them and record writes them, so the max still covers every bucket
+        let counts: Vec<u64> = other
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Acquire))
+            .collect();
+        self.max
+            .fetch_max(other.max.load(Ordering::Relaxed), Ordering::Relaxed);
+        for (mine, n) in self.buckets.iter().zip(counts) {
+            if n > 0 {
+                mine.fetch_add(n, Ordering::Release);
+            }
+        }
+        self.count
+            .fetch_add(other.count.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.total
+            .fetch_add(other.total.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> Duration {
+        Duration::from_nanos(self.total.load(Ordering::Relaxed))
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max.load(Ordering::Relaxed))
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        let count = self.count();
+        (count > 0).then(|| Duration::from_nanos(self.total.load(Ordering::Relaxed) / count))
+    }
+
+    // The value at quantile `q` (0.0..=1.0), e.g. 0.99 for p99.
+    // Reports the upper edge of the bucket it falls in (capped at the max), so it never under-reports.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        assert!((0.0..=1.0).contains(&q), "quantile out of range: {q}");
+
+        // one pass to copy the buckets, so the rank and the walk agree even while others record
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Acquire))
+            .collect();
+        // after the buckets, so it's at least every value counted in them
+        let max = self.max.load(Ordering::Relaxed);
+        let count: u64 = counts.iter().sum();
+        if count == 0 {
+            return None;
+        }
+
+        // the rank of the sample we're after, 1-based
+        let rank = ((q * count as f64).ceil() as u64).clamp(1, count);
+        let mut seen = 0;
+        for (index, n) in counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                let upper = bucket_upper_bound(index).min(max);
+                return Some(Duration::from_nanos(upper));
+            }
+        }
+        unreachable!("rank is at most the total count")
+    }
+
+    pub fn percentiles(&self) -> Option<Percentiles> {
+        Some(Percentiles {
+            p50: self.quantile(0.50)?,
+            p90: self.quantile(0.90)?,
+            p99: self.quantile(0.99)?,
+            p999: self.quantile(0.999)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Percentiles {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+}
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS {
+        // the first power of two ranges are narrower than SUB_BUCKETS, so every value gets its own bucket
+        return value as usize;
+    }
+    let magnitude = 63 - value.leading_zeros(); // >= SUB_BUCKET_BITS
+    let shift = magnitude - SUB_BUCKET_BITS;
+    let sub = (value >> shift) & (SUB_BUCKETS - 1);
+    ((shift + 1) as u64 * SUB_BUCKETS + sub) as usize
+}
+
+fn bucket_upper_bound(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let shift = (index / SUB_BUCKETS - 1) as u32;
+    let sub = index % SUB_BUCKETS;
+    let lower = (SUB_BUCKETS + sub) << shift;
+    lower + ((1u64 << shift) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_values_get_their_own_bucket() {
+        for v in 0..SUB_BUCKETS {
+            assert_eq!(bucket_index(v), v as usize);
+            assert_eq!(bucket_upper_bound(v as usize), v);
+        }
+    }
+
+    #[test]
+    fn buckets_are_contiguous_and_narrow() {
+        for index in SUB_BUCKETS as usize..BUCKETS {
+            let lower = bucket_upper_bound(index - 1) + 1;
+            let upper = bucket_upper_bound(index);
+            assert_eq!(bucket_index(lower), index);
+            assert_eq!(bucket_index(upper), index);
+            // never wider than 1/SUB_BUCKETS of the values in it
+            assert!(upper - lower < lower.div_ceil(SUB_BUCKETS));
+        }
+        assert_eq!(bucket_index(u64::MAX), BUCKETS - 1);
+        assert_eq!(bucket_upper_bound(BUCKETS - 1), u64::MAX);
+    }
+
+    #[test]
+    fn quantiles_report_the_upper_edge_capped_at_the_max() {
+        let histogram = Histogram::new();
+        assert_eq!(histogram.quantile(0.5), None);
+        for micros in 1..=1000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        assert_eq!(histogram.count(), 1000);
+        assert_eq!(histogram.max(), Duration::from_micros(1000));
+        assert_eq!(histogram.mean(), Some(Duration::from_nanos(500_500)));
+
+        let p = histogram.percentiles().unwrap();
+        for (q, actual) in [(0.5, p.p50), (0.9, p.p90), (0.99, p.p99)] {
+            let exact = Duration::from_micros((q * 1000.0) as u64);
+            assert!(actual >= exact, "p{q} {actual:?} under {exact:?}");
+            assert!(actual <= exact.mul_f64(1.0 + 1.0 / SUB_BUCKETS as f64));
+        }
+        assert_eq!(p.p999, Duration::from_micros(1000));
+        // the bucket 1000ns is in goes up to 1023ns
+        assert_eq!(histogram.quantile(0.0), Some(Duration::from_nanos(1023)));
+        assert_eq!(histogram.quantile(1.0), Some(histogram.max()));
+    }
+
+    #[test]
+    #[should_panic(expected = "quantile out of range")]
+    fn quantile_out_of_range() {
+        Histogram::new().quantile(1.5);
+    }
+
+    #[test]
+    fn merge_adds_up() {
+        let (a, b) = (Histogram::new(), Histogram::new());
+        a.record(Duration::from_micros(10));
+        b.record(Duration::from_micros(30));
+        b.record(Duration::from_micros(20));
+        a.merge(&b);
+        assert_eq!(a.count(), 3);
+        assert_eq!(a.total(), Duration::from_micros(60));
+        assert_eq!(a.max(), Duration::from_micros(30));
+        assert_eq!(a.quantile(1.0), Some(Duration::from_micros(30)));
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    collections::HashSet,
+    error::Error,
+    fmt::{self, Debug},
+    hash::Hash,
+    ops::Range,
+    sync::atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+    thread,
+};
+
+/*
+  The allocate_new_id family from fetch_add_example.rs and compare_exchange.rs, as one reusable type.
+
+  Each of those hard-codes a static AtomicU32, a limit of 1000 and a panic.
+  IdAllocator takes any unsigned atomic integer, a range of ids, and one of the strategies below,
+  and reports running out of ids with Err(Exhausted) instead of panicking.
+*/
+
+// The handful of atomic operations the strategies need, so IdAllocator can be generic over the integer width.
+pub trait AtomicInteger: Send + Sync {
+    type Value: Copy + Ord + Hash + Debug + Send;
+
+    fn new(v: Self::Value) -> Self;
+    fn load(&self, order: Ordering) -> Self::Value;
+    fn store(&self, v: Self::Value, order: Ordering);
+    // both wrap around on overflow, like the std methods
+    fn fetch_add_one(&self, order: Ordering) -> Self::Value;
+    fn fetch_sub_one(&self, order: Ordering) -> Self::Value;
+    fn compare_exchange_weak(
+        &self,
+        current: Self::Value,
+        new: Self::Value,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<Self::Value, Self::Value>;
+    fn fetch_update(
+        &self,
+        set_order: Ordering,
+        fetch_order: Ordering,
+        f: impl FnMut(Self::Value) -> Option<Self::Value>,
+    ) -> Result<Self::Value, Self::Value>;
+    fn checked_add_one(v: Self::Value) -> Option<Self::Value>;
+}
+
+macro_rules! impl_atomic_integer {
+    ($($atomic:ty => $value:ty),*) => {$(
+        impl AtomicInteger for $atomic {
+            type Value = $value;
+
+            fn new(v: $value) -> Self {
+                <$atomic>::new(v)
+            }
+            fn load(&self, order: Ordering) -> $value {
+                self.load(order)
+            }
+            fn store(&self, v: $value, order: Ordering) {
+                self.store(v, order)
+            }
+            fn fetch_add_one(&self, order: Ordering) -> $value {
+                self.fetch_add(1, order)
+            }
+            fn fetch_sub_one(&self, order: Ordering) -> $value {
+                self.fetch_sub(1, order)
+            }
+            fn compare_exchange_weak(
+                &self,
+                current: $value,
+                new: $value,
+                success: Ordering,
+                failure: Ordering,
+            ) -> Result<$value, $value> {
+                self.compare_exchange_weak(current, new, success, failure)
+            }
+            fn fetch_update(
+                &self,
+                set_order: Ordering,
+                fetch_order: Ordering,
+                f: impl FnMut($value) -> Option<$value>,
+            ) -> Result<$value, $value> {
+                self.fetch_update(set_order, fetch_order, f)
+            }
+            fn checked_add_one(v: $value) -> Option<$value> {
+                v.checked_add(1)
+            }
+        }
+    )*};
+}
+
+impl_atomic_integer!(
+    AtomicU8 => u8,
+    AtomicU16 => u16,
+    AtomicU32 => u32,
+    AtomicU64 => u64,
+    AtomicUsize => usize
+);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    // allocate_new_id: a bare fetch_add. The counter keeps going past the range and eventually wraps,
+    // after which it hands out the same ids again.
+    Wrapping,
+    // allocate_new_id_subtract: fetch_add, and fetch_sub again if it went past the end.
+    // For a moment the counter can overshoot the end by up to one per racing thread,
+    // and if that takes it past T::MAX it wraps to 0 and hands out duplicates, just like Wrapping.
+    // So it's only safe when range.end is more than the number of threads below T::MAX
+    // (verification::explorer finds the duplicate with an AtomicU8 and 254..255).
+    FetchAddRollback,
+    // allocate_new_id_upper_bound: a compare_exchange loop that checks before it increments.
+    // This one and FetchUpdate never go past the end, so they're the ones to use.
+    CompareExchange,
+    // allocate_new_id_fetch_update: the same loop, written with fetch_update.
+    FetchUpdate,
+}
+
+impl Strategy {
+    pub const ALL: [Strategy; 4] = [
+        Strategy::Wrapping,
+        Strategy::FetchAddRollback,
+        Strategy::CompareExchange,
+        Strategy::FetchUpdate,
+    ];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exhausted;
+
+impl fmt::Display for Exhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("too many IDs")
+    }
+}
+
+impl Error for Exhausted {}
+
+pub type Id<T> = <T as AtomicInteger>::Value;
+
+pub struct IdAllocator<T: AtomicInteger> {
+    next: T,
+    range: Range<T::Value>,
+    strategy: Strategy,
+}
+
+impl<T: AtomicInteger> IdAllocator<T> {
+    // hands out ids from `range.start` up to, but not including, `range.end`
+    pub fn new(range: Range<T::Value>, strategy: Strategy) -> Self {
+        Self {
+            next: T::new(range.start),
+            range,
+            strategy,
+        }
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    pub fn try_allocate(&self) -> Result<Id<T>, Exhausted> {
+        let end = self.range.end;
+        match self.strategy {
+            Strategy::Wrapping => {
+                let id = self.next.fetch_add_one(Ordering::Relaxed);
+                if self.range.contains(&id) {
+                    Ok(id)
+                } else {
+                    Err(Exhausted)
+                }
+            }
+            Strategy::FetchAddRollback => {
+                let id = self.next.fetch_add_one(Ordering::Relaxed);
+                if id >= end {
+                    self.next.fetch_sub_one(Ordering::Relaxed);
+                    return Err(Exhausted);
+                }
+                Ok(id)
+            }
+            Strategy::CompareExchange => {
+                let mut id = self.next.load(Ordering::Relaxed);
+                loop {
+                    if id >= end {
+                        return Err(Exhausted);
+                    }
+                    // can't overflow, since id < end
+                    let new = T::checked_add_one(id).ok_or(Exhausted)?;
+                    match self.next.compare_exchange_weak(
+                        id,
+                        new,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => return Ok(id),
+                        Err(v) => id = v,
+                    }
+                }
+            }
+            Strategy::FetchUpdate => self
+                .next
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                    if n < end {
+                        T::checked_add_one(n)
+                    } else {
+                        None
+                    }
+                })
+                .map_err(|_| Exhausted),
+        }
+    }
+}
+
+/*
+  Runs the same workload under every strategy: `threads` threads each try `attempts` allocations from 0..200 with an AtomicU8.
+  With enough attempts the Wrapping counter goes past 255, wraps to 0, and starts handing out duplicates.
+  FetchAddRollback can do the same once more than 56 threads overshoot 200 at the same time.
+  Otherwise they hand out every id exactly once and then only return Exhausted.
+*/
+pub fn compare_strategies(threads: usize, attempts: usize) {
+    for strategy in Strategy::ALL {
+        let allocator = IdAllocator::<AtomicU8>::new(0..200, strategy);
+
+        let results: Vec<Vec<Result<u8, Exhausted>>> = thread::scope(|s| {
+            let handles: Vec<_> = (0..threads)
+                .map(|_| s.spawn(|| (0..attempts).map(|_| allocator.try_allocate()).collect()))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        let ids: Vec<u8> = results.iter().flatten().filter_map(|r| r.ok()).collect();
+        let unique: HashSet<u8> = ids.iter().copied().collect();
+        let exhausted = results.iter().flatten().filter(|r| r.is_err()).count();
+        println!(
+            "{strategy:?}: {} allocated, {} unique, {} duplicates, {exhausted} exhausted",
+            ids.len(),
+            unique.len(),
+            ids.len() - unique.len(),
+        );
+
+        let can_wrap = match strategy {
+            Strategy::Wrapping => true,
+            Strategy::FetchAddRollback => 200 + threads > 256,
+            Strategy::CompareExchange | Strategy::FetchUpdate => false,
+        };
+        if !can_wrap {
+            assert_eq!(
+                ids.len(),
+                unique.len(),
+                "{strategy:?} handed out a duplicate"
+            );
+            assert_eq!(unique.len(), (threads * attempts).min(200));
+        }
+    }
+}
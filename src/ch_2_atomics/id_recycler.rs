@@ -0,0 +1,399 @@
+use std::{
+    error::Error,
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    thread,
+};
+
+use rand::Rng;
+
+use super::id_allocator::Exhausted;
+
+/*
+  An id allocator whose ids can be released and handed out again.
+
+  The free ids form a lock-free stack (a Treiber stack): every slot stores the index of the next free slot,
+  and `head` points at the first one. Allocating pops, releasing pushes, both with a compare_exchange on `head`.
+
+  This is where the ABA problem mentioned in increment_compare_exchange actually bites:
+    1. Thread A loads head = 5 and reads slot 5's next = 7, then gets preempted.
+    2. Thread B pops 5, pops 7, and releases 5 again. head is 5 once more, but 7 is now in use.
+    3. Thread A's compare_exchange(5, 7) succeeds, because head *looks* unchanged, and 7 is handed out twice.
+
+  The fix is to pack a tag next to the index in a single AtomicU64 and bump it on every change,
+  so in step 3 head is (5, tag + 3) instead of (5, tag) and A's compare_exchange fails.
+  (The tag is 32 bits, so A would have to sleep through ~4 billion operations for it to wrap back around.)
+
+  Ids also carry a generation, bumped every time the slot is released,
+  so releasing the same id twice (or a stale copy of it) is caught instead of corrupting the stack.
+*/
+
+const NIL: u32 = u32::MAX;
+
+// Only the recycler makes these, so an id can't be forged with the current generation to release a free slot again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RecycledId {
+    index: u32,
+    generation: u32,
+}
+
+impl RecycledId {
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaleId(pub RecycledId);
+
+impl fmt::Display for StaleId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "id {:?} was already released", self.0)
+    }
+}
+
+impl Error for StaleId {}
+
+struct Slot {
+    next: AtomicU32,
+    generation: AtomicU32,
+}
+
+pub struct IdRecycler {
+    // (tag << 32) | index of the first free slot, NIL when every id is in use
+    head: AtomicU64,
+    slots: Box<[Slot]>,
+    // false only for the naive version used to show the ABA problem
+    tagged: bool,
+}
+
+fn pack(tag: u32, index: u32) -> u64 {
+    (tag as u64) << 32 | index as u64
+}
+
+fn unpack(head: u64) -> (u32, u32) {
+    ((head >> 32) as u32, head as u32)
+}
+
+impl IdRecycler {
+    pub fn new(capacity: u32) -> Self {
+        Self::with_tagging(capacity, true)
+    }
+
+    // The same stack without the tag, which is vulnerable to ABA. Only here to show what goes wrong.
+    pub fn naive(capacity: u32) -> Self {
+        Self::with_tagging(capacity, false)
+    }
+
+    fn with_tagging(capacity: u32, tagged: bool) -> Self {
+        assert!(capacity < NIL, "capacity too large");
+        // start with every id on the free list: 0 -> 1 -> ... -> capacity - 1
+        let slots = (0..capacity)
+            .map(|i| Slot {
+                next: AtomicU32::new(if i + 1 < capacity { i + 1 } else { NIL }),
+                generation: AtomicU32::new(0),
+            })
+            .collect();
+        let first = if capacity > 0 { 0 } else { NIL };
+        Self {
+            head: AtomicU64::new(pack(0, first)),
+            slots,
+            tagged,
+        }
+    }
+
+    fn next_tag(&self, tag: u32) -> u32 {
+        if self.tagged {
+            tag.wrapping_add(1)
+        } else {
+            tag
+        }
+    }
+
+    pub fn try_allocate(&self) -> Result<RecycledId, Exhausted> {
+        self.allocate_with(|| {})
+    }
+
+    // `in_window` runs between reading `next` and the compare_exchange, so tests can simulate preemption there
+    fn allocate_with(&self, in_window: impl Fn()) -> Result<RecycledId, Exhausted> {
+        // Acquire, so we see the `next` written by whoever pushed the slot we're about to pop
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            let (tag, index) = unpack(head);
+            if index == NIL {
+                return Err(Exhausted);
+            }
+            let next = self.slots[index as usize].next.load(Ordering::Relaxed);
+            // <- the ABA window: `index` can be popped, and pushed back, right here
+            in_window();
+            match self.head.compare_exchange_weak(
+                head,
+                pack(self.next_tag(tag), next),
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    let generation = self.slots[index as usize]
+                        .generation
+                        .load(Ordering::Relaxed);
+                    return Ok(RecycledId { index, generation });
+                }
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    pub fn release(&self, id: RecycledId) -> Result<(), StaleId> {
+        let slot = self.slots.get(id.index as usize).ok_or(StaleId(id))?;
+
+        // only one release per allocation can move the generation forward
+        slot.generation
+            .compare_exchange(
+                id.generation,
+                id.generation.wrapping_add(1),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .map_err(|_| StaleId(id))?;
+
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            let (tag, top) = unpack(head);
+            slot.next.store(top, Ordering::Relaxed);
+            // Release, so the next store above is visible to whoever pops this slot
+            match self.head.compare_exchange_weak(
+                head,
+                pack(self.next_tag(tag), id.index),
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(h) => head = h,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StressReport {
+    pub allocations: usize,
+    pub duplicates: usize,
+    pub exhausted: usize,
+    // ids that could no longer be allocated once every thread released everything
+    pub lost: usize,
+}
+
+/*
+  Many threads allocate and release ids from a small pool as fast as they can.
+  Every live id is marked in `in_use`, so an id handed to two threads at once is caught as a duplicate.
+  Afterwards the free list is drained, to catch ids that fell off it (or a list that loops back on itself).
+  A correct allocator must report 0 duplicates and 0 lost ids.
+*/
+pub fn stress(recycler: &IdRecycler, threads: usize, iterations: usize) -> StressReport {
+    let in_use: Vec<AtomicBool> = (0..recycler.slots.len())
+        .map(|_| AtomicBool::new(false))
+        .collect();
+    let allocations = AtomicUsize::new(0);
+    let duplicates = AtomicUsize::new(0);
+    let exhausted = AtomicUsize::new(0);
+
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                let mut rng = rand::thread_rng();
+                for _ in 0..iterations {
+                    // give up the cpu in the ABA window now and then, like a preemption at the worst moment.
+                    // a random number of times, so the threads don't just take turns in lockstep
+                    let window_yields = rng.gen_range(0..4);
+                    let allocated = recycler.allocate_with(|| {
+                        for _ in 0..window_yields {
+                            thread::yield_now();
+                        }
+                    });
+                    let Ok(id) = allocated else {
+                        exhausted.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    };
+                    allocations.fetch_add(1, Ordering::Relaxed);
+                    if in_use[id.index as usize].swap(true, Ordering::Relaxed) {
+                        // someone else holds this id right now. They will release it, not us.
+                        duplicates.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    // hold on to the id for a moment, so it can be handed out twice while we have it
+                    for _ in 0..rng.gen_range(0..4) {
+                        thread::yield_now();
+                    }
+                    in_use[id.index as usize].store(false, Ordering::Relaxed);
+                    // a duplicate may have been released by its other holder already
+                    let _ = recycler.release(id);
+                }
+            });
+        }
+    });
+
+    let capacity = recycler.slots.len();
+    let mut drained = vec![false; capacity];
+    for _ in 0..capacity {
+        match recycler.try_allocate() {
+            Ok(id) => drained[id.index as usize] = true,
+            Err(Exhausted) => break,
+        }
+    }
+
+    StressReport {
+        allocations: allocations.into_inner(),
+        duplicates: duplicates.into_inner(),
+        exhausted: exhausted.into_inner(),
+        lost: drained.iter().filter(|&&d| !d).count(),
+    }
+}
+
+// The exact interleaving from the comment at the top, replayed step by step on one thread.
+fn replay_aba(recycler: &IdRecycler) -> Option<(RecycledId, RecycledId)> {
+    let b_ids = std::cell::RefCell::new(Vec::new());
+    let a = recycler.allocate_with(|| {
+        // we are thread A, stuck between reading head = 0 (next = 1) and the compare_exchange
+        let mut b_ids = b_ids.borrow_mut();
+        if b_ids.is_empty() {
+            // thread B pops 0, pops 1, and releases 0 again
+            let zero = recycler.try_allocate().unwrap();
+            let one = recycler.try_allocate().unwrap();
+            recycler.release(zero).unwrap();
+            b_ids.push(one);
+        }
+    });
+    let one = b_ids.into_inner()[0];
+    // naive: A pops 0 and sets head to 1, so the next allocation hands out 1 again
+    // tagged: A retries and still pops 0, and head moves on to 2
+    let a = a.unwrap();
+    let next = recycler.try_allocate().unwrap();
+    (next.index == one.index)
+        .then_some((one, next))
+        .filter(|_| a.index == 0)
+}
+
+// replay_aba on both versions: the naive one has to fall for it, the tagged one must not.
+pub fn check_replay() {
+    let naive_aba = replay_aba(&IdRecycler::naive(4));
+    println!("naive replay:  id handed out twice: {naive_aba:?}");
+    assert!(naive_aba.is_some(), "naive free list should fall for ABA");
+    let tagged_aba = replay_aba(&IdRecycler::new(4));
+    println!("tagged replay: id handed out twice: {tagged_aba:?}");
+    assert!(tagged_aba.is_none(), "tagged free list fell for ABA");
+}
+
+pub fn check_tagged_stress(threads: usize, iterations: usize) {
+    let tagged = stress(&IdRecycler::new(4), threads, iterations);
+    println!("tagged: {tagged:?}");
+    assert_eq!(tagged.allocations + tagged.exhausted, threads * iterations);
+    assert_eq!(
+        tagged.duplicates, 0,
+        "tagged free list handed out an id twice"
+    );
+    assert_eq!(tagged.lost, 0, "tagged free list lost an id");
+}
+
+// Releasing the same id twice is refused, and the slot comes back with a new generation.
+pub fn check_double_release() {
+    let recycler = IdRecycler::new(1);
+    let id = recycler.try_allocate().unwrap();
+    assert_eq!(recycler.try_allocate(), Err(Exhausted));
+    recycler.release(id).unwrap();
+    assert_eq!(recycler.release(id), Err(StaleId(id)));
+    let again = recycler.try_allocate().unwrap();
+    assert_eq!(again.index(), id.index());
+    assert_ne!(again.generation(), id.generation());
+}
+
+pub fn recycling_stress_test(threads: usize, iterations: usize) {
+    let threads = threads.max(2);
+    check_replay();
+
+    // whether the naive stack falls for ABA here depends on the scheduler, so this is only printed.
+    // check_replay, and the tests that force the window across two threads, are what show it can.
+    let naive = stress(&IdRecycler::naive(4), threads, iterations);
+    println!("naive:  {naive:?}");
+    assert_eq!(naive.allocations + naive.exhausted, threads * iterations);
+    if naive.duplicates == 0 && naive.lost == 0 {
+        println!("(no ABA observed this run, try more --items)");
+    }
+
+    check_tagged_stress(threads, iterations);
+    check_double_release();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+
+    /*
+      The replay_aba interleaving, but with A and B on two real threads:
+      A stops in the ABA window until B has popped 0 and 1 and pushed 0 back, with 1 still in use.
+      Returns the id B holds and the one the next allocation after A's gets, which must not be the same.
+      (A spurious compare_exchange_weak failure would make A retry and miss it, but that never happens on x86.)
+    */
+    fn aba_across_threads(recycler: &IdRecycler) -> (RecycledId, RecycledId) {
+        let in_window = Barrier::new(2);
+        let b_done = Barrier::new(2);
+        let first = AtomicBool::new(true);
+        thread::scope(|s| {
+            let b = s.spawn(|| {
+                in_window.wait();
+                let zero = recycler.try_allocate().unwrap();
+                let one = recycler.try_allocate().unwrap();
+                recycler.release(zero).unwrap();
+                b_done.wait();
+                one
+            });
+            let a = recycler
+                .allocate_with(|| {
+                    // only the first attempt waits, a retry goes straight on
+                    if first.swap(false, Ordering::Relaxed) {
+                        in_window.wait();
+                        b_done.wait();
+                    }
+                })
+                .unwrap();
+            assert_eq!(a.index(), 0);
+            let held_by_b = b.join().unwrap();
+            (held_by_b, recycler.try_allocate().unwrap())
+        })
+    }
+
+    #[test]
+    fn naive_hands_out_an_id_twice_across_threads() {
+        let (held_by_b, next) = aba_across_threads(&IdRecycler::naive(4));
+        assert_eq!(
+            next.index(),
+            held_by_b.index(),
+            "naive free list should fall for ABA"
+        );
+    }
+
+    #[test]
+    fn tagged_survives_aba_across_threads() {
+        let (held_by_b, next) = aba_across_threads(&IdRecycler::new(4));
+        assert_ne!(next.index(), held_by_b.index());
+    }
+
+    #[test]
+    fn replay() {
+        check_replay();
+    }
+
+    #[test]
+    fn tagged_stress() {
+        check_tagged_stress(4, 20_000);
+    }
+
+    #[test]
+    fn double_release() {
+        check_double_release();
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    marker::PhantomData,
+    mem::MaybeUninit,
+    sync::{atomic::AtomicUsize, Mutex},
+    thread::{self, Thread},
+};
+
+use crate::sync::{
+    atomic::{AtomicU8, Ordering},
+    cell::UnsafeCell,
+};
+
+/*
+  The three strategies from lazy_init.rs, as one generic cell with the strategy picked by a type parameter:
+
+  - Racy: like get_x. Every thread that finds the cell empty runs the initializer,
+    and the first one to store its value wins. The losers drop their value and use the winner's.
+  - Blocking: like get_x_once_lock. One thread runs the initializer, the others block until it's done.
+    If the initializer panics the cell is poisoned, like std::sync::Once, and every later call panics too.
+  - RetryOnPanic: like Blocking, but a panic resets the cell, so the next caller runs the initializer again.
+
+  The value is only ever handed out as a &T after the cell is READY,
+  so there's no way to get back a stale 0 the way get_x_once did.
+*/
+
+pub trait Policy {
+    const RACY: bool;
+    const POISON_ON_PANIC: bool;
+}
+
+pub struct Racy;
+pub struct Blocking;
+pub struct RetryOnPanic;
+
+impl Policy for Racy {
+    const RACY: bool = true;
+    const POISON_ON_PANIC: bool = false;
+}
+
+impl Policy for Blocking {
+    const RACY: bool = false;
+    const POISON_ON_PANIC: bool = true;
+}
+
+impl Policy for RetryOnPanic {
+    const RACY: bool = false;
+    const POISON_ON_PANIC: bool = false;
+}
+
+const UNINIT: u8 = 0;
+// Racy: the winner is copying its value in. Blocking/RetryOnPanic: the initializer is running.
+const RUNNING: u8 = 1;
+const READY: u8 = 2;
+const POISONED: u8 = 3;
+
+pub struct LazyCell<T, P: Policy = Blocking> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+    // threads blocked waiting for RUNNING to end. Only touched on the slow path.
+    waiters: Mutex<Vec<Thread>>,
+    _policy: PhantomData<P>,
+}
+
+// Shared between threads as &LazyCell, handing out &T, and the T can be dropped on whichever thread drops the cell
+unsafe impl<T: Send + Sync, P: Policy> Sync for LazyCell<T, P> {}
+unsafe impl<T: Send, P: Policy> Send for LazyCell<T, P> {}
+
+impl<T, P: Policy> Default for LazyCell<T, P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, P: Policy> LazyCell<T, P> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(UNINIT),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+            waiters: Mutex::new(Vec::new()),
+            _policy: PhantomData,
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        // Acquire pairs with the Release store of READY, making the written value visible
+        if self.state.load(Ordering::Acquire) == READY {
+            Some(self.value.with(|v| unsafe { (*v).assume_init_ref() }))
+        } else {
+            None
+        }
+    }
+
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        match self.get_or_try_init(|| Ok::<T, std::convert::Infallible>(f())) {
+            Ok(v) => v,
+            Err(never) => match never {},
+        }
+    }
+
+    // If `f` returns an error the cell stays empty and the error is passed back.
+    pub fn get_or_try_init<E>(&self, f: impl FnOnce() -> Result<T, E>) -> Result<&T, E> {
+        if let Some(v) = self.get() {
+            return Ok(v);
+        }
+        if P::RACY {
+            self.init_racy(f)
+        } else {
+            self.init_blocking(f)
+        }
+    }
+
+    fn init_racy<E>(&self, f: impl FnOnce() -> Result<T, E>) -> Result<&T, E> {
+        let value = f()?;
+        match self
+            .state
+            .compare_exchange(UNINIT, RUNNING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                self.value.with_mut(|v| unsafe { (*v).write(value) });
+                self.state.store(READY, Ordering::Release);
+            }
+            Err(_) => {
+                // we lost, our value is dropped here.
+                drop(value);
+                // the winner is only copying its value in, which can't fail, so a short spin is enough
+                while self.state.load(Ordering::Acquire) != READY {
+                    thread::yield_now();
+                }
+            }
+        }
+        Ok(self.value.with(|v| unsafe { (*v).assume_init_ref() }))
+    }
+
+    fn init_blocking<E>(&self, f: impl FnOnce() -> Result<T, E>) -> Result<&T, E> {
+        let mut f = Some(f);
+        loop {
+            match self
+                .state
+                .compare_exchange(UNINIT, RUNNING, Ordering::Acquire, Ordering::Acquire)
+            {
+                Ok(_) => {
+                    // if f panics, the guard's drop puts the cell back (or poisons it) and wakes everyone
+                    let guard = RunningGuard { cell: self };
+                    let f = f
+                        .take()
+                        .expect("only one successful compare_exchange per call");
+                    let result = f();
+                    std::mem::forget(guard);
+                    return match result {
+                        Ok(value) => {
+                            self.value.with_mut(|v| unsafe { (*v).write(value) });
+                            self.finish(READY);
+                            Ok(self.value.with(|v| unsafe { (*v).assume_init_ref() }))
+                        }
+                        Err(e) => {
+                            // leave it empty for the next caller to try
+                            self.finish(UNINIT);
+                            Err(e)
+                        }
+                    };
+                }
+                Err(READY) => return Ok(self.value.with(|v| unsafe { (*v).assume_init_ref() })),
+                Err(POISONED) => panic!("LazyCell poisoned: its initializer panicked"),
+                Err(_) => self.wait_while_running(),
+            }
+        }
+    }
+
+    fn finish(&self, state: u8) {
+        self.state.store(state, Ordering::Release);
+        for waiter in self.waiters.lock().unwrap().drain(..) {
+            waiter.unpark();
+        }
+    }
+
+    fn wait_while_running(&self) {
+        {
+            let mut waiters = self.waiters.lock().unwrap();
+            // checked under the lock: finish() stores the state before locking, so we can't miss its wakeup
+            if self.state.load(Ordering::Acquire) != RUNNING {
+                return;
+            }
+            waiters.push(thread::current());
+        }
+        // a spurious wakeup just sends us around the loop once more
+        thread::park();
+    }
+}
+
+struct RunningGuard<'a, T, P: Policy> {
+    cell: &'a LazyCell<T, P>,
+}
+
+impl<T, P: Policy> Drop for RunningGuard<'_, T, P> {
+    fn drop(&mut self) {
+        // only reached when the initializer panicked
+        self.cell
+            .finish(if P::POISON_ON_PANIC { POISONED } else { UNINIT });
+    }
+}
+
+impl<T, P: Policy> Drop for LazyCell<T, P> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == READY {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+/*
+  The same initializer under every policy, on `threads` threads at once.
+  Returns how often the initializer ran, and the value every thread got back.
+*/
+fn run_policy<P: Policy>(threads: usize) -> (usize, Vec<u64>) {
+    let cell = LazyCell::<u64, P>::new();
+    let runs = AtomicUsize::new(0);
+
+    let seen: Vec<u64> = thread::scope(|s| {
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                s.spawn(|| {
+                    *cell.get_or_init(|| {
+                        let run = runs.fetch_add(1, Ordering::Relaxed);
+                        // do some expensive calculation to calc the value
+                        thread::yield_now();
+                        12 + run as u64
+                    })
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+    (runs.into_inner(), seen)
+}
+
+/*
+  Racy may run the initializer once per thread, Blocking and RetryOnPanic run it exactly once,
+  and every thread always sees the same, non-zero, value. What happens when it panics is in the tests.
+*/
+pub fn lazy_cell_policies(threads: usize) {
+    for (name, (runs, seen)) in [
+        ("Racy", run_policy::<Racy>(threads)),
+        ("Blocking", run_policy::<Blocking>(threads)),
+        ("RetryOnPanic", run_policy::<RetryOnPanic>(threads)),
+    ] {
+        println!("{name}: initializer ran {runs} time(s), every thread saw {seen:?}");
+    }
+
+    // a failing fallible initializer leaves the cell empty for the next try
+    let cell = LazyCell::<String, Blocking>::new();
+    let failed = cell.get_or_try_init(|| Err("not yet"));
+    let ready = cell.get_or_try_init(|| Ok::<_, ()>("ready".to_string()));
+    println!("get_or_try_init: {failed:?}, then {ready:?}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+
+    fn check_policy<P: Policy>() {
+        for _ in 0..20 {
+            let (runs, seen) = run_policy::<P>(8);
+            assert!(seen.iter().all(|&x| x != 0 && x == seen[0]), "{seen:?}");
+            if P::RACY {
+                assert!((1..=8).contains(&runs));
+            } else {
+                assert_eq!(runs, 1);
+            }
+        }
+    }
+
+    #[test]
+    fn racy_hands_out_one_value() {
+        check_policy::<Racy>();
+    }
+
+    #[test]
+    fn blocking_runs_the_initializer_once() {
+        check_policy::<Blocking>();
+    }
+
+    #[test]
+    fn retry_on_panic_runs_the_initializer_once() {
+        check_policy::<RetryOnPanic>();
+    }
+
+    #[test]
+    fn failed_try_init_leaves_it_empty() {
+        let cell = LazyCell::<String, Blocking>::new();
+        assert_eq!(cell.get_or_try_init(|| Err("not yet")), Err("not yet"));
+        assert_eq!(cell.get(), None);
+        assert_eq!(
+            cell.get_or_try_init(|| Ok::<_, ()>("ready".to_string()))
+                .unwrap(),
+            "ready"
+        );
+        assert_eq!(cell.get().map(String::as_str), Some("ready"));
+    }
+
+    #[test]
+    fn retry_on_panic_runs_again_after_a_panic() {
+        let cell = LazyCell::<u64, RetryOnPanic>::new();
+        let first = panic::catch_unwind(AssertUnwindSafe(|| *cell.get_or_init(|| panic!("boom"))));
+        assert!(first.is_err());
+        assert_eq!(cell.get(), None);
+        assert_eq!(*cell.get_or_init(|| 12), 12);
+    }
+
+    #[test]
+    #[should_panic(expected = "LazyCell poisoned")]
+    fn blocking_is_poisoned_by_a_panic() {
+        let cell = LazyCell::<u64, Blocking>::new();
+        let first = panic::catch_unwind(AssertUnwindSafe(|| *cell.get_or_init(|| panic!("boom"))));
+        assert!(first.is_err());
+        cell.get_or_init(|| 12);
+    }
+
+    #[test]
+    fn waiters_see_the_blocking_panic_too() {
+        let cell = LazyCell::<u64, Blocking>::new();
+        let started = std::sync::Barrier::new(2);
+        thread::scope(|s| {
+            let initializer = s.spawn(|| {
+                cell.get_or_init(|| {
+                    started.wait();
+                    // usually long enough for the waiter to block on RUNNING. If not, it finds POISONED instead
+                    thread::sleep(std::time::Duration::from_millis(20));
+                    panic!("boom")
+                });
+            });
+            started.wait();
+            let waiter = s.spawn(|| *cell.get_or_init(|| 12));
+            assert!(initializer.join().is_err());
+            assert!(
+                waiter.join().is_err(),
+                "a waiter got a value from a poisoned cell"
+            );
+        });
+    }
+}
@@ -2,6 +2,8 @@ use std::sync::{
     atomic::{AtomicU64, Ordering},
     Once, OnceLock,
 };
+
+use super::id_allocator::AtomicInteger;
 /*
   The problem with this is that it another thread can call get_x()
   and then can cause a "Race"
@@ -27,18 +29,36 @@ pub fn get_x() -> u64 {
     x
 }
 
+// X is only loaded after call_once returns.
+// Loading it before call_once meant a thread that lost the race
+// would wait for the winner to finish and then return its own stale 0.
+// verification::explorer finds that schedule.
+// See lazy_cell.rs for a cell where that can't happen.
 pub fn get_x_once() -> u64 {
     static START: Once = Once::new();
     static X: AtomicU64 = AtomicU64::new(0);
-    let mut x = X.load(Ordering::Relaxed);
+    get_x_once_with(&START, &X)
+}
+
+// What get_x_once needs from a Once, so the explorer can run it on its own.
+pub trait CallOnce {
+    fn call_once(&self, f: impl FnOnce());
+}
 
-    START.call_once(|| {
+impl CallOnce for Once {
+    fn call_once(&self, f: impl FnOnce()) {
+        self.call_once(f)
+    }
+}
+
+// get_x_once with its statics passed in
+pub fn get_x_once_with<A: AtomicInteger<Value = u64>>(start: &impl CallOnce, x: &A) -> u64 {
+    start.call_once(|| {
         // do some expensive calculation to calc the value
-        x = 12;
-        X.store(12, Ordering::Relaxed);
+        x.store(12, Ordering::Relaxed);
     });
 
-    x
+    x.load(Ordering::Relaxed)
 }
 
 // much clearer and simpler
@@ -1,62 +1,38 @@
-use std::{
-    io::stdin,
-    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
-    thread,
-    time::Duration,
-};
+use std::{io::BufRead, sync::Arc, thread, time::Duration};
 
-pub fn stop_flag() {
-    static STOP: AtomicBool = AtomicBool::new(false);
+use super::{clock::Clock, console::Console, progress::ProgressTracker};
 
-    // spawn a thread to do the work.
-    let background_thread = thread::spawn(|| {
-        while !STOP.load(Ordering::Relaxed) {
-            println!("background thread working");
-            thread::sleep(Duration::from_secs(1));
-        }
-    });
-
-    for line in stdin().lines() {
-        match line.unwrap().as_str() {
-            "help" => println!("commands: help, stop"),
-            "stop" => break,
-            cmd => println!("unknown command: {cmd:?}"),
-        }
-    }
-
-    STOP.store(true, Ordering::Relaxed);
-
-    background_thread.join().unwrap();
+// The STOP flag used to be a `static STOP: AtomicBool`, checked with a Relaxed load before every one second sleep.
+// Now every worker has its own CancellationToken, and waiting on it parks instead of sleeping,
+// so a worker stops as soon as "stop" is entered instead of finishing its sleep first.
+// The commands (help, stop, pause, status, rate, spawn, kill, ...) live in console.rs,
+// and come from stdin, a file, or anything else that implements BufRead.
+pub fn stop_flag(commands: impl BufRead, interval: Duration, clock: Arc<dyn Clock>) {
+    Console::with_clock(interval, clock).run(commands);
+    println!("stopped every background thread");
 }
 
-pub fn progress_reporting() {
-    let num_done = AtomicUsize::new(0);
-    let main_thread = thread::current();
+// Every sleep and wait goes through `clock`, so on a VirtualClock this takes no time at all.
+pub fn progress_reporting(items: usize, work: Duration, clock: Arc<dyn Clock>) {
+    let progress = ProgressTracker::with_clock(items, clock.clone());
 
     thread::scope(|s| {
-        // background thread to process all 100 items
-
-        s.spawn(|| {
-            for i in 0..100 {
+        // background thread to process all the items
+        let reporter = progress.reporter();
+        let clock = &*clock;
+        clock.spawn_scoped(s, move || {
+            for _ in 0..items {
                 // presuming that the processing takes a bunch of time
-                thread::sleep(Duration::from_millis(75));
-                num_done.store(i + 1, Ordering::Relaxed);
-                if i == 99 {
-                    main_thread.unpark();
-                }
+                clock.sleep(work);
+                reporter.inc();
             }
         });
 
         // why can't this be put outside the thread::scope closure?
-        loop {
-            let n = num_done.load(Ordering::Relaxed);
-            if n == 100 {
-                break;
-            };
-            println!("Working.. {n}/100 done");
-            thread::park_timeout(Duration::from_secs(1));
-            // thread::sleep(Duration::from_secs(1));
-        }
+        progress.wait_with(
+            |n, total| println!("Working.. {n}/{total} done"),
+            Duration::from_secs(1),
+        );
     });
 
     println!("Done");
@@ -64,31 +40,41 @@ pub fn progress_reporting() {
 
 // https://marabos.nl/atomics/atomics.html#example-progress-reporting-from-multiple-threads
 
-pub fn progress_reporting_multiple_threads() {
-    let num_done = &AtomicUsize::new(0);
-
-    let main_thread = thread::current();
+// The tracker unparks the main thread when the last item is done, so it no longer waits out the full timeout
+// (the done counter is Release/Acquire, not Relaxed: it's the MP test in ch_3_memory_ordering::litmus,
+// and that's what makes the items' results visible to the thread that sees them counted)
+pub fn progress_reporting_multiple_threads(
+    threads: usize,
+    items: usize,
+    work: Duration,
+    clock: Arc<dyn Clock>,
+) {
+    let progress = ProgressTracker::with_clock(items, clock.clone());
 
     thread::scope(|s| {
-        for t in 0..4 {
-            s.spawn(move || {
-                thread::sleep(Duration::from_millis(75));
-                for i in 0..25 {
+        let clock = &*clock;
+        for t in 0..threads {
+            // split the items as evenly as possible between the threads
+            let share = items / threads + usize::from(t < items % threads);
+            if share == 0 {
+                // nothing to report, and a virtual clock would have no one left to wake it up
+                continue;
+            }
+            let reporter = progress.reporter();
+            clock.spawn_scoped(s, move || {
+                clock.sleep(work);
+                for i in 0..share {
                     // simulate work being done
                     println!("thread: {t}, i: {i}");
-                    thread::sleep(Duration::from_millis(75));
-                    num_done.fetch_add(1, Ordering::Relaxed);
+                    clock.sleep(work);
+                    reporter.inc();
                 }
             });
         }
 
-        loop {
-            let n = num_done.load(Ordering::Relaxed);
-            if n == 100 {
-                break;
-            }
-            println!("processed {}/100", n);
-            thread::park_timeout(Duration::from_secs(1));
-        }
+        progress.wait_with(
+            |n, total| println!("processed {n}/{total}"),
+            Duration::from_secs(1),
+        );
     })
 }
@@ -1,6 +1,15 @@
+pub mod cancellation;
+pub mod clock;
 pub mod compare_exchange;
+pub mod console;
 pub mod fetch_add_example;
 pub mod fetch_modify;
+pub mod histogram;
+pub mod id_allocator;
+pub mod id_recycler;
+pub mod lazy_cell;
 pub mod lazy_init;
 pub mod load_and_store;
+pub mod progress;
+pub mod race_once_box;
 pub mod statistics;
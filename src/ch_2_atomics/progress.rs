@@ -0,0 +1,108 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, Thread},
+    time::Duration,
+};
+
+use super::clock::{Clock, RealClock};
+
+/*
+  The progress reporting pattern from load_and_store, pulled out into a reusable type.
+
+  Workers get a Reporter (cloneable, so one per thread) and bump a shared counter.
+  One thread calls wait_with, which parks with a timeout and calls back with the progress each time it wakes.
+  Whichever reporter finishes the last item unparks the waiter right away, instead of it waiting out the timeout.
+  Both the parking and the unparking go through the tracker's Clock, so it works on a VirtualClock too.
+
+  The counter uses Release/Acquire instead of Relaxed,
+  so everything the workers did before reporting is visible to the waiter once wait_with returns.
+*/
+pub struct ProgressTracker {
+    inner: Arc<Inner>,
+}
+
+#[derive(Clone)]
+pub struct Reporter {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    done: AtomicUsize,
+    total: usize,
+    clock: Arc<dyn Clock>,
+    // Only locked when registering the waiter and when the last item finishes, never per item.
+    waiter: Mutex<Option<Thread>>,
+}
+
+impl ProgressTracker {
+    pub fn new(total: usize) -> Self {
+        Self::with_clock(total, Arc::new(RealClock))
+    }
+
+    pub fn with_clock(total: usize, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                done: AtomicUsize::new(0),
+                total,
+                clock,
+                waiter: Mutex::new(None),
+            }),
+        }
+    }
+
+    pub fn reporter(&self) -> Reporter {
+        Reporter {
+            inner: self.inner.clone(),
+        }
+    }
+
+    pub fn done(&self) -> usize {
+        self.inner.done.load(Ordering::Acquire)
+    }
+
+    pub fn total(&self) -> usize {
+        self.inner.total
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.done() >= self.inner.total
+    }
+
+    // Blocks until every item is done, calling `callback(done, total)` roughly every `interval` until then.
+    pub fn wait_with(&self, mut callback: impl FnMut(usize, usize), interval: Duration) {
+        // register before the first check, so a reporter that finishes after our check is guaranteed to see us
+        *self.inner.waiter.lock().unwrap() = Some(thread::current());
+
+        loop {
+            let n = self.done();
+            if n >= self.inner.total {
+                break;
+            }
+            callback(n, self.inner.total);
+            // spurious wakeups just mean an extra callback
+            self.inner.clock.park_timeout(interval);
+        }
+
+        *self.inner.waiter.lock().unwrap() = None;
+    }
+}
+
+impl Reporter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: usize) {
+        let before = self.inner.done.fetch_add(n, Ordering::Release);
+        let total = self.inner.total;
+        // only the reporter that crosses the finish line wakes the waiter
+        if before < total && before + n >= total {
+            if let Some(waiter) = &*self.inner.waiter.lock().unwrap() {
+                self.inner.clock.unpark(waiter);
+            }
+        }
+    }
+}
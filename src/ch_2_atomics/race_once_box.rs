@@ -0,0 +1,207 @@
+use std::{
+    ptr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Barrier,
+    },
+    thread,
+};
+
+use crate::sync::atomic::AtomicPtr;
+
+/*
+  lazy_one_time_key_initialization, but for any heap allocated value instead of a non-zero u64.
+
+  Null plays the part of the 0 key. Every thread that finds it null builds and boxes its own value,
+  then tries to compare_exchange its pointer in. The first one wins,
+  and the losers free their allocation and use the winner's instead.
+
+  Unlike the u64 version, the orderings can't all be Relaxed here:
+  a thread that loads the pointer must also see the value behind it,
+  so the winning store is Release and every load of it is Acquire.
+*/
+pub struct RaceOnceBox<T> {
+    ptr: AtomicPtr<T>,
+}
+
+// The T is shared through &RaceOnceBox (needs Sync), and may be dropped by another thread than the one that created it (needs Send)
+unsafe impl<T: Send + Sync> Sync for RaceOnceBox<T> {}
+unsafe impl<T: Send> Send for RaceOnceBox<T> {}
+
+impl<T> Default for RaceOnceBox<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> RaceOnceBox<T> {
+    pub const fn new() -> Self {
+        Self {
+            ptr: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        let p = self.ptr.load(Ordering::Acquire);
+        // non-null pointers always come from a Box that lives until self is dropped
+        unsafe { p.as_ref() }
+    }
+
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        if let Some(v) = self.get() {
+            return v;
+        }
+        let new = Box::into_raw(Box::new(f()));
+        match self
+            .ptr
+            .compare_exchange(ptr::null_mut(), new, Ordering::Release, Ordering::Acquire)
+        {
+            Ok(_) => unsafe { &*new },
+            Err(winner) => {
+                // we lost the race, free our value and return the one that is in there
+                drop(unsafe { Box::from_raw(new) });
+                unsafe { &*winner }
+            }
+        }
+    }
+}
+
+impl<T> Drop for RaceOnceBox<T> {
+    fn drop(&mut self) {
+        let p = *self.ptr.get_mut();
+        if !p.is_null() {
+            drop(unsafe { Box::from_raw(p) });
+        }
+    }
+}
+
+// Counts how many values were built and dropped, so leaks and double frees show up as a mismatch.
+struct Tracked<'a> {
+    id: usize,
+    drops: &'a AtomicUsize,
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        self.drops.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/*
+  Every thread races to initialize the same box with its own value.
+  Only one of them wins, the losers' values are dropped straight away, and the winner's with the box.
+*/
+pub fn race_once_box_demo(threads: usize) {
+    let drops = AtomicUsize::new(0);
+    let built = AtomicUsize::new(0);
+    let cell = RaceOnceBox::new();
+    let start = Barrier::new(threads);
+
+    let seen: Vec<usize> = thread::scope(|s| {
+        let handles: Vec<_> = (0..threads)
+            .map(|id| {
+                let (cell, built, drops, start) = (&cell, &built, &drops, &start);
+                s.spawn(move || {
+                    start.wait();
+                    cell.get_or_init(|| {
+                        built.fetch_add(1, Ordering::Relaxed);
+                        // give the other threads a chance to start building too
+                        thread::yield_now();
+                        Tracked { id, drops }
+                    })
+                    .id
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    let winner = cell.get().unwrap().id;
+    println!(
+        "{} value(s) built, thread {winner} won, every thread saw {seen:?}",
+        built.load(Ordering::Relaxed)
+    );
+    println!(
+        "{} dropped while the box was alive",
+        drops.load(Ordering::Relaxed)
+    );
+    drop(cell);
+    println!("{} after it was dropped", drops.load(Ordering::Relaxed));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One race on 8 threads: every thread gets the winner, losers are dropped at once and the winner with the box.
+    fn race() {
+        const THREADS: usize = 8;
+        let drops = AtomicUsize::new(0);
+        let built = AtomicUsize::new(0);
+        let cell = RaceOnceBox::new();
+        let start = Barrier::new(THREADS);
+
+        let seen: Vec<usize> = thread::scope(|s| {
+            let handles: Vec<_> = (0..THREADS)
+                .map(|id| {
+                    let (cell, built, drops, start) = (&cell, &built, &drops, &start);
+                    s.spawn(move || {
+                        start.wait();
+                        cell.get_or_init(|| {
+                            built.fetch_add(1, Ordering::Relaxed);
+                            thread::yield_now();
+                            Tracked { id, drops }
+                        })
+                        .id
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        let winner = cell.get().unwrap().id;
+        assert_eq!(seen, vec![winner; THREADS]);
+        let built = built.into_inner();
+        assert!((1..=THREADS).contains(&built));
+        assert_eq!(
+            drops.load(Ordering::Relaxed),
+            built - 1,
+            "a loser wasn't dropped"
+        );
+        drop(cell);
+        assert_eq!(
+            drops.into_inner(),
+            built,
+            "the winner wasn't dropped exactly once"
+        );
+    }
+
+    #[test]
+    fn one_winner_and_every_value_dropped_once() {
+        for _ in 0..100 {
+            race();
+        }
+    }
+
+    #[test]
+    fn get_before_and_after_init() {
+        let cell = RaceOnceBox::new();
+        assert!(cell.get().is_none());
+        assert_eq!(*cell.get_or_init(|| 1), 1);
+        assert_eq!(*cell.get_or_init(|| 2), 1);
+        assert_eq!(cell.get(), Some(&1));
+    }
+
+    #[test]
+    fn drops_its_value_only_if_it_has_one() {
+        let drops = AtomicUsize::new(0);
+        drop(RaceOnceBox::<Tracked>::new());
+        let cell = RaceOnceBox::new();
+        cell.get_or_init(|| Tracked {
+            id: 0,
+            drops: &drops,
+        });
+        drop(cell);
+        assert_eq!(drops.into_inner(), 1);
+    }
+}
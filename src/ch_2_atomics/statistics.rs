@@ -1,55 +1,287 @@
 use std::{
-    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    hint,
+    sync::atomic::{fence, AtomicU64, Ordering},
+    sync::Arc,
     thread,
-    time::{Duration, Instant},
+    time::Duration,
 };
 
 use rand::Rng;
+
+use super::{clock::Clock, histogram::Histogram};
+
 /*
- Some problems here are that we could briefly be reporting an innaccurate average, since the main thread can load the values after a thread has incremented num_done but before it has updated total_time.
+  The stats below originally lived in three separate atomics (num_done, total_time, max_time),
+  so the main thread could load them in between a worker's updates and report an inaccurate average.
 
- All three values could be placed inside a Mutex, slowing things down further.
+  StatsCollector keeps all the fields behind a sequence lock (seqlock) instead of a Mutex:
+  - A writer bumps `seq` to an odd number, updates the fields, then bumps it to the next even number.
+    The odd value doubles as a tiny spin lock between writers, so there is still no Mutex on the record path.
+  - A reader loads `seq`, reads the fields, then checks `seq` is unchanged and even.
+    If it changed, a write overlapped the read and it simply retries.
+
+  The fields themselves are still atomics (with Relaxed loads and stores),
+  since a reader overlapping a writer would otherwise be a data race even if it throws the values away.
 */
-pub fn stats() {
-    let num_done = &AtomicUsize::new(0);
-    let total_time = &AtomicU64::new(0);
-    let max_time = &AtomicU64::new(0);
+pub struct StatsCollector {
+    seq: AtomicU64,
+    num_done: AtomicU64,
+    // all times are in microseconds
+    total_time: AtomicU64,
+    max_time: AtomicU64,
+    min_time: AtomicU64,
+    // f64 bits, since the squares of microsecond timings can get large quickly
+    sum_of_squares: AtomicU64,
+}
+
+// Every field comes from the same instant, between two writes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Snapshot {
+    pub num_done: u64,
+    pub total_time: Duration,
+    pub max_time: Duration,
+    pub min_time: Duration,
+    pub sum_of_squares: f64,
+}
+
+impl Default for StatsCollector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StatsCollector {
+    pub const fn new() -> Self {
+        Self {
+            seq: AtomicU64::new(0),
+            num_done: AtomicU64::new(0),
+            total_time: AtomicU64::new(0),
+            max_time: AtomicU64::new(0),
+            min_time: AtomicU64::new(u64::MAX),
+            sum_of_squares: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, time_taken: Duration) {
+        let micros = time_taken.as_micros() as u64;
+
+        // take the write side: even -> odd
+        let mut seq = self.seq.load(Ordering::Relaxed);
+        loop {
+            if seq % 2 == 1 {
+                hint::spin_loop();
+                seq = self.seq.load(Ordering::Relaxed);
+                continue;
+            }
+            match self
+                .seq
+                .compare_exchange_weak(seq, seq + 1, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => break,
+                Err(s) => seq = s,
+            }
+        }
+        // keeps the field stores below from being reordered before the odd seq becomes visible
+        fence(Ordering::Release);
+
+        // we are the only writer now, so plain load + store is enough
+        let num_done = self.num_done.load(Ordering::Relaxed);
+        self.num_done.store(num_done + 1, Ordering::Relaxed);
+        let total = self.total_time.load(Ordering::Relaxed);
+        self.total_time.store(total + micros, Ordering::Relaxed);
+        let max = self.max_time.load(Ordering::Relaxed);
+        self.max_time.store(max.max(micros), Ordering::Relaxed);
+        let min = self.min_time.load(Ordering::Relaxed);
+        self.min_time.store(min.min(micros), Ordering::Relaxed);
+        let squares = f64::from_bits(self.sum_of_squares.load(Ordering::Relaxed));
+        let squares = squares + (micros as f64) * (micros as f64);
+        self.sum_of_squares
+            .store(squares.to_bits(), Ordering::Relaxed);
+
+        // release the write side: odd -> even, publishing the field stores
+        self.seq.store(seq + 2, Ordering::Release);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        loop {
+            let before = self.seq.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                hint::spin_loop();
+                continue;
+            }
+
+            let num_done = self.num_done.load(Ordering::Relaxed);
+            let total_time = self.total_time.load(Ordering::Relaxed);
+            let max_time = self.max_time.load(Ordering::Relaxed);
+            let min_time = self.min_time.load(Ordering::Relaxed);
+            let sum_of_squares = self.sum_of_squares.load(Ordering::Relaxed);
+
+            // keeps the field loads above from being reordered after the second seq load
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == before {
+                return Snapshot {
+                    num_done,
+                    total_time: Duration::from_micros(total_time),
+                    max_time: Duration::from_micros(max_time),
+                    min_time: Duration::from_micros(if num_done == 0 { 0 } else { min_time }),
+                    sum_of_squares: f64::from_bits(sum_of_squares),
+                };
+            }
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn average(&self) -> Option<Duration> {
+        (self.num_done > 0).then(|| self.total_time / self.num_done as u32)
+    }
+
+    // population standard deviation: sqrt(E[x^2] - E[x]^2)
+    pub fn stddev(&self) -> Option<Duration> {
+        if self.num_done == 0 {
+            return None;
+        }
+        let n = self.num_done as f64;
+        let mean = self.total_time.as_micros() as f64 / n;
+        let variance = (self.sum_of_squares / n - mean * mean).max(0.0);
+        Some(Duration::from_secs_f64(variance.sqrt() / 1_000_000.0))
+    }
+}
+
+/*
+ stats now records into a StatsCollector, so the reported average, peak, min and stddev
+ always come from the same set of finished items.
+
+ Each thread also records into its own Histogram shard, which the reporting loop merges for percentiles.
+ The work and the reporting loop sleep on `clock`, so on a VirtualClock the timings are exactly the simulated work.
+*/
+pub fn stats(threads: usize, items: usize, sleep_scale: f64, clock: Arc<dyn Clock>) {
+    let collector = &StatsCollector::new();
+    let shards: &Vec<Histogram> = &(0..threads).map(|_| Histogram::new()).collect();
 
     thread::scope(|s| {
-        // four thread to process all 100 items, 25 each
-        for t in 0..4 {
-            s.spawn(|| {
-                for i in 0..25 {
-                    let start = Instant::now();
+        let clock = &*clock;
+        // the threads split the items between them, e.g. four threads with 25 items each
+        for (t, shard) in shards.iter().enumerate() {
+            let share = items / threads + usize::from(t < items % threads);
+            clock.spawn_scoped(s, move || {
+                for _ in 0..share {
+                    let start = clock.now();
                     let mut rng = rand::thread_rng();
-                    thread::sleep(Duration::from_millis(rng.gen_range(200..300) + 1));
-                    let time_taken = start.elapsed().as_micros() as u64;
-                    num_done.fetch_add(1, Ordering::Relaxed);
-                    total_time.fetch_add(time_taken, Ordering::Relaxed);
-                    max_time.fetch_max(time_taken, Ordering::Relaxed);
+                    let work = Duration::from_millis(rng.gen_range(200..300) + 1);
+                    clock.sleep(work.mul_f64(sleep_scale));
+                    let time_taken = clock.now() - start;
+                    collector.record(time_taken);
+                    shard.record(time_taken);
                 }
             });
         }
 
         loop {
-            let total_time = Duration::from_micros(total_time.load(Ordering::Relaxed));
-            let max_time = Duration::from_micros(max_time.load(Ordering::Relaxed));
-            let n = num_done.load(Ordering::Relaxed);
-            if n == 100 {
+            let snapshot = collector.snapshot();
+            let n = snapshot.num_done;
+            if n == items as u64 {
                 break;
             }
-            if n == 0 {
-                println!("Working.. nothing done yet.");
-            } else {
+            match (snapshot.average(), snapshot.stddev()) {
+                (Some(average), Some(stddev)) => println!(
+                    "Working.. {n}/{items} done, {average:?} average, {:?} peak, {:?} min, {stddev:?} stddev",
+                    snapshot.max_time, snapshot.min_time,
+                ),
+                _ => println!("Working.. nothing done yet."),
+            }
+            if let Some(p) = merge(shards).percentiles() {
                 println!(
-                    "Working.. {n}/100 done, {:?} average, {:?} peak",
-                    total_time / n as u32,
-                    max_time
+                    "           p50 {:?}, p90 {:?}, p99 {:?}, p999 {:?}",
+                    p.p50, p.p90, p.p99, p.p999
                 );
             }
-            thread::sleep(Duration::from_secs(1));
+            clock.sleep(Duration::from_secs(1).mul_f64(sleep_scale));
         }
     });
 
-    println!("Done!");
+    let histogram = merge(shards);
+    if let Some(p) = histogram.percentiles() {
+        println!(
+            "Done! {} items, {:?} peak, p50 {:?}, p90 {:?}, p99 {:?}, p999 {:?}",
+            histogram.count(),
+            histogram.max(),
+            p.p50,
+            p.p90,
+            p.p99,
+            p.p999
+        );
+    } else {
+        println!("Done!");
+    }
+}
+
+fn merge(shards: &[Histogram]) -> Histogram {
+    let merged = Histogram::new();
+    for shard in shards {
+        merged.merge(shard);
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn empty_snapshot() {
+        let snapshot = StatsCollector::new().snapshot();
+        assert_eq!(snapshot.num_done, 0);
+        assert_eq!(snapshot.min_time, Duration::ZERO);
+        assert_eq!(snapshot.average(), None);
+        assert_eq!(snapshot.stddev(), None);
+    }
+
+    #[test]
+    fn average_min_max_and_stddev() {
+        let collector = StatsCollector::new();
+        for micros in [100, 200, 300] {
+            collector.record(Duration::from_micros(micros));
+        }
+        let snapshot = collector.snapshot();
+        assert_eq!(snapshot.num_done, 3);
+        assert_eq!(snapshot.average(), Some(Duration::from_micros(200)));
+        assert_eq!(snapshot.min_time, Duration::from_micros(100));
+        assert_eq!(snapshot.max_time, Duration::from_micros(300));
+        // sqrt(((100)^2 + 0 + (100)^2) / 3)
+        let stddev = snapshot.stddev().unwrap().as_secs_f64() * 1e6;
+        assert!((stddev - 81.65).abs() < 0.01, "{stddev}");
+    }
+
+    // Every record is the same 10µs, so any snapshot that mixes two records' fields shows up as a mismatch.
+    #[test]
+    fn snapshots_never_see_half_a_record() {
+        let collector = StatsCollector::new();
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    for _ in 0..20_000 {
+                        collector.record(Duration::from_micros(10));
+                    }
+                });
+            }
+            s.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    let snapshot = collector.snapshot();
+                    let n = snapshot.num_done;
+                    assert_eq!(snapshot.total_time, Duration::from_micros(10 * n));
+                    assert_eq!(snapshot.sum_of_squares, 100.0 * n as f64);
+                    if n > 0 {
+                        assert_eq!(snapshot.max_time, Duration::from_micros(10));
+                    }
+                }
+            });
+            while collector.snapshot().num_done < 60_000 {
+                thread::yield_now();
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+    }
 }
@@ -0,0 +1,405 @@
+use std::{
+    collections::BTreeMap,
+    fmt, hint,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Barrier,
+    },
+    thread,
+};
+
+// https://marabos.nl/atomics/memory-ordering.html
+
+/*
+  Litmus tests: tiny multi-threaded programs whose outcome shows whether the hardware (and compiler)
+  reordered memory operations. Every atomic in ch_2_atomics is Relaxed, and e.g. progress_reporting infers
+  "the work is done" from a counter. That's the message passing pattern below, so this shows which outcomes
+  the memory model allows there, and which ones this machine actually produces.
+
+  Every test runs in batches of `BATCH` independent instances (their own x and y). Each thread runs its part of
+  every instance in a batch, then they all meet at a Barrier, and the main thread tallies the registers and resets them.
+  A reordering only shows up when the threads run the *same* instance at the same time, though,
+  and one instance takes nanoseconds while the threads leave a Barrier microseconds apart.
+  So like litmus7's "user" mode, the threads also meet at the start of every instance:
+  each one bumps the instance's `ready` counter and spins until all of them have, and then they're off within a
+  cache miss of each other. With fewer CPUs than threads they can't run at the same time at all,
+  so litmus_suite runs far fewer iterations there, and says so.
+
+  Every access in a test uses the orderings of the chosen Model:
+  - Relaxed:        everything Relaxed
+  - AcquireRelease: stores Release, loads Acquire
+  - SeqCst:         everything SeqCst
+  An outcome is marked FORBIDDEN when the memory model rules it out for that Model.
+  Allowed outcomes don't have to show up: x86 for example never reorders two stores, so it never shows
+  the Relaxed message passing reordering that ARM does.
+  verification::weak_memory enumerates the allowed outcomes instead, for C++20 and for x86.
+*/
+
+const BATCH: usize = 1000;
+// how long a thread spins at the start of an instance before it yields to the threads it's waiting for
+const SPINS: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    Relaxed,
+    AcquireRelease,
+    SeqCst,
+}
+
+impl Model {
+    pub const ALL: [Model; 3] = [Model::Relaxed, Model::AcquireRelease, Model::SeqCst];
+
+    fn store(self) -> Ordering {
+        match self {
+            Model::Relaxed => Ordering::Relaxed,
+            Model::AcquireRelease => Ordering::Release,
+            Model::SeqCst => Ordering::SeqCst,
+        }
+    }
+
+    fn load(self) -> Ordering {
+        match self {
+            Model::Relaxed => Ordering::Relaxed,
+            Model::AcquireRelease => Ordering::Acquire,
+            Model::SeqCst => Ordering::SeqCst,
+        }
+    }
+}
+
+#[derive(Default)]
+#[repr(align(64))]
+struct Instance {
+    x: AtomicU32,
+    y: AtomicU32,
+    // registers, each only written by one thread, read by the main thread after the batch
+    r: [AtomicU32; 4],
+    // how many threads have arrived at this instance
+    ready: AtomicU32,
+}
+
+impl Instance {
+    fn reset(&self) {
+        self.x.store(0, Ordering::Relaxed);
+        self.y.store(0, Ordering::Relaxed);
+        for r in &self.r {
+            r.store(0, Ordering::Relaxed);
+        }
+        self.ready.store(0, Ordering::Relaxed);
+    }
+
+    // Returns once all `threads` are here. Only timing, so Relaxed: an Acquire here could hide the reorderings.
+    fn start(&self, threads: u32) {
+        self.ready.fetch_add(1, Ordering::Relaxed);
+        let mut spins = 0;
+        while self.ready.load(Ordering::Relaxed) < threads {
+            if spins < SPINS {
+                hint::spin_loop();
+                spins += 1;
+            } else {
+                // the others aren't running, so let them
+                thread::yield_now();
+            }
+        }
+    }
+
+    fn set(&self, r: usize, value: u32) {
+        self.r[r].store(value, Ordering::Relaxed);
+    }
+}
+
+pub struct Litmus {
+    pub name: &'static str,
+    pub description: &'static str,
+    threads: usize,
+    registers: usize,
+    // one thread's part of the test
+    run: fn(thread: usize, i: &Instance, model: Model),
+    // the outcome people argue about, shown even when it never happens
+    interesting: &'static [u32],
+    forbidden: fn(outcome: &[u32], model: Model) -> bool,
+}
+
+pub const TESTS: &[Litmus] = &[
+    Litmus {
+        name: "MP",
+        description: "message passing: t0 writes x then y, t1 reads y then x (r0=y, r1=x)",
+        threads: 2,
+        registers: 2,
+        run: |t, i, m| match t {
+            0 => {
+                i.x.store(1, m.store());
+                i.y.store(1, m.store());
+            }
+            _ => {
+                i.set(0, i.y.load(m.load()));
+                i.set(1, i.x.load(m.load()));
+            }
+        },
+        // saw the flag, but not the data
+        interesting: &[1, 0],
+        forbidden: |o, m| o == [1, 0] && m != Model::Relaxed,
+    },
+    Litmus {
+        name: "SB",
+        description: "store buffering: t0 writes x reads y, t1 writes y reads x (r0=y, r1=x)",
+        threads: 2,
+        registers: 2,
+        run: |t, i, m| match t {
+            0 => {
+                i.x.store(1, m.store());
+                i.set(0, i.y.load(m.load()));
+            }
+            _ => {
+                i.y.store(1, m.store());
+                i.set(1, i.x.load(m.load()));
+            }
+        },
+        // both loads ran before the other thread's store became visible
+        interesting: &[0, 0],
+        forbidden: |o, m| o == [0, 0] && m == Model::SeqCst,
+    },
+    Litmus {
+        name: "LB",
+        description: "load buffering: t0 reads x writes y, t1 reads y writes x (r0=x, r1=y)",
+        threads: 2,
+        registers: 2,
+        run: |t, i, m| match t {
+            0 => {
+                i.set(0, i.x.load(m.load()));
+                i.y.store(1, m.store());
+            }
+            _ => {
+                i.set(1, i.y.load(m.load()));
+                i.x.store(1, m.store());
+            }
+        },
+        // both loads saw a store that comes after the other load
+        interesting: &[1, 1],
+        forbidden: |o, m| o == [1, 1] && m != Model::Relaxed,
+    },
+    Litmus {
+        name: "IRIW",
+        description: "independent reads of independent writes: t0 writes x, t1 writes y, \
+                      t2 reads x then y (r0, r1), t3 reads y then x (r2, r3)",
+        threads: 4,
+        registers: 4,
+        run: |t, i, m| match t {
+            0 => i.x.store(1, m.store()),
+            1 => i.y.store(1, m.store()),
+            2 => {
+                i.set(0, i.x.load(m.load()));
+                i.set(1, i.y.load(m.load()));
+            }
+            _ => {
+                i.set(2, i.y.load(m.load()));
+                i.set(3, i.x.load(m.load()));
+            }
+        },
+        // the readers disagree about which write happened first
+        interesting: &[1, 0, 1, 0],
+        forbidden: |o, m| o == [1, 0, 1, 0] && m == Model::SeqCst,
+    },
+    Litmus {
+        name: "CoRR",
+        description: "coherence: t0 writes x, t1 reads x twice (r0, r1)",
+        threads: 2,
+        registers: 2,
+        run: |t, i, m| match t {
+            0 => i.x.store(1, m.store()),
+            _ => {
+                i.set(0, i.x.load(m.load()));
+                i.set(1, i.x.load(m.load()));
+            }
+        },
+        // going back in time: every atomic has a single total modification order, even Relaxed ones
+        interesting: &[1, 0],
+        forbidden: |o, _| o == [1, 0],
+    },
+];
+
+pub struct Histogram {
+    pub test: &'static str,
+    pub model: Model,
+    pub iterations: u64,
+    counts: BTreeMap<Vec<u32>, u64>,
+    interesting: &'static [u32],
+    forbidden: fn(&[u32], Model) -> bool,
+}
+
+impl Histogram {
+    pub fn count(&self, outcome: &[u32]) -> u64 {
+        self.counts.get(outcome).copied().unwrap_or(0)
+    }
+
+    // forbidden outcomes that showed up anyway. Always empty, unless the compiler, the CPU or this code is broken.
+    pub fn violations(&self) -> Vec<&[u32]> {
+        self.counts
+            .keys()
+            .filter(|o| (self.forbidden)(o, self.model))
+            .map(Vec::as_slice)
+            .collect()
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} {:?}, {} runs",
+            self.test, self.model, self.iterations
+        )?;
+        let mut outcomes: Vec<&[u32]> = self.counts.keys().map(Vec::as_slice).collect();
+        if !outcomes.contains(&self.interesting) {
+            outcomes.push(self.interesting);
+            outcomes.sort();
+        }
+        for outcome in outcomes {
+            let count = self.count(outcome);
+            let mark = match ((self.forbidden)(outcome, self.model), count) {
+                (true, 0) => "forbidden",
+                (true, _) => "FORBIDDEN, BUT SEEN",
+                (false, 0) => "allowed, never seen here",
+                (false, _) => "allowed",
+            };
+            writeln!(f, "  {outcome:?} {count:>10}  {mark}")?;
+        }
+        Ok(())
+    }
+}
+
+pub fn run(test: &Litmus, model: Model, iterations: u64) -> Histogram {
+    let instances: Vec<Instance> = (0..BATCH).map(|_| Instance::default()).collect();
+    let batches = iterations.div_ceil(BATCH as u64);
+    let barrier = Barrier::new(test.threads + 1);
+    let mut counts = BTreeMap::new();
+
+    thread::scope(|s| {
+        for t in 0..test.threads {
+            let (instances, barrier) = (&instances, &barrier);
+            s.spawn(move || {
+                for _ in 0..batches {
+                    barrier.wait();
+                    for i in instances {
+                        i.start(test.threads as u32);
+                        (test.run)(t, i, model);
+                    }
+                    barrier.wait();
+                }
+            });
+        }
+        for _ in 0..batches {
+            barrier.wait();
+            // the threads run the batch
+            barrier.wait();
+            for i in &instances {
+                let outcome: Vec<u32> = i.r[..test.registers]
+                    .iter()
+                    .map(|r| r.load(Ordering::Relaxed))
+                    .collect();
+                *counts.entry(outcome).or_insert(0) += 1;
+                i.reset();
+            }
+        }
+    });
+
+    Histogram {
+        test: test.name,
+        model,
+        iterations: batches * BATCH as u64,
+        counts,
+        interesting: test.interesting,
+        forbidden: test.forbidden,
+    }
+}
+
+// Every test under every model, `iterations` runs each.
+pub fn litmus_suite(iterations: u64) {
+    let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+    for test in TESTS {
+        println!("--- {}", test.description);
+        let iterations = if cpus < test.threads {
+            // every instance costs a few context switches, and nothing can be reordered by running at the same time
+            let fewer = (iterations / 1000).max(BATCH as u64);
+            println!(
+                "only {cpus} CPUs for {} threads, so they never run at the same time: {fewer} runs instead of {iterations}",
+                test.threads
+            );
+            fewer
+        } else {
+            iterations
+        };
+        for model in Model::ALL {
+            // a forbidden outcome that showed up is marked in the histogram
+            print!("{}", run(test, model, iterations));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test(name: &str) -> &'static Litmus {
+        TESTS.iter().find(|t| t.name == name).unwrap()
+    }
+
+    #[test]
+    fn no_forbidden_outcomes() {
+        for test in TESTS {
+            for model in Model::ALL {
+                let histogram = run(test, model, 2 * BATCH as u64);
+                assert_eq!(
+                    histogram.violations(),
+                    Vec::<&[u32]>::new(),
+                    "{} under {model:?}",
+                    test.name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn every_run_counted_once() {
+        let histogram = run(test("MP"), Model::SeqCst, BATCH as u64 + 1);
+        // rounded up to whole batches
+        assert_eq!(histogram.iterations, 2 * BATCH as u64);
+        assert_eq!(histogram.counts.values().sum::<u64>(), histogram.iterations);
+        // only r0=y and r1=x, each 0 or 1
+        for outcome in histogram.counts.keys() {
+            assert_eq!(outcome.len(), 2);
+            assert!(outcome.iter().all(|&r| r <= 1));
+        }
+        assert_eq!(histogram.count(&[7, 7]), 0);
+    }
+
+    #[test]
+    fn forbidden_per_model() {
+        let forbidden =
+            |name, outcome: &[u32]| Model::ALL.map(|model| (test(name).forbidden)(outcome, model));
+        // [Relaxed, AcquireRelease, SeqCst]
+        assert_eq!(forbidden("MP", &[1, 0]), [false, true, true]);
+        assert_eq!(forbidden("MP", &[1, 1]), [false, false, false]);
+        assert_eq!(forbidden("SB", &[0, 0]), [false, false, true]);
+        assert_eq!(forbidden("LB", &[1, 1]), [false, true, true]);
+        assert_eq!(forbidden("IRIW", &[1, 0, 1, 0]), [false, false, true]);
+        assert_eq!(forbidden("CoRR", &[1, 0]), [true, true, true]);
+        assert_eq!(forbidden("CoRR", &[0, 1]), [false, false, false]);
+    }
+
+    #[test]
+    fn shows_the_interesting_outcome_even_when_unseen() {
+        let histogram = Histogram {
+            test: "SB",
+            model: Model::SeqCst,
+            iterations: 3,
+            counts: BTreeMap::from([(vec![0, 1], 2), (vec![1, 1], 1)]),
+            interesting: test("SB").interesting,
+            forbidden: test("SB").forbidden,
+        };
+        let shown = histogram.to_string();
+        assert!(shown.starts_with("SB SeqCst, 3 runs\n"));
+        assert!(shown.contains("[0, 0]          0  forbidden\n"), "{shown}");
+        assert!(shown.contains("[0, 1]          2  allowed\n"), "{shown}");
+        assert!(histogram.violations().is_empty());
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    hint,
+    ops::{Deref, DerefMut},
+    thread,
+};
+
+use crate::{
+    ch_1_basics::Lock,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        cell::UnsafeCell,
+    },
+};
+
+// https://marabos.nl/atomics/building-spinlock.html
+
+/*
+- A spin lock is a mutex that busy-loops ("spins") while waiting, instead of asking the OS to put the thread to sleep.
+- Great when the lock is only ever held for a very short time, terrible when it's held for long:
+  every waiting thread burns a whole CPU core doing nothing.
+- Locking is a swap(true, Acquire) that returns false, unlocking is a store(false, Release).
+  The Acquire/Release pair makes everything done while holding the lock visible to the next thread that takes it.
+- The Guard unlocks on drop, like a MutexGuard, so the lock can't be forgotten
+  and the &mut T can't outlive the lock.
+ */
+
+// What to do between two failed attempts to take the lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    // just try again, as fast as possible
+    Spin,
+    // std::hint::spin_loop: tells the processor we're spinning, e.g. `pause` on x86
+    SpinLoopHint,
+    // spin_loop a doubling number of times, and once that gets long, yield to the OS scheduler instead
+    Exponential,
+}
+
+const MAX_SPIN_SHIFT: u32 = 6;
+
+impl Backoff {
+    fn wait(self, attempt: &mut u32) {
+        match self {
+            Backoff::Spin => {}
+            Backoff::SpinLoopHint => hint::spin_loop(),
+            Backoff::Exponential => {
+                if *attempt <= MAX_SPIN_SHIFT {
+                    for _ in 0..1 << *attempt {
+                        hint::spin_loop();
+                    }
+                    *attempt += 1;
+                } else {
+                    thread::yield_now();
+                }
+            }
+        }
+    }
+}
+
+/// The T ends up on whichever thread locks it, so it has to be Send:
+///
+/// ```compile_fail,E0277
+/// use atomics_and_locks::ch_4_spin_lock::SpinLock;
+/// let lock = SpinLock::new(std::rc::Rc::new(1_i32));
+/// std::thread::scope(|s| {
+///     s.spawn(|| lock.lock().clone());
+/// });
+/// ```
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+    backoff: Backoff,
+}
+
+// Only one thread can access the value at a time, so T only needs to be Send, not Sync. Same as std's Mutex.
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+pub struct Guard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+// The guard gives out &T, so sharing the guard shares the T.
+unsafe impl<T: Sync> Sync for Guard<'_, T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self::with_backoff(value, Backoff::SpinLoopHint)
+    }
+
+    pub const fn with_backoff(value: T, backoff: Backoff) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+            backoff,
+        }
+    }
+
+    pub fn lock(&self) -> Guard<'_, T> {
+        let mut attempt = 0;
+        while self.locked.swap(true, Ordering::Acquire) {
+            // wait with plain loads until it looks unlocked, so waiting threads don't keep
+            // pulling the cache line back and forth with writes
+            while self.locked.load(Ordering::Relaxed) {
+                self.backoff.wait(&mut attempt);
+            }
+        }
+        Guard { lock: self }
+    }
+
+    pub fn try_lock(&self) -> Option<Guard<'_, T>> {
+        if self.locked.swap(true, Ordering::Acquire) {
+            None
+        } else {
+            Some(Guard { lock: self })
+        }
+    }
+
+    // No other thread can hold a reference to it, so no locking needed
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T> Deref for Guard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // Safety: the existence of this Guard guarantees we've exclusively locked the lock.
+        self.lock.value.with(|v| unsafe { &*v })
+    }
+}
+
+impl<T> DerefMut for Guard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: the existence of this Guard guarantees we've exclusively locked the lock.
+        self.lock.value.with_mut(|v| unsafe { &mut *v })
+    }
+}
+
+impl<T> Drop for Guard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+impl<T: Send> Lock<T> for SpinLock<T> {
+    type Guard<'a>
+        = Guard<'a, T>
+    where
+        T: 'a;
+
+    fn lock(&self) -> Guard<'_, T> {
+        self.lock()
+    }
+
+    fn into_inner(self) -> T {
+        self.into_inner()
+    }
+}
@@ -0,0 +1,670 @@
+use std::{
+    error::Error,
+    fmt,
+    mem::MaybeUninit,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::sync::{
+    atomic::{fence, AtomicUsize, Ordering},
+    cell::UnsafeCell,
+};
+
+// https://www.1024cores.net/home/lock-free-algorithms/queues/bounded-mpmc-queue
+
+/*
+- A fixed size ring buffer, so a producer that's faster than its consumers blocks in send (backpressure)
+  instead of growing a VecDeque without limit like thread_parking_queue and condvar_usage do.
+- No mutex around the buffer. Every slot has its own sequence number that says whose turn it is:
+  - `seq == 2 * pos`:      empty, the sender that claims position `pos` may write it
+  - `seq == 2 * pos + 1`:  full, the receiver that claims position `pos` may read it
+  - after reading, the receiver sets it to `2 * (pos + capacity)`: empty again, for the sender one lap later.
+  (The usual version uses pos and pos + 1, but with a capacity of 1 a full slot would then look empty to the next lap.)
+  Senders claim positions by compare_exchanging `tail` forward, receivers `head`.
+  The slot's seq is stored with Release after writing/reading the value, and loaded with Acquire before touching it.
+- Only *waiting* goes through a mutex and condvar (a Signal), and only once a try_ has failed.
+  Someone that makes progress only touches the Signal's mutex if it sees a waiter.
+- Disconnection: when the last Sender is dropped, receivers get what's left and then Err(Disconnected).
+  When the last Receiver is dropped, send hands the value back in the error.
+ */
+
+fn empty(pos: usize) -> usize {
+    pos.wrapping_mul(2)
+}
+
+fn full(pos: usize) -> usize {
+    empty(pos).wrapping_add(1)
+}
+
+struct Slot<T> {
+    seq: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// Lets threads sleep until the other side made progress, with a waiter count so progress is free when nobody sleeps.
+struct Signal {
+    waiters: AtomicUsize,
+    lock: Mutex<()>,
+    condvar: Condvar,
+}
+
+impl Signal {
+    fn new() -> Self {
+        Self {
+            waiters: AtomicUsize::new(0),
+            lock: Mutex::new(()),
+            condvar: Condvar::new(),
+        }
+    }
+
+    // Calls `attempt` until it returns Some, sleeping in between. None once the deadline has passed.
+    fn wait_for<R>(
+        &self,
+        deadline: Option<Instant>,
+        mut attempt: impl FnMut() -> Option<R>,
+    ) -> Option<R> {
+        let mut guard = self.lock.lock().unwrap();
+        self.waiters.fetch_add(1, Ordering::Relaxed);
+        // pairs with the fence in notify: either we see their progress in `attempt`, or they see us waiting
+        fence(Ordering::SeqCst);
+        let result = loop {
+            if let Some(r) = attempt() {
+                break Some(r);
+            }
+            guard = match deadline {
+                None => self.condvar.wait(guard).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break None;
+                    }
+                    self.condvar.wait_timeout(guard, deadline - now).unwrap().0
+                }
+            };
+        };
+        self.waiters.fetch_sub(1, Ordering::Relaxed);
+        drop(guard);
+        result
+    }
+
+    fn notify_one(&self) {
+        fence(Ordering::SeqCst);
+        if self.waiters.load(Ordering::Relaxed) > 0 {
+            // taking the lock makes sure a waiter that's between `attempt` and sleeping doesn't miss this
+            drop(self.lock.lock().unwrap());
+            self.condvar.notify_one();
+        }
+    }
+
+    fn notify_all(&self) {
+        fence(Ordering::SeqCst);
+        if self.waiters.load(Ordering::Relaxed) > 0 {
+            drop(self.lock.lock().unwrap());
+            self.condvar.notify_all();
+        }
+    }
+}
+
+struct Channel<T> {
+    slots: Box<[Slot<T>]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    senders: AtomicUsize,
+    receivers: AtomicUsize,
+    not_empty: Signal,
+    not_full: Signal,
+}
+
+// Every value goes from one sender to one receiver, never shared, so T only needs to be Send.
+unsafe impl<T: Send> Sync for Channel<T> {}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+// The errors hand back the value that couldn't be sent, like std::sync::mpsc's.
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Disconnected(T),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SendTimeoutError<T> {
+    Timeout(T),
+    Disconnected(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+    Timeout,
+    Disconnected,
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("sending on a channel without receivers")
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("sending on a full channel"),
+            TrySendError::Disconnected(_) => f.write_str("sending on a channel without receivers"),
+        }
+    }
+}
+
+impl<T> fmt::Display for SendTimeoutError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendTimeoutError::Timeout(_) => f.write_str("timed out sending on a full channel"),
+            SendTimeoutError::Disconnected(_) => {
+                f.write_str("sending on a channel without receivers")
+            }
+        }
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("receiving on an empty channel without senders")
+    }
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => f.write_str("receiving on an empty channel"),
+            TryRecvError::Disconnected => RecvError.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvTimeoutError::Timeout => f.write_str("timed out receiving on an empty channel"),
+            RecvTimeoutError::Disconnected => RecvError.fmt(f),
+        }
+    }
+}
+
+impl<T: fmt::Debug> Error for SendError<T> {}
+impl<T: fmt::Debug> Error for TrySendError<T> {}
+impl<T: fmt::Debug> Error for SendTimeoutError<T> {}
+impl Error for RecvError {}
+impl Error for TryRecvError {}
+impl Error for RecvTimeoutError {}
+
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "capacity must be at least 1");
+    let channel = Arc::new(Channel {
+        slots: (0..capacity)
+            .map(|i| Slot {
+                seq: AtomicUsize::new(empty(i)),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            })
+            .collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        senders: AtomicUsize::new(1),
+        receivers: AtomicUsize::new(1),
+        not_empty: Signal::new(),
+        not_full: Signal::new(),
+    });
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver { channel },
+    )
+}
+
+impl<T> Channel<T> {
+    fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    // Err(value) if it's full
+    fn push(&self, value: T) -> Result<(), T> {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % self.capacity()];
+            let seq = slot.seq.load(Ordering::Acquire);
+            match seq.wrapping_sub(empty(pos)) as isize {
+                0 => match self.tail.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // Safety: claiming `pos` made this slot ours until we bump its seq
+                        slot.value.with_mut(|v| unsafe { (*v).write(value) });
+                        slot.seq.store(full(pos), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                },
+                // the receiver one lap behind hasn't emptied it yet
+                diff if diff < 0 => return Err(value),
+                // another sender claimed `pos` already
+                _ => pos = self.tail.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    // None if it's empty
+    fn pop(&self) -> Option<T> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % self.capacity()];
+            let seq = slot.seq.load(Ordering::Acquire);
+            match seq.wrapping_sub(full(pos)) as isize {
+                0 => match self.head.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // Safety: a full seq means the sender wrote it, and claiming `pos` made it ours
+                        let value = slot.value.with(|v| unsafe { (*v).assume_init_read() });
+                        slot.seq
+                            .store(empty(pos.wrapping_add(self.capacity())), Ordering::Release);
+                        return Some(value);
+                    }
+                    Err(current) => pos = current,
+                },
+                diff if diff < 0 => return None,
+                _ => pos = self.head.load(Ordering::Relaxed),
+            }
+        }
+    }
+}
+
+impl<T> Channel<T> {
+    // push and pop, plus checking the other side is still there. Nobody gets notified.
+    fn try_push(&self, value: T) -> Result<(), TrySendError<T>> {
+        if self.receivers.load(Ordering::Relaxed) == 0 {
+            return Err(TrySendError::Disconnected(value));
+        }
+        self.push(value).map_err(TrySendError::Full)
+    }
+
+    fn try_pop(&self) -> Result<T, TryRecvError> {
+        if let Some(value) = self.pop() {
+            return Ok(value);
+        }
+        if self.senders.load(Ordering::Acquire) == 0 {
+            // a last send could have landed between our pop and seeing the sender gone
+            return self.pop().ok_or(TryRecvError::Disconnected);
+        }
+        Err(TryRecvError::Empty)
+    }
+}
+
+impl<T> Drop for Channel<T> {
+    fn drop(&mut self) {
+        // sent but never received
+        while self.pop().is_some() {}
+    }
+}
+
+impl<T> Sender<T> {
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.channel.try_push(value)?;
+        self.channel.not_empty.notify_one();
+        Ok(())
+    }
+
+    // Blocks while the channel is full.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.send_until(value, None).map_err(|e| match e {
+            SendTimeoutError::Disconnected(value) | SendTimeoutError::Timeout(value) => {
+                SendError(value)
+            }
+        })
+    }
+
+    pub fn send_timeout(&self, value: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        self.send_until(value, Some(Instant::now() + timeout))
+    }
+
+    fn send_until(&self, value: T, deadline: Option<Instant>) -> Result<(), SendTimeoutError<T>> {
+        let mut value = match self.channel.try_push(value) {
+            Ok(()) => None,
+            Err(TrySendError::Disconnected(value)) => {
+                return Err(SendTimeoutError::Disconnected(value))
+            }
+            Err(TrySendError::Full(value)) => Some(value),
+        };
+        if value.is_some() {
+            // attempts under not_full's lock must not notify not_empty: a receiver holding
+            // not_empty's lock could be notifying not_full at the same time. So notify after.
+            let result = self.channel.not_full.wait_for(deadline, || {
+                match self.channel.try_push(value.take().unwrap()) {
+                    Ok(()) => Some(Ok(())),
+                    Err(TrySendError::Disconnected(v)) => Some(Err(v)),
+                    Err(TrySendError::Full(v)) => {
+                        value = Some(v);
+                        None
+                    }
+                }
+            });
+            match result {
+                Some(Ok(())) => {}
+                Some(Err(value)) => return Err(SendTimeoutError::Disconnected(value)),
+                None => return Err(SendTimeoutError::Timeout(value.unwrap())),
+            }
+        }
+        self.channel.not_empty.notify_one();
+        Ok(())
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.channel.capacity()
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let value = self.channel.try_pop()?;
+        self.channel.not_full.notify_one();
+        Ok(value)
+    }
+
+    // Blocks while the channel is empty, until something is sent or every Sender is gone.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.recv_until(None).map_err(|_| RecvError)
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        // same as in send_until: only notify not_full once we're out of not_empty's wait
+        let attempt = || match self.channel.try_pop() {
+            Ok(value) => Some(Ok(value)),
+            Err(TryRecvError::Disconnected) => Some(Err(RecvTimeoutError::Disconnected)),
+            Err(TryRecvError::Empty) => None,
+        };
+        let value = match attempt() {
+            Some(result) => result?,
+            None => self
+                .channel
+                .not_empty
+                .wait_for(deadline, attempt)
+                .unwrap_or(Err(RecvTimeoutError::Timeout))?,
+        };
+        self.channel.not_full.notify_one();
+        Ok(value)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.channel.capacity()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.channel.receivers.fetch_add(1, Ordering::Relaxed);
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.channel.senders.fetch_sub(1, Ordering::Release) == 1 {
+            // wake every receiver, to find out there's nothing coming anymore
+            self.channel.not_empty.notify_all();
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        if self.channel.receivers.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.channel.not_full.notify_all();
+        }
+    }
+}
+
+/*
+  condvar_usage with several producers and consumers, through a channel with room for only `capacity` items.
+  The producers are faster than the consumers, so they keep finding it full and have to wait:
+  the number of items in flight never goes over the capacity.
+  Returns how many items were received, their sum, and how often a producer found the channel full.
+*/
+fn pump(threads: usize, items: usize, capacity: usize, work: Duration) -> (usize, usize, usize) {
+    let (sender, receiver) = channel(capacity);
+    let full = AtomicUsize::new(0);
+    let sum = AtomicUsize::new(0);
+    let received = AtomicUsize::new(0);
+
+    thread::scope(|s| {
+        for t in 0..threads {
+            let sender = sender.clone();
+            let full = &full;
+            s.spawn(move || {
+                for i in (t..items).step_by(threads) {
+                    if let Err(TrySendError::Full(i)) = sender.try_send(i) {
+                        full.fetch_add(1, Ordering::Relaxed);
+                        sender.send(i).unwrap();
+                    }
+                }
+            });
+        }
+        // only the producers' clones are left, so the consumers stop once they're done
+        drop(sender);
+
+        for _ in 0..threads {
+            let receiver = receiver.clone();
+            let (sum, received) = (&sum, &received);
+            s.spawn(move || {
+                while let Ok(i) = receiver.recv() {
+                    sum.fetch_add(i, Ordering::Relaxed);
+                    received.fetch_add(1, Ordering::Relaxed);
+                    thread::sleep(work);
+                }
+            });
+        }
+    });
+    (received.into_inner(), sum.into_inner(), full.into_inner())
+}
+
+// pump, then the timeouts and both kinds of disconnection
+pub fn bounded_channel_demo(threads: usize, items: usize, capacity: usize, work: Duration) {
+    let (received, sum, full) = pump(threads, items, capacity, work);
+    println!(
+        "{received} of {items} items (sum {sum}) through a channel of {capacity}, producers found it full {full} times"
+    );
+
+    let (sender, receiver) = channel(1);
+    println!(
+        "recv_timeout on an empty channel: {:?}",
+        receiver.recv_timeout(work)
+    );
+    sender.send(1).unwrap();
+    println!("try_send on a full one:           {:?}", sender.try_send(2));
+    println!(
+        "send_timeout on a full one:       {:?}",
+        sender.send_timeout(2, work)
+    );
+
+    // what's left can still be received after the senders are gone
+    drop(sender);
+    println!("recv after the senders are gone:  {:?}", receiver.recv());
+    println!("and once it's empty:              {:?}", receiver.recv());
+
+    let (sender, receiver) = channel(1);
+    drop(receiver);
+    println!("send without receivers:           {:?}", sender.send(3));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    #[test]
+    fn every_item_received_exactly_once() {
+        for (threads, capacity) in [(1, 1), (4, 1), (4, 3), (2, 64)] {
+            let items = 500;
+            let (received, sum, _) = pump(threads, items, capacity, Duration::ZERO);
+            assert_eq!(received, items);
+            assert_eq!(sum, items * (items - 1) / 2);
+        }
+    }
+
+    #[test]
+    fn full_at_capacity_and_in_order() {
+        for capacity in [1, 2, 5] {
+            let (sender, receiver) = channel(capacity);
+            // a few laps around the ring
+            for lap in 0..3 {
+                for i in 0..capacity {
+                    sender.try_send(lap * capacity + i).unwrap();
+                }
+                assert_eq!(sender.try_send(99), Err(TrySendError::Full(99)));
+                for i in 0..capacity {
+                    assert_eq!(receiver.try_recv(), Ok(lap * capacity + i));
+                }
+                assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "capacity must be at least 1")]
+    fn zero_capacity() {
+        channel::<()>(0);
+    }
+
+    #[test]
+    fn timeouts() {
+        let (sender, receiver) = channel(1);
+        let start = Instant::now();
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        sender.send(1).unwrap();
+        assert_eq!(
+            sender.send_timeout(2, Duration::from_millis(10)),
+            Err(SendTimeoutError::Timeout(2))
+        );
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(10)), Ok(1));
+    }
+
+    #[test]
+    fn blocked_send_gets_in_once_there_is_room() {
+        let (sender, receiver) = channel(1);
+        sender.send(1).unwrap();
+        thread::scope(|s| {
+            let blocked = s.spawn(|| sender.send(2));
+            while receiver.channel.not_full.waiters.load(Ordering::Relaxed) == 0 {
+                thread::yield_now();
+            }
+            assert_eq!(receiver.recv(), Ok(1));
+            blocked.join().unwrap().unwrap();
+        });
+        assert_eq!(receiver.recv(), Ok(2));
+    }
+
+    #[test]
+    fn disconnection() {
+        let (sender, receiver) = channel(2);
+        sender.send(1).unwrap();
+        let second = sender.clone();
+        drop(sender);
+        second.send(2).unwrap();
+        drop(second);
+        // what's left can still be received after the senders are gone
+        assert_eq!(receiver.recv(), Ok(1));
+        assert_eq!(receiver.try_recv(), Ok(2));
+        assert_eq!(receiver.recv(), Err(RecvError));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+
+        let (sender, receiver) = channel(1);
+        drop(receiver);
+        assert_eq!(sender.send(3), Err(SendError(3)));
+        assert_eq!(sender.try_send(4), Err(TrySendError::Disconnected(4)));
+    }
+
+    #[test]
+    fn dropping_the_last_sender_wakes_the_receivers() {
+        let (sender, receiver) = channel::<()>(1);
+        thread::scope(|s| {
+            let receivers: Vec<_> = (0..3)
+                .map(|_| {
+                    let receiver = receiver.clone();
+                    s.spawn(move || receiver.recv())
+                })
+                .collect();
+            while receiver.channel.not_empty.waiters.load(Ordering::Relaxed) < 3 {
+                thread::yield_now();
+            }
+            drop(sender);
+            for r in receivers {
+                assert_eq!(r.join().unwrap(), Err(RecvError));
+            }
+        });
+    }
+
+    #[test]
+    fn unreceived_values_are_dropped() {
+        #[derive(Debug)]
+        struct Tracked<'a>(&'a AtomicUsize);
+        impl Drop for Tracked<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let drops = AtomicUsize::new(0);
+        let (sender, receiver) = channel(4);
+        for _ in 0..3 {
+            sender.send(Tracked(&drops)).unwrap();
+        }
+        drop(receiver.recv());
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+        drop((sender, receiver));
+        assert_eq!(drops.load(Ordering::Relaxed), 3);
+    }
+}
@@ -0,0 +1,2 @@
+pub mod bounded;
+pub mod oneshot;
@@ -0,0 +1,327 @@
+use std::{
+    error::Error,
+    fmt,
+    mem::MaybeUninit,
+    sync::Arc,
+    thread::{self, Thread},
+    time::Duration,
+};
+
+use crate::sync::{
+    atomic::{AtomicU8, AtomicUsize, Ordering},
+    cell::UnsafeCell,
+};
+
+// https://marabos.nl/atomics/building-channels.html#one-shot-channel
+
+/*
+- A channel for exactly one message, e.g. a single result back from a spawned thread, without join.
+- The message lives in an UnsafeCell<MaybeUninit<T>>; `state` says who may touch it:
+  - EMPTY:   nothing sent yet
+  - WAITING: nothing sent yet, and the receiver is parked. Its Thread is in `receiver` for the sender to unpark.
+  - READY:   the message is written, and not received yet
+  - TAKEN:   the receiver moved the message out
+  - CLOSED:  the sender was dropped without sending
+- send and recv take self by value, so the type system makes sure each happens at most once.
+- Whoever drops the channel last (the Arc) drops the message if it's still READY, so it can't leak.
+ */
+
+const EMPTY: u8 = 0;
+const WAITING: u8 = 1;
+const READY: u8 = 2;
+const TAKEN: u8 = 3;
+const CLOSED: u8 = 4;
+
+struct Channel<T> {
+    message: UnsafeCell<MaybeUninit<T>>,
+    // only written by the receiver before it sets WAITING, only read by the sender after it sees WAITING
+    receiver: UnsafeCell<Option<Thread>>,
+    state: AtomicU8,
+}
+
+// The message is sent from one thread to another, but never shared, so T only needs to be Send.
+unsafe impl<T: Send> Sync for Channel<T> {}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+    sent: bool,
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Canceled;
+
+impl fmt::Display for Canceled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("sender dropped without sending")
+    }
+}
+
+impl Error for Canceled {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    // nothing sent yet, try again later
+    Empty,
+    // nothing will ever be received (anymore): the sender is gone, or the message was already taken
+    Canceled,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => f.write_str("nothing sent yet"),
+            TryRecvError::Canceled => Canceled.fmt(f),
+        }
+    }
+}
+
+impl Error for TryRecvError {}
+
+/// The message moves to the receiver's thread, so it has to be Send:
+///
+/// ```compile_fail,E0277
+/// use atomics_and_locks::ch_5_channels::oneshot;
+/// let (sender, receiver) = oneshot::channel();
+/// std::thread::spawn(move || sender.send(std::rc::Rc::new(1_i32)));
+/// receiver.recv().unwrap();
+/// ```
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let channel = Arc::new(Channel {
+        message: UnsafeCell::new(MaybeUninit::uninit()),
+        receiver: UnsafeCell::new(None),
+        state: AtomicU8::new(EMPTY),
+    });
+    (
+        Sender {
+            channel: channel.clone(),
+            sent: false,
+        },
+        Receiver { channel },
+    )
+}
+
+impl<T> Sender<T> {
+    pub fn send(mut self, message: T) {
+        // Safety: only the one sender ever writes, and the receiver doesn't read before READY
+        self.channel
+            .message
+            .with_mut(|m| unsafe { (*m).write(message) });
+        self.sent = true;
+        self.finish(READY);
+    }
+
+    // Publishes READY or CLOSED, and wakes the receiver if it's parked.
+    fn finish(&self, state: u8) {
+        if self.channel.state.swap(state, Ordering::AcqRel) == WAITING {
+            // Safety: the receiver stored its Thread before setting WAITING, and won't touch it again
+            let receiver = self.channel.receiver.with(|r| unsafe { (*r).clone() });
+            receiver.unwrap().unpark();
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if !self.sent {
+            self.finish(CLOSED);
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    // Parks until the message arrives, or the sender is dropped without sending.
+    pub fn recv(self) -> Result<T, Canceled> {
+        // Safety: the sender only reads this after it sees WAITING, which we haven't set yet
+        self.channel
+            .receiver
+            .with_mut(|r| unsafe { *r = Some(thread::current()) });
+        let mut state = match self.channel.state.compare_exchange(
+            EMPTY,
+            WAITING,
+            Ordering::Release,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => WAITING,
+            Err(state) => state,
+        };
+        while state == WAITING {
+            thread::park();
+            state = self.channel.state.load(Ordering::Acquire);
+        }
+        self.take(state).map_err(|_| Canceled)
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let state = self.channel.state.load(Ordering::Acquire);
+        self.take(state)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.channel.state.load(Ordering::Relaxed) == READY
+    }
+
+    fn take(&self, state: u8) -> Result<T, TryRecvError> {
+        match state {
+            READY => {
+                self.channel.state.store(TAKEN, Ordering::Relaxed);
+                // Safety: READY means it's written, and TAKEN makes sure we only move it out once
+                Ok(self
+                    .channel
+                    .message
+                    .with(|m| unsafe { (*m).assume_init_read() }))
+            }
+            EMPTY => Err(TryRecvError::Empty),
+            _ => Err(TryRecvError::Canceled),
+        }
+    }
+}
+
+impl<T> Drop for Channel<T> {
+    fn drop(&mut self) {
+        // sent, but never received
+        if *self.state.get_mut() == READY {
+            unsafe { self.message.get_mut().assume_init_drop() }
+        }
+    }
+}
+
+struct Tracked<'a>(&'a AtomicUsize);
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/*
+  basics()'s t3 averaging thread, handing its result back through a oneshot instead of join.
+  Then the other ways it can go: a dropped sender, try_recv, and a message nobody receives.
+*/
+pub fn oneshot_demo(delay: Duration) {
+    let numbers = Vec::from_iter(0..=777);
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        thread::sleep(delay);
+        sender.send(numbers.iter().sum::<usize>() / numbers.len());
+    });
+    // parks until the thread is done
+    println!("average: {:?}", receiver.recv());
+
+    let (sender, receiver) = channel::<usize>();
+    thread::spawn(move || {
+        thread::sleep(delay);
+        drop(sender);
+    });
+    println!(
+        "dropped sender: {:?}",
+        receiver.recv().map_err(|e| e.to_string())
+    );
+
+    let (sender, mut receiver) = channel();
+    println!("try_recv before send: {:?}", receiver.try_recv());
+    sender.send("hi");
+    println!("try_recv after send:  {:?}", receiver.try_recv());
+    println!("and once more:        {:?}", receiver.try_recv());
+
+    let drops = AtomicUsize::new(0);
+    let (sender, receiver) = channel();
+    sender.send(Tracked(&drops));
+    drop(receiver);
+    println!(
+        "a message nobody received dropped {} time(s)",
+        drops.load(Ordering::Relaxed)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recv_waits_for_the_message() {
+        let (sender, receiver) = channel();
+        let t = thread::spawn(move || receiver.recv());
+        thread::sleep(Duration::from_millis(10));
+        sender.send(388);
+        assert_eq!(t.join().unwrap(), Ok(388));
+    }
+
+    #[test]
+    fn dropping_the_sender_cancels() {
+        let (sender, receiver) = channel::<usize>();
+        let t = thread::spawn(move || receiver.recv());
+        thread::sleep(Duration::from_millis(10));
+        drop(sender);
+        assert_eq!(t.join().unwrap(), Err(Canceled));
+    }
+
+    #[test]
+    fn sent_before_recv() {
+        let (sender, receiver) = channel();
+        sender.send("hi");
+        assert_eq!(receiver.recv(), Ok("hi"));
+    }
+
+    #[test]
+    fn try_recv() {
+        let (sender, mut receiver) = channel();
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        assert!(!receiver.is_ready());
+        sender.send("hi");
+        assert!(receiver.is_ready());
+        assert_eq!(receiver.try_recv(), Ok("hi"));
+        assert!(!receiver.is_ready());
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Canceled));
+
+        let (sender, mut receiver) = channel::<()>();
+        drop(sender);
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Canceled));
+    }
+
+    #[test]
+    fn messages_are_dropped_exactly_once() {
+        let drops = AtomicUsize::new(0);
+        let (sender, receiver) = channel();
+        sender.send(Tracked(&drops));
+        assert_eq!(drops.load(Ordering::Relaxed), 0);
+        drop(receiver);
+        assert_eq!(
+            drops.load(Ordering::Relaxed),
+            1,
+            "unreceived message leaked"
+        );
+
+        let (sender, receiver) = channel();
+        sender.send(Tracked(&drops));
+        drop(receiver.recv());
+        assert_eq!(
+            drops.load(Ordering::Relaxed),
+            2,
+            "received message dropped twice"
+        );
+
+        let (sender, receiver) = channel();
+        drop(receiver);
+        sender.send(Tracked(&drops));
+        assert_eq!(
+            drops.load(Ordering::Relaxed),
+            3,
+            "message sent to a dropped receiver leaked"
+        );
+    }
+
+    #[test]
+    fn racing_send_and_recv() {
+        for i in 0..1000 {
+            let (sender, receiver) = channel();
+            thread::scope(|s| {
+                s.spawn(move || sender.send(i));
+                assert_eq!(receiver.recv(), Ok(i));
+            });
+        }
+    }
+}
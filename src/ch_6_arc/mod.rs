@@ -0,0 +1 @@
+pub mod our_arc;
@@ -0,0 +1,396 @@
+use std::{hint, mem::ManuallyDrop, ops::Deref, process, ptr::NonNull, thread};
+
+use crate::sync::{
+    atomic::{fence, AtomicUsize, Ordering},
+    cell::UnsafeCell,
+};
+
+// https://marabos.nl/atomics/building-arc.html
+
+/*
+- The Arc from the send_and_sync notes: an Rc whose counters are atomics, so it can be shared across threads.
+- Two counters in one allocation:
+  - `data_ref_count`: the number of Arcs. When it hits zero the T is dropped.
+  - `alloc_ref_count`: the number of Weaks, plus one for all the Arcs together. When it hits zero the allocation is freed.
+  So cloning and dropping an Arc only touches the first counter, like it would without Weak support.
+- Dropping: the decrement is Release, and whoever brings it to zero does a fence(Acquire) before dropping.
+  That way every use of the T through any other Arc happens before the drop.
+- Cloning only needs Relaxed: we already have an Arc, so the count can't hit zero under us.
+  It aborts instead of overflowing, like std's does, since forgetting Arcs in a loop could otherwise wrap it to zero.
+- get_mut needs to know there are no other Arcs *and* no Weaks that could upgrade in the meantime.
+  It briefly "locks" alloc_ref_count by setting it to usize::MAX, which makes downgrade wait.
+ */
+
+struct ArcData<T> {
+    data_ref_count: AtomicUsize,
+    alloc_ref_count: AtomicUsize,
+    data: UnsafeCell<ManuallyDrop<T>>,
+}
+
+/// Sending one to another thread needs T: Sync, since every clone hands out &T:
+///
+/// ```compile_fail,E0277
+/// use atomics_and_locks::ch_6_arc::our_arc::Arc;
+/// let a = Arc::new(std::cell::Cell::new(1_i32));
+/// std::thread::spawn(move || a.set(2));
+/// ```
+pub struct Arc<T> {
+    ptr: NonNull<ArcData<T>>,
+}
+
+// An Arc<T> gives out &T on every thread that has a clone, and the last one drops the T, wherever that is.
+unsafe impl<T: Send + Sync> Send for Arc<T> {}
+unsafe impl<T: Send + Sync> Sync for Arc<T> {}
+
+pub struct Weak<T> {
+    ptr: NonNull<ArcData<T>>,
+}
+
+unsafe impl<T: Send + Sync> Send for Weak<T> {}
+unsafe impl<T: Send + Sync> Sync for Weak<T> {}
+
+const MAX_REFS: usize = usize::MAX / 2;
+
+impl<T> Arc<T> {
+    pub fn new(data: T) -> Arc<T> {
+        Arc {
+            ptr: NonNull::from(Box::leak(Box::new(ArcData {
+                data_ref_count: AtomicUsize::new(1),
+                alloc_ref_count: AtomicUsize::new(1),
+                data: UnsafeCell::new(ManuallyDrop::new(data)),
+            }))),
+        }
+    }
+
+    fn data(&self) -> &ArcData<T> {
+        // Safety: the allocation lives as long as there's an Arc (or Weak) pointing to it
+        unsafe { self.ptr.as_ref() }
+    }
+
+    // Like std's, these are associated functions instead of methods, so they can't be confused with T's methods.
+
+    pub fn get_mut(arc: &mut Self) -> Option<&mut T> {
+        if !Self::is_unique(arc) {
+            return None;
+        }
+        // Safety: nothing else can access the data, since there's only one Arc (ours), and no Weak
+        Some(arc.data().data.with_mut(|d| unsafe { &mut **d }))
+    }
+
+    // Clone-on-write: mutates in place if we're the only one, otherwise clones the T into a new Arc first.
+    pub fn make_mut(arc: &mut Self) -> &mut T
+    where
+        T: Clone,
+    {
+        if !Self::is_unique(arc) {
+            // the others keep the old one, Weaks included
+            *arc = Arc::new(T::clone(arc));
+        }
+        Self::get_mut(arc).unwrap()
+    }
+
+    fn is_unique(arc: &mut Self) -> bool {
+        // Acquire matches Weak::drop's Release decrement, to make sure any upgraded pointers are visible in the next data_ref_count.load
+        if arc
+            .data()
+            .alloc_ref_count
+            .compare_exchange(1, usize::MAX, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return false;
+        }
+        let is_unique = arc.data().data_ref_count.load(Ordering::Relaxed) == 1;
+        // Release matches Acquire increment in `downgrade`, to make sure any changes to data_ref_count that come after
+        // `downgrade` don't change the is_unique result above
+        arc.data().alloc_ref_count.store(1, Ordering::Release);
+        if is_unique {
+            // Acquire to match Arc::drop's Release decrement, to make sure nothing else is accessing the data
+            fence(Ordering::Acquire);
+        }
+        is_unique
+    }
+
+    pub fn downgrade(arc: &Self) -> Weak<T> {
+        let mut n = arc.data().alloc_ref_count.load(Ordering::Relaxed);
+        loop {
+            if n == usize::MAX {
+                // locked by get_mut
+                hint::spin_loop();
+                n = arc.data().alloc_ref_count.load(Ordering::Relaxed);
+                continue;
+            }
+            if n > MAX_REFS {
+                process::abort();
+            }
+            // Acquire synchronises with is_unique's Release store
+            match arc.data().alloc_ref_count.compare_exchange_weak(
+                n,
+                n + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Weak { ptr: arc.ptr },
+                Err(e) => n = e,
+            }
+        }
+    }
+
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        a.ptr == b.ptr
+    }
+
+    // Only a snapshot: other threads can change them right after.
+    pub fn strong_count(arc: &Self) -> usize {
+        arc.data().data_ref_count.load(Ordering::Relaxed)
+    }
+
+    pub fn weak_count(arc: &Self) -> usize {
+        match arc.data().alloc_ref_count.load(Ordering::Relaxed) {
+            // get_mut only locks it when it's 1, i.e. no Weaks
+            usize::MAX => 0,
+            n => n - 1,
+        }
+    }
+}
+
+impl<T> Deref for Arc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: since there's an Arc to the data, the data exists and may be shared
+        self.data().data.with(|d| unsafe { &**d })
+    }
+}
+
+impl<T> Clone for Arc<T> {
+    fn clone(&self) -> Self {
+        if self.data().data_ref_count.fetch_add(1, Ordering::Relaxed) > MAX_REFS {
+            process::abort();
+        }
+        Arc { ptr: self.ptr }
+    }
+}
+
+impl<T> Drop for Arc<T> {
+    fn drop(&mut self) {
+        if self.data().data_ref_count.fetch_sub(1, Ordering::Release) == 1 {
+            fence(Ordering::Acquire);
+            // Safety: the data reference counter is zero, so nothing will access the data anymore
+            self.data()
+                .data
+                .with_mut(|d| unsafe { ManuallyDrop::drop(&mut *d) });
+            // now that there's no Arc<T> left, drop the implicit weak pointer that represented all of them
+            drop(Weak { ptr: self.ptr });
+        }
+    }
+}
+
+impl<T> Weak<T> {
+    fn data(&self) -> &ArcData<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    // An Arc, if there's still any other Arc keeping the T alive.
+    pub fn upgrade(&self) -> Option<Arc<T>> {
+        let mut n = self.data().data_ref_count.load(Ordering::Relaxed);
+        loop {
+            if n == 0 {
+                return None;
+            }
+            if n > MAX_REFS {
+                process::abort();
+            }
+            match self.data().data_ref_count.compare_exchange_weak(
+                n,
+                n + 1,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(Arc { ptr: self.ptr }),
+                Err(e) => n = e,
+            }
+        }
+    }
+}
+
+impl<T> Clone for Weak<T> {
+    fn clone(&self) -> Self {
+        if self.data().alloc_ref_count.fetch_add(1, Ordering::Relaxed) > MAX_REFS {
+            process::abort();
+        }
+        Weak { ptr: self.ptr }
+    }
+}
+
+impl<T> Drop for Weak<T> {
+    fn drop(&mut self) {
+        if self.data().alloc_ref_count.fetch_sub(1, Ordering::Release) == 1 {
+            fence(Ordering::Acquire);
+            unsafe { drop(Box::from_raw(self.ptr.as_ptr())) };
+        }
+    }
+}
+
+/*
+  The leak and double free detector: every DetectDrop counts itself in `created` (also through Clone) and in `dropped`.
+  Once all the Arcs and Weaks are gone, the two have to be equal:
+  more drops than values means something was dropped twice, fewer means something leaked.
+*/
+#[derive(Default)]
+struct Counters {
+    created: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+impl Counters {
+    #[cfg(test)]
+    fn alive(&self) -> isize {
+        self.created.load(Ordering::Relaxed) as isize
+            - self.dropped.load(Ordering::Relaxed) as isize
+    }
+}
+
+struct DetectDrop<'a> {
+    value: usize,
+    counters: &'a Counters,
+}
+
+impl<'a> DetectDrop<'a> {
+    fn new(value: usize, counters: &'a Counters) -> Self {
+        counters.created.fetch_add(1, Ordering::Relaxed);
+        Self { value, counters }
+    }
+}
+
+impl Clone for DetectDrop<'_> {
+    fn clone(&self) -> Self {
+        Self::new(self.value, self.counters)
+    }
+}
+
+impl Drop for DetectDrop<'_> {
+    fn drop(&mut self) {
+        self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/*
+  Clones, drops, downgrades and upgrades one Arc from `threads` threads at once,
+  with a make_mut copy every now and then, then counts how many values were created and dropped.
+  The two only differ if something leaked or was dropped twice.
+*/
+pub fn our_arc_demo(threads: usize, iterations: usize) {
+    let counters = Counters::default();
+    let shared = Arc::new(DetectDrop::new(3, &counters));
+    thread::scope(|s| {
+        for t in 0..threads {
+            let shared = shared.clone();
+            s.spawn(move || {
+                let mut mine = shared.clone();
+                for i in 0..iterations {
+                    let weak = Arc::downgrade(&shared);
+                    let clone = weak.upgrade().expect("`shared` is still alive");
+                    drop((weak.clone(), clone));
+                    // a private copy every now and then, racing with the others' clones of `shared`
+                    if i % 16 == t % 16 {
+                        Arc::make_mut(&mut mine).value += 1;
+                        mine = shared.clone();
+                    }
+                }
+            });
+        }
+    });
+    drop(shared);
+    println!(
+        "{} values created and {} dropped over {threads} threads",
+        counters.created.into_inner(),
+        counters.dropped.into_inner()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_mut_only_without_other_arcs_or_weaks() {
+        let counters = Counters::default();
+        let mut a = Arc::new(DetectDrop::new(1, &counters));
+        assert!(Arc::get_mut(&mut a).is_some());
+        let b = a.clone();
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(Arc::get_mut(&mut a).is_none(), "get_mut with another Arc");
+        drop(b);
+        let weak = Arc::downgrade(&a);
+        assert!(Arc::get_mut(&mut a).is_none(), "get_mut with a Weak");
+        assert_eq!((Arc::strong_count(&a), Arc::weak_count(&a)), (1, 1));
+        drop(weak);
+        assert!(Arc::get_mut(&mut a).is_some());
+    }
+
+    #[test]
+    fn weak_does_not_keep_the_value_alive() {
+        let counters = Counters::default();
+        let a = Arc::new(DetectDrop::new(1, &counters));
+        let weak = Arc::downgrade(&a);
+        assert!(weak.upgrade().is_some());
+        drop(a);
+        assert_eq!(counters.alive(), 0);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn make_mut_copies_only_when_shared() {
+        let counters = Counters::default();
+        let mut a = Arc::new(DetectDrop::new(2, &counters));
+        Arc::make_mut(&mut a).value += 1;
+        assert_eq!(counters.alive(), 1);
+        let b = a.clone();
+        Arc::make_mut(&mut a).value += 1;
+        assert!(!Arc::ptr_eq(&a, &b));
+        assert_eq!((a.value, b.value), (4, 3));
+        assert_eq!(counters.alive(), 2);
+        drop((a, b));
+        assert_eq!(counters.alive(), 0);
+    }
+
+    // our_arc_demo, checking every value as it goes
+    fn hammer(threads: usize, iterations: usize) -> (usize, usize) {
+        let counters = Counters::default();
+        let shared = Arc::new(DetectDrop::new(3, &counters));
+        thread::scope(|s| {
+            for t in 0..threads {
+                let shared = shared.clone();
+                s.spawn(move || {
+                    let mut mine = shared.clone();
+                    for i in 0..iterations {
+                        let weak = Arc::downgrade(&shared);
+                        let clone = weak.upgrade().expect("`shared` is still alive");
+                        assert_eq!(clone.value, 3);
+                        drop((weak.clone(), clone));
+                        if i % 16 == t % 16 {
+                            Arc::make_mut(&mut mine).value += 1;
+                            assert_eq!(mine.value, 4);
+                            assert_eq!(shared.value, 3, "make_mut changed the shared value");
+                            mine = shared.clone();
+                        }
+                    }
+                });
+            }
+        });
+        drop(shared);
+        (counters.created.into_inner(), counters.dropped.into_inner())
+    }
+
+    #[test]
+    fn nothing_leaks_or_drops_twice_under_contention() {
+        let (created, dropped) = hammer(4, 10_000);
+        assert!(created > 1);
+        assert!(
+            dropped <= created,
+            "{} values dropped twice",
+            dropped - created
+        );
+        assert!(dropped >= created, "{} values leaked", created - dropped);
+    }
+}
@@ -0,0 +1,310 @@
+use std::{
+    collections::VecDeque,
+    sync::{LockResult, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{
+    futex::{wait_timeout, wake_all, wake_one},
+    mutex::{Mutex, MutexGuard},
+};
+use crate::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+// https://marabos.nl/atomics/building-locks.html#condition-variables
+
+/*
+- `counter` is bumped by every notify. A waiter loads it *before* unlocking the mutex,
+  then futex waits for it to change. A notify after the unlock changes the counter,
+  so either the futex wait sees the new value and doesn't sleep, or it's asleep and gets woken. Nothing gets lost.
+- `num_waiters` lets notify_one/notify_all skip the counter bump and the wake syscall when nobody is waiting,
+  which is the common case in something like a queue where consumers are usually busy.
+- Spurious wakeups: wait can return without a notify meant for us:
+  - a notify_all wakes every waiter, but maybe only one of them finds anything to do,
+  - the counter can change because of a notify that happened to come in between loading it and sleeping,
+  - and the futex syscall itself is allowed to return spuriously.
+  So the condition always has to be checked again after waking, in a loop. wait_while does exactly that.
+ */
+pub struct Condvar {
+    counter: AtomicU32,
+    num_waiters: AtomicUsize,
+    wake_syscalls: AtomicU64,
+}
+
+// std's WaitTimeoutResult has no public constructor, so here's our own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            counter: AtomicU32::new(0),
+            num_waiters: AtomicUsize::new(0),
+            wake_syscalls: AtomicU64::new(0),
+        }
+    }
+
+    pub fn notify_one(&self) {
+        if self.num_waiters.load(Ordering::Relaxed) > 0 {
+            self.counter.fetch_add(1, Ordering::Relaxed);
+            self.wake_syscalls.fetch_add(1, Ordering::Relaxed);
+            wake_one(&self.counter);
+        }
+    }
+
+    pub fn notify_all(&self) {
+        if self.num_waiters.load(Ordering::Relaxed) > 0 {
+            self.counter.fetch_add(1, Ordering::Relaxed);
+            self.wake_syscalls.fetch_add(1, Ordering::Relaxed);
+            wake_all(&self.counter);
+        }
+    }
+
+    // how many notifies actually had to make a syscall
+    pub fn wake_syscalls(&self) -> u64 {
+        self.wake_syscalls.load(Ordering::Relaxed)
+    }
+
+    // Unlocks the mutex, waits for a notify (or a spurious wakeup), and locks it again.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> LockResult<MutexGuard<'a, T>> {
+        self.wait_inner(guard, None)
+    }
+
+    // Like wait, but also returns after `timeout`. A spurious wakeup can still make it return early.
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> LockResult<(MutexGuard<'a, T>, WaitTimeoutResult)> {
+        let start = Instant::now();
+        let guard = self.wait_inner(guard, Some(timeout));
+        let result = WaitTimeoutResult(start.elapsed() >= timeout);
+        match guard {
+            Ok(guard) => Ok((guard, result)),
+            Err(poisoned) => Err(PoisonError::new((poisoned.into_inner(), result))),
+        }
+    }
+
+    // Waits for as long as `condition` returns true, taking care of spurious wakeups.
+    pub fn wait_while<'a, T>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> LockResult<MutexGuard<'a, T>> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard)?;
+        }
+        Ok(guard)
+    }
+
+    fn wait_inner<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Option<Duration>,
+    ) -> LockResult<MutexGuard<'a, T>> {
+        self.num_waiters.fetch_add(1, Ordering::Relaxed);
+
+        let counter_value = self.counter.load(Ordering::Relaxed);
+
+        // unlock the mutex by dropping the guard,
+        // but remember the mutex so we can lock it again
+        let mutex = guard.mutex;
+        drop(guard);
+
+        // whether this timed out or not, the caller has to look at the time (or its condition) anyway
+        wait_timeout(&self.counter, counter_value, timeout);
+
+        self.num_waiters.fetch_sub(1, Ordering::Relaxed);
+
+        mutex.lock()
+    }
+}
+
+// How the condvar_usage queue went in `consume`.
+#[derive(Debug, Clone, Copy)]
+struct Consumption {
+    consumed: usize,
+    // wakeups of a consumer that found the queue empty
+    empty_wakeups: usize,
+    wake_syscalls: u64,
+}
+
+// The condvar_usage queue, with several consumers that all get woken for every item.
+fn consume(threads: usize, items: usize) -> Consumption {
+    // `.1` is set once the producer is done
+    let queue = Mutex::new((VecDeque::new(), false));
+    let not_empty = Condvar::new();
+    let emptied = Condvar::new();
+    let empty_wakeups = AtomicUsize::new(0);
+    let consumed = AtomicUsize::new(0);
+
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| loop {
+                let mut q = queue.lock().unwrap();
+                let item = loop {
+                    if let Some(item) = q.0.pop_front() {
+                        if q.0.is_empty() {
+                            emptied.notify_one();
+                        }
+                        break Some(item);
+                    }
+                    if q.1 {
+                        break None;
+                    }
+                    q = not_empty.wait(q).unwrap();
+                    if q.0.is_empty() {
+                        empty_wakeups.fetch_add(1, Ordering::Relaxed);
+                    }
+                };
+                drop(q);
+                match item {
+                    Some(_) => consumed.fetch_add(1, Ordering::Relaxed),
+                    None => break,
+                };
+            });
+        }
+
+        for i in 0..items {
+            queue.lock().unwrap().0.push_back(i);
+            // wakes every consumer, but only one of them gets the item
+            not_empty.notify_all();
+            thread::yield_now();
+        }
+
+        // sleeps until the consumers have emptied the queue, however often it's woken
+        let mut q = emptied
+            .wait_while(queue.lock().unwrap(), |q| !q.0.is_empty())
+            .unwrap();
+        q.1 = true;
+        drop(q);
+        not_empty.notify_all();
+    });
+
+    Consumption {
+        consumed: consumed.into_inner(),
+        empty_wakeups: empty_wakeups.into_inner(),
+        wake_syscalls: not_empty.wake_syscalls(),
+    }
+}
+
+pub fn futex_condvar_demo(threads: usize, items: usize) {
+    let condvar = Condvar::new();
+    condvar.notify_one();
+    condvar.notify_all();
+    println!(
+        "notify with nobody waiting: {} syscalls",
+        condvar.wake_syscalls()
+    );
+
+    let mutex = Mutex::new(());
+    let (_guard, result) = condvar
+        .wait_timeout(mutex.lock().unwrap(), Duration::from_millis(10))
+        .unwrap();
+    println!(
+        "wait_timeout with nobody notifying: timed out: {}",
+        result.timed_out()
+    );
+
+    let Consumption {
+        consumed,
+        empty_wakeups,
+        wake_syscalls,
+    } = consume(threads, items);
+    println!(
+        "{consumed} of {items} items consumed by {threads} consumers, {empty_wakeups} wakeups found nothing to do, {wake_syscalls} wake syscalls"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+      Spins until a thread is inside wait, so a notify can't come too early.
+      num_waiters goes up before wait loads the counter, so that alone isn't enough:
+      a notify right in between would be missed. But wait only unlocks the mutex after loading the counter,
+      so once we get the lock, any notify is one it will see.
+    */
+    fn until_waiting(
+        condvar: &Condvar,
+        mutex: &Mutex<u32>,
+        waiter: &thread::ScopedJoinHandle<'_, u32>,
+    ) {
+        while condvar.num_waiters.load(Ordering::Relaxed) == 0 {
+            assert!(!waiter.is_finished(), "returned without being notified");
+            thread::yield_now();
+        }
+        drop(mutex.lock().unwrap());
+    }
+
+    #[test]
+    fn notify_without_waiters_makes_no_syscall() {
+        let condvar = Condvar::new();
+        condvar.notify_one();
+        condvar.notify_all();
+        assert_eq!(condvar.wake_syscalls(), 0);
+    }
+
+    #[test]
+    fn wait_timeout_times_out() {
+        let condvar = Condvar::new();
+        let mutex = Mutex::new(());
+        let (_guard, result) = condvar
+            .wait_timeout(mutex.lock().unwrap(), Duration::from_millis(10))
+            .unwrap();
+        assert!(result.timed_out());
+    }
+
+    #[test]
+    fn every_item_is_consumed() {
+        assert_eq!(consume(4, 1000).consumed, 1000);
+    }
+
+    #[test]
+    fn wait_returns_on_a_notify_that_changed_nothing() {
+        let condvar = Condvar::new();
+        let mutex = Mutex::new(0);
+        thread::scope(|s| {
+            let waiter = s.spawn(|| *condvar.wait(mutex.lock().unwrap()).unwrap());
+            until_waiting(&condvar, &mutex, &waiter);
+            // nothing the waiter cares about changed, but it's woken anyway
+            condvar.notify_all();
+            assert_eq!(waiter.join().unwrap(), 0);
+        });
+        assert_eq!(condvar.wake_syscalls(), 1);
+    }
+
+    #[test]
+    fn wait_while_only_returns_once_its_condition_holds() {
+        let condvar = Condvar::new();
+        let mutex = Mutex::new(0);
+        thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                *condvar
+                    .wait_while(mutex.lock().unwrap(), |n| *n < 3)
+                    .unwrap()
+            });
+            // the increments are made under the lock and notified, so it can't sleep through the last one,
+            // and until_waiting fails if it returns before that
+            for _ in 0..3 {
+                until_waiting(&condvar, &mutex, &waiter);
+                *mutex.lock().unwrap() += 1;
+                condvar.notify_all();
+            }
+            assert_eq!(waiter.join().unwrap(), 3);
+        });
+    }
+}
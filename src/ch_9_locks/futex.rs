@@ -0,0 +1,62 @@
+use std::{ptr, time::Duration};
+
+use crate::sync::atomic::AtomicU32;
+
+// https://marabos.nl/atomics/os-primitives.html#futex
+
+/*
+- A futex ("fast user-space mutex") is a syscall that lets a thread sleep on a plain AtomicU32.
+- wait(a, expected) only goes to sleep if `a` still holds `expected`, checked by the kernel atomically with going to sleep,
+  so a wake_* that happens right after we loaded the value can't get lost.
+- wake_one / wake_all wake threads sleeping in wait on the same atomic.
+- Both can return spuriously, so callers always check the value again in a loop.
+- Everything in ch_9_locks is built on these three functions.
+- They only need the atomic's address, so they work just the same on crate::sync's race detector atomics.
+ */
+
+// Sleeps while `a` holds `expected`.
+pub fn wait(a: &AtomicU32, expected: u32) {
+    wait_timeout(a, expected, None);
+}
+
+// Like wait, but gives up after `timeout`. Returns false if it timed out.
+pub fn wait_timeout(a: &AtomicU32, expected: u32, timeout: Option<Duration>) -> bool {
+    let timespec = timeout.map(|t| libc::timespec {
+        tv_sec: t.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+        tv_nsec: t.subsec_nanos() as libc::c_long,
+    });
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a.as_ptr(),
+            libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+            expected,
+            timespec
+                .as_ref()
+                .map_or(ptr::null(), |t| t as *const libc::timespec),
+        )
+    };
+    !(r == -1 && std::io::Error::last_os_error().raw_os_error() == Some(libc::ETIMEDOUT))
+}
+
+pub fn wake_one(a: &AtomicU32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a.as_ptr(),
+            libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
+            1,
+        );
+    }
+}
+
+pub fn wake_all(a: &AtomicU32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a.as_ptr(),
+            libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
+            i32::MAX,
+        );
+    }
+}
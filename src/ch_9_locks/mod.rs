@@ -0,0 +1,4 @@
+pub mod condvar;
+pub mod futex;
+pub mod mutex;
+pub mod rwlock;
@@ -0,0 +1,370 @@
+use std::{
+    fmt, hint,
+    ops::{Deref, DerefMut},
+    sync::{LockResult, PoisonError, TryLockError, TryLockResult},
+    thread,
+    time::Duration,
+};
+
+use super::futex::{wait, wake_one};
+use crate::{
+    ch_1_basics::Lock,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        cell::UnsafeCell,
+    },
+};
+
+// https://marabos.nl/atomics/building-locks.html#mutex
+
+/*
+- The whole lock is a single AtomicU32:
+  - 0: unlocked
+  - 1: locked, nobody waiting
+  - 2: locked, and other threads might be waiting
+- Locking is a compare_exchange(0, 1). Only if that fails do we (after spinning briefly) swap in a 2 and futex wait.
+- Unlocking is a swap(0). Only if the old value was 2 do we need a futex wake.
+- So as long as there's no contention, neither lock nor unlock ever makes a syscall.
+  The counters below keep track of how often that worked out.
+- A guard dropped while its thread is panicking poisons the mutex, like std's:
+  every later lock() returns Err(PoisonError), which still holds the guard.
+ */
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+const CONTENDED: u32 = 2;
+
+pub struct Mutex<T> {
+    state: AtomicU32,
+    poisoned: AtomicBool,
+    stats: Counters,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+struct Counters {
+    fast_locks: AtomicU64,
+    fast_unlocks: AtomicU64,
+    wait_syscalls: AtomicU64,
+    wake_syscalls: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MutexStats {
+    // locks and unlocks that never entered the kernel
+    pub fast_locks: u64,
+    pub fast_unlocks: u64,
+    // the ones that did
+    pub wait_syscalls: u64,
+    pub wake_syscalls: u64,
+}
+
+impl MutexStats {
+    // one avoided syscall for every lock and unlock that took the fast path
+    pub fn syscalls_avoided(&self) -> u64 {
+        self.fast_locks + self.fast_unlocks
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    pub(crate) mutex: &'a Mutex<T>,
+    // whether we were already panicking when we locked, so only a new panic poisons
+    panicking: bool,
+}
+
+unsafe impl<T: Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(UNLOCKED),
+            poisoned: AtomicBool::new(false),
+            stats: Counters {
+                fast_locks: AtomicU64::new(0),
+                fast_unlocks: AtomicU64::new(0),
+                wait_syscalls: AtomicU64::new(0),
+                wake_syscalls: AtomicU64::new(0),
+            },
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.stats.fast_locks.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.lock_contended();
+        }
+        self.guard()
+    }
+
+    #[cold]
+    fn lock_contended(&self) {
+        // spin for a little while first, in case the lock is only held very briefly.
+        // not while others are already waiting (2), they're first in line
+        let mut spins = 0;
+        while self.state.load(Ordering::Relaxed) == LOCKED && spins < 100 {
+            spins += 1;
+            hint::spin_loop();
+        }
+
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.stats.fast_locks.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        // we don't know if anyone else is waiting, so we have to assume they are and set 2
+        while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+            self.stats.wait_syscalls.fetch_add(1, Ordering::Relaxed);
+            wait(&self.state, CONTENDED);
+        }
+    }
+
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.stats.fast_locks.fetch_add(1, Ordering::Relaxed);
+            Ok(self.guard()?)
+        } else {
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    fn guard(&self) -> LockResult<MutexGuard<'_, T>> {
+        let guard = MutexGuard {
+            mutex: self,
+            panicking: thread::panicking(),
+        };
+        if self.is_poisoned() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    // Unlocks without a guard. Used by the guard's drop and by Condvar::wait.
+    pub(crate) fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            self.stats.wake_syscalls.fetch_add(1, Ordering::Relaxed);
+            wake_one(&self.state);
+        } else {
+            self.stats.fast_unlocks.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+
+    pub fn clear_poison(&self) {
+        self.poisoned.store(false, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> MutexStats {
+        MutexStats {
+            fast_locks: self.stats.fast_locks.load(Ordering::Relaxed),
+            fast_unlocks: self.stats.fast_unlocks.load(Ordering::Relaxed),
+            wait_syscalls: self.stats.wait_syscalls.load(Ordering::Relaxed),
+            wake_syscalls: self.stats.wake_syscalls.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let poisoned = self.is_poisoned();
+        let value = self.value.get_mut();
+        if poisoned {
+            Err(PoisonError::new(value))
+        } else {
+            Ok(value)
+        }
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.is_poisoned();
+        let value = self.value.into_inner();
+        if poisoned {
+            Err(PoisonError::new(value))
+        } else {
+            Ok(value)
+        }
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // Safety: the existence of this guard guarantees we've exclusively locked the mutex.
+        self.mutex.value.with(|v| unsafe { &*v })
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: the existence of this guard guarantees we've exclusively locked the mutex.
+        self.mutex.value.with_mut(|v| unsafe { &mut *v })
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        if !self.panicking && thread::panicking() {
+            self.mutex.poisoned.store(true, Ordering::Relaxed);
+        }
+        self.mutex.unlock();
+    }
+}
+
+impl<T: Send> Lock<T> for Mutex<T> {
+    type Guard<'a>
+        = MutexGuard<'a, T>
+    where
+        T: 'a;
+
+    fn lock(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap()
+    }
+
+    fn into_inner(self) -> T {
+        self.into_inner().unwrap()
+    }
+}
+
+/*
+  Runs the mutex_use workload on our Mutex, first with threads that hold the lock for a while (contended),
+  then with threads that each lock and unlock on their own turn (uncontended), and prints the counters.
+  Poisoning is checked in the tests, so this never has to panic.
+*/
+pub fn futex_mutex_demo(threads: usize, hold: Duration) {
+    let contended = Mutex::new(0);
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                let mut guard = contended.lock().unwrap();
+                thread::sleep(hold);
+                *guard += 1;
+            });
+        }
+    });
+    println!("contended:   {:?}", contended.stats());
+
+    let uncontended = Mutex::new(0);
+    for _ in 0..threads {
+        thread::scope(|s| {
+            s.spawn(|| *uncontended.lock().unwrap() += 1);
+        });
+    }
+    let stats = uncontended.stats();
+    println!(
+        "uncontended: {stats:?}, {} syscalls avoided",
+        stats.syscalls_avoided()
+    );
+
+    crate::ch_1_basics::mutex_use(Mutex::new(0), hold);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uncontended_locks_make_no_syscalls() {
+        let mutex = Mutex::new(0);
+        for _ in 0..4 {
+            thread::scope(|s| {
+                s.spawn(|| *mutex.lock().unwrap() += 1);
+            });
+        }
+        let stats = mutex.stats();
+        assert_eq!(stats.wait_syscalls + stats.wake_syscalls, 0);
+        assert_eq!(stats.syscalls_avoided(), 8);
+        assert_eq!(mutex.into_inner().unwrap(), 4);
+    }
+
+    #[test]
+    fn contended_counts_every_increment() {
+        let mutex = Mutex::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        *mutex.lock().unwrap() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*mutex.lock().unwrap(), 40_000);
+    }
+
+    #[test]
+    fn try_lock_while_locked() {
+        let mutex = Mutex::new(());
+        let guard = mutex.lock().unwrap();
+        assert!(matches!(mutex.try_lock(), Err(TryLockError::WouldBlock)));
+        drop(guard);
+        assert!(mutex.try_lock().is_ok());
+    }
+
+    #[test]
+    fn a_panic_while_locked_poisons_it() {
+        let mutex = Mutex::new(vec![1, 2, 3]);
+        let panicked = thread::scope(|s| {
+            s.spawn(|| {
+                let mut v = mutex.lock().unwrap();
+                v.push(4);
+                panic!("oops");
+            })
+            .join()
+        });
+        assert!(panicked.is_err());
+        assert!(mutex.is_poisoned());
+
+        // the data is still there, behind the PoisonError
+        let v = mutex.lock().unwrap_err().into_inner();
+        assert_eq!(*v, [1, 2, 3, 4]);
+        drop(v);
+        assert!(matches!(mutex.try_lock(), Err(TryLockError::Poisoned(_))));
+
+        mutex.clear_poison();
+        assert!(mutex.lock().is_ok());
+        assert_eq!(mutex.into_inner().unwrap(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn locking_while_already_panicking_does_not_poison() {
+        struct LockOnDrop<'a>(&'a Mutex<i32>);
+        impl Drop for LockOnDrop<'_> {
+            fn drop(&mut self) {
+                *self.0.lock().unwrap() += 1;
+            }
+        }
+        let mutex = Mutex::new(0);
+        let panicked = thread::scope(|s| {
+            s.spawn(|| {
+                let _lock_on_drop = LockOnDrop(&mutex);
+                panic!("oops");
+            })
+            .join()
+        });
+        assert!(panicked.is_err());
+        assert!(!mutex.is_poisoned());
+        assert_eq!(*mutex.lock().unwrap(), 1);
+    }
+}
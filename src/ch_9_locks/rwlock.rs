@@ -0,0 +1,646 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::futex::{wait, wake_all, wake_one};
+use crate::sync::{
+    atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    cell::UnsafeCell,
+};
+
+// https://marabos.nl/atomics/building-locks.html#reader-writer-lock
+
+/*
+- The multi-threaded RefCell from ch_1_basics: any number of readers (&T), or exactly one writer (&mut T).
+- `state` packs everything into one AtomicU32:
+  - bit 0: a writer is waiting. New readers wait while it's set, so a steady stream of readers can't starve writers.
+  - bit 1: an upgradable reader holds the lock.
+  - bits 2..: the number of plain readers (so every reader adds 4).
+  - u32::MAX: write locked.
+- Writers don't futex wait on `state` (it changes with every read lock and unlock, which would wake them for nothing),
+  but on `writer_wake_counter`, which is only bumped when a writer might be able to go ahead.
+- An upgradable read is a read lock that can later be turned into a write lock without letting anyone else write in between.
+  Plain readers can share the lock with it, but other upgradable readers and writers can't.
+  Upgrading sets the writer waiting bit to keep new readers out, and waits for the current ones to leave.
+- `reader_waits` and `writer_waits` count how often a reader or writer found it had to futex wait,
+  like the Mutex's syscall counters. Only the slow path touches them.
+ */
+
+const WRITER_WAITING: u32 = 1;
+const UPGRADABLE: u32 = 2;
+const READER: u32 = 4;
+const WRITE_LOCKED: u32 = u32::MAX;
+
+fn readers(state: u32) -> u32 {
+    state / READER
+}
+
+pub struct RwLock<T> {
+    state: AtomicU32,
+    writer_wake_counter: AtomicU32,
+    reader_waits: AtomicU64,
+    writer_waits: AtomicU64,
+    value: UnsafeCell<T>,
+}
+
+// Readers on several threads get a &T at the same time, so T needs to be Sync as well as Send.
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+pub struct ReadGuard<'a, T> {
+    rwlock: &'a RwLock<T>,
+}
+
+pub struct UpgradableReadGuard<'a, T> {
+    rwlock: &'a RwLock<T>,
+}
+
+pub struct WriteGuard<'a, T> {
+    rwlock: &'a RwLock<T>,
+}
+
+unsafe impl<T: Sync> Sync for WriteGuard<'_, T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            writer_wake_counter: AtomicU32::new(0),
+            reader_waits: AtomicU64::new(0),
+            writer_waits: AtomicU64::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        let mut s = self.state.load(Ordering::Relaxed);
+        loop {
+            if s & WRITER_WAITING == 0 {
+                assert!(s < WRITE_LOCKED - 2 * READER, "too many readers");
+                match self.state.compare_exchange_weak(
+                    s,
+                    s + READER,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return ReadGuard { rwlock: self },
+                    Err(e) => s = e,
+                }
+            }
+            // write locked (all bits set) or a writer is waiting: let it go first
+            if s & WRITER_WAITING != 0 {
+                self.reader_waits.fetch_add(1, Ordering::Relaxed);
+                wait(&self.state, s);
+                s = self.state.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn try_read(&self) -> Option<ReadGuard<'_, T>> {
+        let mut s = self.state.load(Ordering::Relaxed);
+        while s & WRITER_WAITING == 0 && s < WRITE_LOCKED - 2 * READER {
+            match self.state.compare_exchange_weak(
+                s,
+                s + READER,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(ReadGuard { rwlock: self }),
+                Err(e) => s = e,
+            }
+        }
+        None
+    }
+
+    pub fn upgradable_read(&self) -> UpgradableReadGuard<'_, T> {
+        let mut s = self.state.load(Ordering::Relaxed);
+        loop {
+            if s & (WRITER_WAITING | UPGRADABLE) == 0 {
+                match self.state.compare_exchange_weak(
+                    s,
+                    s | UPGRADABLE,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return UpgradableReadGuard { rwlock: self },
+                    Err(e) => s = e,
+                }
+            } else {
+                self.reader_waits.fetch_add(1, Ordering::Relaxed);
+                wait(&self.state, s);
+                s = self.state.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn try_upgradable_read(&self) -> Option<UpgradableReadGuard<'_, T>> {
+        let mut s = self.state.load(Ordering::Relaxed);
+        while s & (WRITER_WAITING | UPGRADABLE) == 0 {
+            match self.state.compare_exchange_weak(
+                s,
+                s | UPGRADABLE,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(UpgradableReadGuard { rwlock: self }),
+                Err(e) => s = e,
+            }
+        }
+        None
+    }
+
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        let mut s = self.state.load(Ordering::Relaxed);
+        loop {
+            // nobody holds it (maybe with the waiting bit set, possibly by us): try to lock
+            if s <= WRITER_WAITING {
+                match self.state.compare_exchange(
+                    s,
+                    WRITE_LOCKED,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return WriteGuard { rwlock: self },
+                    Err(e) => {
+                        s = e;
+                        continue;
+                    }
+                }
+            }
+            // block new readers by making sure the waiting bit is set
+            if s & WRITER_WAITING == 0 {
+                match self.state.compare_exchange(
+                    s,
+                    s | WRITER_WAITING,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        s = e;
+                        continue;
+                    }
+                }
+            }
+            // wait, if it's still locked
+            let w = self.writer_wake_counter.load(Ordering::Acquire);
+            s = self.state.load(Ordering::Relaxed);
+            if s > WRITER_WAITING {
+                self.writer_waits.fetch_add(1, Ordering::Relaxed);
+                wait(&self.writer_wake_counter, w);
+                s = self.state.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn try_write(&self) -> Option<WriteGuard<'_, T>> {
+        let mut s = self.state.load(Ordering::Relaxed);
+        while s <= WRITER_WAITING {
+            match self.state.compare_exchange_weak(
+                s,
+                WRITE_LOCKED,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(WriteGuard { rwlock: self }),
+                Err(e) => s = e,
+            }
+        }
+        None
+    }
+
+    fn wake_writers(&self) {
+        self.writer_wake_counter.fetch_add(1, Ordering::Release);
+        // all of them: a plain writer can't do anything while an upgradable reader is waiting to upgrade,
+        // so waking just one might pick the wrong one
+        wake_all(&self.writer_wake_counter);
+    }
+
+    // how often read or upgradable_read had to wait
+    pub fn reader_waits(&self) -> u64 {
+        self.reader_waits.load(Ordering::Relaxed)
+    }
+
+    // how often write or upgrade had to wait
+    pub fn writer_waits(&self) -> u64 {
+        self.writer_waits.load(Ordering::Relaxed)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<'a, T> UpgradableReadGuard<'a, T> {
+    // Turns this into a write lock once the plain readers have left. No other writer can get in before us.
+    pub fn upgrade(self) -> WriteGuard<'a, T> {
+        let rwlock = self.rwlock;
+        // we hand our part of the state over to the write guard, so don't unlock
+        std::mem::forget(self);
+
+        let mut s = rwlock.state.load(Ordering::Relaxed);
+        loop {
+            if readers(s) == 0 {
+                // only our UPGRADABLE bit and maybe WRITER_WAITING left
+                match rwlock.state.compare_exchange(
+                    s,
+                    WRITE_LOCKED,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return WriteGuard { rwlock },
+                    Err(e) => {
+                        s = e;
+                        continue;
+                    }
+                }
+            }
+            if s & WRITER_WAITING == 0 {
+                if let Err(e) = rwlock.state.compare_exchange(
+                    s,
+                    s | WRITER_WAITING,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    s = e;
+                    continue;
+                }
+            }
+            let w = rwlock.writer_wake_counter.load(Ordering::Acquire);
+            s = rwlock.state.load(Ordering::Relaxed);
+            if readers(s) > 0 {
+                rwlock.writer_waits.fetch_add(1, Ordering::Relaxed);
+                wait(&rwlock.writer_wake_counter, w);
+                s = rwlock.state.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl<T> Deref for ReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.rwlock.value.with(|v| unsafe { &*v })
+    }
+}
+
+impl<T> Deref for UpgradableReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.rwlock.value.with(|v| unsafe { &*v })
+    }
+}
+
+impl<T> Deref for WriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.rwlock.value.with(|v| unsafe { &*v })
+    }
+}
+
+impl<T> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.rwlock.value.with_mut(|v| unsafe { &mut *v })
+    }
+}
+
+impl<T> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let s = self.rwlock.state.fetch_sub(READER, Ordering::Release);
+        // we were the last plain reader, and someone wants to write (or upgrade)
+        if readers(s) == 1 && s & WRITER_WAITING != 0 {
+            self.rwlock.wake_writers();
+        }
+    }
+}
+
+impl<T> Drop for UpgradableReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let s = self.rwlock.state.fetch_and(!UPGRADABLE, Ordering::Release);
+        if s & WRITER_WAITING != 0 {
+            self.rwlock.wake_writers();
+        }
+        // other upgradable readers might be waiting for the bit
+        wake_all(&self.rwlock.state);
+    }
+}
+
+impl<T> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.rwlock.state.store(0, Ordering::Release);
+        self.rwlock
+            .writer_wake_counter
+            .fetch_add(1, Ordering::Release);
+        wake_one(&self.rwlock.writer_wake_counter);
+        wake_all(&self.rwlock.state);
+    }
+}
+
+/*
+  A reader-preferring version, like the first one in the book: state is the number of readers, or u32::MAX when write locked.
+  New readers get in as long as there is no writer *holding* the lock, so a waiting writer has to hope
+  all readers happen to be gone at the same moment. With overlapping readers that never happens.
+*/
+pub struct NaiveRwLock<T> {
+    state: AtomicU32,
+    writer_waits: AtomicU64,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for NaiveRwLock<T> {}
+
+impl<T> NaiveRwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            writer_waits: AtomicU64::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn writer_waits(&self) -> u64 {
+        self.writer_waits.load(Ordering::Relaxed)
+    }
+
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let mut s = self.state.load(Ordering::Relaxed);
+        loop {
+            if s < WRITE_LOCKED {
+                match self.state.compare_exchange_weak(
+                    s,
+                    s + 1,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(e) => s = e,
+                }
+            }
+            if s == WRITE_LOCKED {
+                wait(&self.state, WRITE_LOCKED);
+                s = self.state.load(Ordering::Relaxed);
+            }
+        }
+        let r = self.value.with(|v| f(unsafe { &*v }));
+        if self.state.fetch_sub(1, Ordering::Release) == 1 {
+            wake_one(&self.state);
+        }
+        r
+    }
+
+    pub fn write<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        while let Err(s) =
+            self.state
+                .compare_exchange(0, WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+        {
+            self.writer_waits.fetch_add(1, Ordering::Relaxed);
+            wait(&self.state, s);
+        }
+        let r = self.value.with_mut(|v| f(unsafe { &mut *v }));
+        self.state.store(0, Ordering::Release);
+        wake_all(&self.state);
+        r
+    }
+}
+
+/*
+  A reader holds the naive lock and a writer goes to sleep waiting for it.
+  Then `readers` new readers hand the lock over to each other, every one getting in before the last one leaves.
+  Returns whether the writer was still waiting after all of them.
+*/
+fn naive_hand_over(readers: usize) -> bool {
+    let naive = &NaiveRwLock::new(0);
+    thread::scope(|s| {
+        // a reader that holds the lock until the returned sender is dropped
+        let hold = || {
+            let (entered_tx, entered) = mpsc::channel();
+            let (release, released) = mpsc::channel::<()>();
+            s.spawn(move || {
+                naive.read(|_| {
+                    entered_tx.send(()).unwrap();
+                    released.recv().unwrap_err();
+                })
+            });
+            entered.recv().unwrap();
+            release
+        };
+
+        let mut held = hold();
+        let writer = s.spawn(|| naive.write(|v| *v += 1));
+        while naive.writer_waits() == 0 {
+            thread::yield_now();
+        }
+        for _ in 0..readers {
+            // gets in while the previous reader is still there, so the lock is never free for the writer
+            let next = hold();
+            drop(held);
+            held = next;
+        }
+        let starved = !writer.is_finished();
+        drop(held);
+        writer.join().unwrap();
+        starved
+    })
+}
+
+/*
+  A reader holds the writer-preferring lock, a writer goes to sleep waiting for it, and then so does a new reader.
+  Returns the order the writer and the new reader got in, starting at 0.
+*/
+fn fair_hand_over() -> (u32, u32) {
+    let fair = RwLock::new(0);
+    let order = AtomicU32::new(0);
+    thread::scope(|s| {
+        let held = fair.read();
+        // both take their turn while still holding the lock
+        let writer = s.spawn(|| {
+            let mut guard = fair.write();
+            *guard += 1;
+            order.fetch_add(1, Ordering::Relaxed)
+        });
+        // only counted once its waiting bit is set
+        while fair.writer_waits() == 0 {
+            thread::yield_now();
+        }
+        let reader = s.spawn(|| {
+            let _guard = fair.read();
+            order.fetch_add(1, Ordering::Relaxed)
+        });
+        while fair.reader_waits() == 0 {
+            thread::yield_now();
+        }
+        drop(held);
+        (writer.join().unwrap(), reader.join().unwrap())
+    })
+}
+
+/*
+  First deterministically: a reader holds the lock, a writer is waiting, and then new readers arrive.
+  The naive lock lets them in, so readers handing over to each other keep the writer out for as long as they like.
+  The writer-preferring one makes the new reader wait until the writer is done.
+
+  Then with timings: readers keep the lock busy with overlapping read locks for `duration`, while one writer tries to get in.
+  With the naive lock the writer only gets in once the readers stop. With the writer-preferring one it's in almost right away.
+  Those depend on the machine: on a busy or single CPU one the readers don't always overlap and the naive writer gets lucky.
+*/
+pub fn writer_starvation(threads: usize, duration: Duration) {
+    let readers = threads.max(2);
+
+    let starved = naive_hand_over(readers);
+    println!("naive, reader-preferring: writer still waiting after {readers} new readers got in: {starved}");
+    let (writer, reader) = fair_hand_over();
+    println!(
+        "writer-preferring:        writer got in before the new reader: {}",
+        writer < reader
+    );
+
+    let naive = NaiveRwLock::new(0);
+    let hold = duration / 40;
+    let naive_wait = starve(
+        readers,
+        duration,
+        hold,
+        |hold| naive.read(|_| thread::sleep(hold)),
+        || naive.write(|v| *v += 1),
+    );
+    println!("naive, reader-preferring: writer waited {naive_wait:?}");
+    let fair = RwLock::new(0);
+    let fair_wait = starve(
+        readers,
+        duration,
+        hold,
+        |hold| {
+            let _guard = fair.read();
+            thread::sleep(hold);
+        },
+        || *fair.write() += 1,
+    );
+    println!("writer-preferring:        writer waited {fair_wait:?}");
+}
+
+fn starve(
+    readers: usize,
+    duration: Duration,
+    hold: Duration,
+    read: impl Fn(Duration) + Sync,
+    write: impl FnOnce(),
+) -> Duration {
+    let stop = AtomicBool::new(false);
+    thread::scope(|s| {
+        for _ in 0..readers {
+            s.spawn(|| {
+                while !stop.load(Ordering::Relaxed) {
+                    read(hold);
+                }
+            });
+        }
+        let stopper = s.spawn(|| {
+            thread::sleep(duration);
+            stop.store(true, Ordering::Relaxed);
+        });
+
+        // let the readers get going first
+        thread::sleep(hold * 2);
+        let start = Instant::now();
+        write();
+        let waited = start.elapsed();
+        stop.store(true, Ordering::Relaxed);
+        stopper.join().unwrap();
+        waited
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn naive_readers_keep_a_waiting_writer_out() {
+        assert!(naive_hand_over(8), "the naive writer got in");
+    }
+
+    #[test]
+    fn a_waiting_writer_goes_before_new_readers() {
+        assert_eq!(
+            fair_hand_over(),
+            (0, 1),
+            "the new reader got in before the writer"
+        );
+    }
+
+    #[test]
+    fn readers_share_and_writers_exclude() {
+        let lock = RwLock::new(1);
+        let a = lock.read();
+        let b = lock.try_read().expect("readers share");
+        assert!(lock.try_write().is_none());
+        assert_eq!(*a + *b, 2);
+        drop((a, b));
+        let mut w = lock.try_write().expect("free again");
+        *w += 1;
+        assert!(lock.try_read().is_none());
+        assert!(lock.try_upgradable_read().is_none());
+        drop(w);
+        assert_eq!(lock.into_inner(), 2);
+    }
+
+    #[test]
+    fn upgradable_read() {
+        let lock = RwLock::new(vec![1]);
+        let upgradable = lock.upgradable_read();
+        let reader = lock
+            .try_read()
+            .expect("plain readers can share with an upgradable reader");
+        assert!(lock.try_upgradable_read().is_none());
+        assert!(lock.try_write().is_none());
+        assert_eq!(*reader, *upgradable);
+        drop(reader);
+        let mut writer = upgradable.upgrade();
+        writer.push(2);
+        assert!(lock.try_read().is_none());
+        drop(writer);
+        assert_eq!(*lock.try_read().unwrap(), [1, 2]);
+        assert!(lock.try_upgradable_read().is_some());
+    }
+
+    #[test]
+    fn upgrade_waits_for_the_readers() {
+        let lock = RwLock::new(0);
+        thread::scope(|s| {
+            let reader = lock.read();
+            let upgrader = s.spawn(|| {
+                let mut writer = lock.upgradable_read().upgrade();
+                *writer += 1;
+            });
+            while lock.writer_waits() == 0 {
+                thread::yield_now();
+            }
+            // the upgrade has set the waiting bit, so no new reader gets in either
+            assert!(lock.try_read().is_none());
+            assert_eq!(*reader, 0);
+            drop(reader);
+            upgrader.join().unwrap();
+        });
+        assert_eq!(*lock.read(), 1);
+    }
+
+    #[test]
+    fn every_write_counted_with_readers_around() {
+        let lock = RwLock::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..2_000 {
+                        let before = *lock.read();
+                        let mut n = lock.write();
+                        assert!(*n >= before);
+                        *n += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(lock.into_inner(), 8_000);
+    }
+}
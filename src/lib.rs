@@ -0,0 +1,13 @@
+pub mod ch_1_basics;
+pub mod ch_2_atomics;
+pub mod ch_3_memory_ordering;
+pub mod ch_4_spin_lock;
+pub mod ch_5_channels;
+pub mod ch_6_arc;
+// built directly on the Linux futex syscalls
+#[cfg(target_os = "linux")]
+pub mod ch_9_locks;
+// std's UnsafeCell and atomics, or the race detector's with `--features race_detector`
+pub mod sync;
+// model checking and simulation for the code in the chapters
+pub mod verification;
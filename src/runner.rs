@@ -0,0 +1,632 @@
+use std::{
+    cell::Cell,
+    cell::RefCell,
+    fmt,
+    fs::File,
+    io::{self, BufReader},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[cfg(target_os = "linux")]
+use atomics_and_locks::ch_9_locks;
+use atomics_and_locks::{
+    ch_1_basics,
+    ch_2_atomics::{
+        cancellation,
+        clock::{self, Clock, RealClock, VirtualClock},
+        compare_exchange, console, fetch_add_example, fetch_modify, id_allocator, id_recycler,
+        lazy_cell, lazy_init, load_and_store, race_once_box, statistics,
+    },
+    ch_3_memory_ordering::litmus,
+    ch_4_spin_lock::{Backoff, SpinLock},
+    ch_5_channels::{bounded, oneshot},
+    ch_6_arc::our_arc,
+    verification::{explorer, race_detector, weak_memory},
+};
+
+/*
+  A registry of every demo in the crate, so any of them can be run from the command line
+  instead of uncommenting lines in main.rs.
+
+    atomics_and_locks list
+    atomics_and_locks run ch2::stop_flag
+    atomics_and_locks run ch2::stats --threads 8 --items 200 --sleep-scale 0.1
+    atomics_and_locks run-all --sleep-scale 0.01
+    atomics_and_locks run-all --clock virtual
+*/
+
+const USAGE: &str = "usage:
+  atomics_and_locks list
+  atomics_and_locks run <demo> [options]
+  atomics_and_locks run-all [options]
+
+options:
+  --items <n>          number of items a demo processes (default 100)
+  --threads <n>        number of worker threads a demo spawns (default 4)
+  --sleep-scale <f>    multiplier for every simulated sleep (default 1.0)
+  --clock <c>          real, or virtual to skip the waiting in the time based demos (default real)
+  --script <file>      read an interactive demo's commands from a file instead of stdin";
+
+// Per-demo arguments. Each demo only uses the ones that make sense for it.
+#[derive(Debug, Clone)]
+pub struct DemoArgs {
+    pub items: usize,
+    pub threads: usize,
+    pub sleep_scale: f64,
+    // where interactive demos read their commands from, instead of stdin
+    pub script: Option<PathBuf>,
+    // run the demos that take a Clock on an automatic VirtualClock
+    pub virtual_clock: bool,
+}
+
+impl Default for DemoArgs {
+    fn default() -> Self {
+        Self {
+            items: 100,
+            threads: 4,
+            sleep_scale: 1.0,
+            script: None,
+            virtual_clock: false,
+        }
+    }
+}
+
+impl DemoArgs {
+    // scales one of the demo's simulated work durations
+    pub fn scaled(&self, d: Duration) -> Duration {
+        d.mul_f64(self.sleep_scale)
+    }
+
+    // A fresh clock for one demo, created on the thread that runs it.
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        if self.virtual_clock {
+            Arc::new(VirtualClock::automatic())
+        } else {
+            Arc::new(RealClock)
+        }
+    }
+}
+
+pub struct Demo {
+    pub name: &'static str,
+    pub about: &'static str,
+    // Some(reason) for demos that can't run unattended: they wait on stdin, never terminate, or are UB.
+    pub skip_in_run_all: Option<&'static str>,
+    pub run: fn(&DemoArgs),
+}
+
+pub fn demos() -> Vec<Demo> {
+    vec![
+        // ch_1_basics
+        Demo {
+            name: "ch1::basics",
+            about: "spawning, moving into and joining threads, and scoped threads",
+            skip_in_run_all: None,
+            run: |_| ch_1_basics::basics(),
+        },
+        Demo {
+            name: "ch1::undefined_behavior",
+            about: "reads out of bounds with get_unchecked",
+            skip_in_run_all: Some("undefined behavior"),
+            run: |_| ch_1_basics::undefined_behavior(),
+        },
+        Demo {
+            name: "ch1::cell_usage",
+            about: "Cell with distinct and aliased references",
+            skip_in_run_all: None,
+            run: |_| {
+                let a = Cell::new(8);
+                let b = Cell::new(20);
+                ch_1_basics::cell_usage(&a, &b);
+                ch_1_basics::cell_usage(&a, &a);
+            },
+        },
+        Demo {
+            name: "ch1::ref_cell_usage",
+            about: "borrowing the contents of a RefCell mutably",
+            skip_in_run_all: None,
+            run: |_| {
+                let v = RefCell::new(vec![0]);
+                ch_1_basics::ref_cell_usage(&v);
+                println!("v: {:?}", v.borrow());
+            },
+        },
+        Demo {
+            name: "ch1::send_and_sync",
+            about: "PhantomData<Cell<()>> and why Rc can't be sent",
+            skip_in_run_all: None,
+            run: |_| ch_1_basics::send_and_sync(),
+        },
+        Demo {
+            name: "ch1::mutex_use",
+            about: "threads incrementing a shared Mutex<i32>",
+            skip_in_run_all: None,
+            run: |args| ch_1_basics::mutex_use(Mutex::new(0), args.scaled(Duration::from_secs(1))),
+        },
+        Demo {
+            name: "ch1::mutex_guard_lifetime",
+            about: "when a MutexGuard temporary is dropped",
+            skip_in_run_all: None,
+            run: |_| ch_1_basics::mutex_guard_lifetime(),
+        },
+        Demo {
+            name: "ch1::thread_parking_queue",
+            about: "single consumer woken by park/unpark",
+            skip_in_run_all: None,
+            run: |args| {
+                ch_1_basics::thread_parking_queue(
+                    args.scaled(Duration::from_millis(10)),
+                    args.items as isize,
+                )
+            },
+        },
+        Demo {
+            name: "ch1::work_queue",
+            about: "several consumers, each parked one woken by exactly one push",
+            skip_in_run_all: None,
+            run: |args| {
+                ch_1_basics::work_queue::work_queue(
+                    args.threads,
+                    args.items,
+                    args.scaled(Duration::from_millis(10)),
+                )
+            },
+        },
+        Demo {
+            name: "ch1::condvar_usage",
+            about: "single consumer woken by a Condvar",
+            skip_in_run_all: None,
+            run: |args| {
+                ch_1_basics::condvar_usage(
+                    args.scaled(Duration::from_millis(10)),
+                    args.items as isize,
+                )
+            },
+        },
+        Demo {
+            name: "ch1::another_condvar_usage",
+            about: "apples and oranges, each consumer woken only for its own fruit",
+            skip_in_run_all: None,
+            run: |args| {
+                ch_1_basics::another_condvar_usage(
+                    args.scaled(Duration::from_millis(10)),
+                    args.items,
+                )
+            },
+        },
+        // ch_2_atomics
+        Demo {
+            name: "ch2::stop_flag",
+            about: "console controlling background workers, from stdin or --script",
+            skip_in_run_all: Some("reads commands from stdin"),
+            run: |args| {
+                let interval = args.scaled(Duration::from_secs(1));
+                match &args.script {
+                    Some(path) => {
+                        let file = File::open(path)
+                            .unwrap_or_else(|e| panic!("can't open {}: {e}", path.display()));
+                        load_and_store::stop_flag(BufReader::new(file), interval, args.clock())
+                    }
+                    None => load_and_store::stop_flag(io::stdin().lock(), interval, args.clock()),
+                }
+            },
+        },
+        Demo {
+            name: "ch2::stop_flag_scripted",
+            about: "the stop_flag console driven by a built-in script",
+            skip_in_run_all: None,
+            run: |args| {
+                console::scripted_console(args.scaled(Duration::from_millis(10)), args.clock())
+            },
+        },
+        Demo {
+            name: "ch2::cancellation",
+            about: "a CancellationToken with child tokens, parked waiters and callbacks",
+            skip_in_run_all: None,
+            run: |args| {
+                cancellation::cancellation_demo(
+                    args.threads,
+                    args.scaled(Duration::from_millis(100)),
+                )
+            },
+        },
+        Demo {
+            name: "ch2::progress_reporting",
+            about: "one worker reporting progress through an AtomicUsize",
+            skip_in_run_all: None,
+            run: |args| {
+                load_and_store::progress_reporting(
+                    args.items,
+                    args.scaled(Duration::from_millis(75)),
+                    args.clock(),
+                )
+            },
+        },
+        Demo {
+            name: "ch2::progress_reporting_multiple_threads",
+            about: "several workers reporting progress with fetch_add",
+            skip_in_run_all: None,
+            run: |args| {
+                load_and_store::progress_reporting_multiple_threads(
+                    args.threads,
+                    args.items,
+                    args.scaled(Duration::from_millis(75)),
+                    args.clock(),
+                )
+            },
+        },
+        Demo {
+            name: "ch2::stats",
+            about: "timing stats from a seqlock snapshot, percentiles from histogram shards",
+            skip_in_run_all: None,
+            run: |args| statistics::stats(args.threads, args.items, args.sleep_scale, args.clock()),
+        },
+        Demo {
+            name: "ch2::virtual_clock",
+            about: "every time based demo on a virtual clock, checking their exact timings",
+            skip_in_run_all: None,
+            run: |args| clock::virtual_clock_demo(args.threads, args.items),
+        },
+        Demo {
+            name: "ch2::fetch_add_example",
+            about: "fetch_add returns the previous value",
+            skip_in_run_all: None,
+            run: |_| fetch_modify::fetch_add_example(),
+        },
+        Demo {
+            name: "ch2::allocate_new_id",
+            about: "fetch_add id allocation that can overflow",
+            skip_in_run_all: None,
+            run: |args| {
+                for _ in 0..args.items {
+                    fetch_add_example::allocate_new_id();
+                }
+                println!("allocated {} ids", args.items);
+            },
+        },
+        Demo {
+            name: "ch2::allocate_new_id_panic",
+            about: "fetch_add id allocation that panics past 1000, after incrementing",
+            skip_in_run_all: None,
+            run: |args| print_ids(args, fetch_add_example::allocate_new_id_panic),
+        },
+        Demo {
+            name: "ch2::allocate_new_id_subtract",
+            about: "fetch_add id allocation that subtracts again before panicking",
+            skip_in_run_all: None,
+            run: |args| print_ids(args, fetch_add_example::allocate_new_id_subtract),
+        },
+        Demo {
+            name: "ch2::increment_compare_exchange",
+            about: "fetch_add written as a compare_exchange loop",
+            skip_in_run_all: None,
+            run: |args| {
+                let a = std::sync::atomic::AtomicU32::new(0);
+                std::thread::scope(|s| {
+                    for _ in 0..args.threads {
+                        s.spawn(|| {
+                            for _ in 0..args.items {
+                                compare_exchange::increment_compare_exchange(&a);
+                            }
+                        });
+                    }
+                });
+                let a = a.into_inner();
+                println!("a: {a}");
+                assert_eq!(a as usize, args.threads * args.items);
+            },
+        },
+        Demo {
+            name: "ch2::allocate_new_id_upper_bound",
+            about: "compare_exchange id allocation that checks before incrementing",
+            skip_in_run_all: None,
+            run: |args| print_ids(args, compare_exchange::allocate_new_id_upper_bound),
+        },
+        Demo {
+            name: "ch2::allocate_new_id_fetch_update",
+            about: "id allocation with fetch_update and checked_add",
+            skip_in_run_all: None,
+            run: |args| print_ids(args, compare_exchange::allocate_new_id_fetch_update),
+        },
+        Demo {
+            name: "ch2::id_allocator",
+            about: "the same IdAllocator workload under each allocation strategy",
+            skip_in_run_all: None,
+            run: |args| id_allocator::compare_strategies(args.threads, args.items),
+        },
+        Demo {
+            name: "ch2::id_recycler",
+            about: "stress test of a recycling id free list, with and without an ABA tag",
+            skip_in_run_all: None,
+            run: |args| id_recycler::recycling_stress_test(args.threads, args.items * 100),
+        },
+        Demo {
+            name: "ch2::lazy_one_time_key_initialization",
+            about: "racy key generation where the first compare_exchange wins",
+            skip_in_run_all: None,
+            run: |args| {
+                let keys: Vec<u64> = std::thread::scope(|s| {
+                    let handles: Vec<_> = (0..args.threads)
+                        .map(|_| s.spawn(compare_exchange::lazy_one_time_key_initialization))
+                        .collect();
+                    handles.into_iter().map(|h| h.join().unwrap()).collect()
+                });
+                println!("keys: {keys:?}");
+                assert!(keys.iter().all(|&k| k == keys[0]));
+            },
+        },
+        Demo {
+            name: "ch2::race_once_box",
+            about: "first compare_exchange wins for a boxed value, losers free theirs",
+            skip_in_run_all: None,
+            run: |args| race_once_box::race_once_box_demo(args.threads),
+        },
+        Demo {
+            name: "ch2::get_x",
+            about: "racy lazy initialization with load and store",
+            skip_in_run_all: None,
+            run: |_| println!("x: {}", lazy_init::get_x()),
+        },
+        Demo {
+            name: "ch2::get_x_once",
+            about: "lazy initialization with Once and an atomic",
+            skip_in_run_all: None,
+            run: |_| println!("x: {}", lazy_init::get_x_once()),
+        },
+        Demo {
+            name: "ch2::get_x_once_lock",
+            about: "lazy initialization with OnceLock",
+            skip_in_run_all: None,
+            run: |_| println!("x: {}", lazy_init::get_x_once_lock()),
+        },
+        Demo {
+            name: "ch2::lazy_cell",
+            about: "one initializer under the Racy, Blocking and RetryOnPanic LazyCell policies",
+            skip_in_run_all: None,
+            run: |args| lazy_cell::lazy_cell_policies(args.threads),
+        },
+        // ch_3_memory_ordering
+        Demo {
+            name: "ch3::litmus",
+            about: "MP, SB, LB, IRIW and CoRR litmus tests under each ordering, with outcome histograms",
+            skip_in_run_all: None,
+            run: |args| litmus::litmus_suite(args.items as u64 * 10_000),
+        },
+        // ch_4_spin_lock
+        Demo {
+            name: "ch4::mutex_use_spin_lock",
+            about: "the mutex_use workload on our SpinLock, once per backoff strategy",
+            skip_in_run_all: None,
+            run: |args| {
+                for backoff in [Backoff::Spin, Backoff::SpinLoopHint, Backoff::Exponential] {
+                    println!("--- {backoff:?}");
+                    ch_1_basics::mutex_use(
+                        SpinLock::with_backoff(0, backoff),
+                        args.scaled(Duration::from_secs(1)),
+                    );
+                }
+            },
+        },
+        // ch_5_channels
+        Demo {
+            name: "ch5::oneshot",
+            about: "a single result back from a thread, a dropped sender and unreceived messages",
+            skip_in_run_all: None,
+            run: |args| oneshot::oneshot_demo(args.scaled(Duration::from_millis(100))),
+        },
+        Demo {
+            name: "ch5::bounded_channel",
+            about: "producers blocked by a full bounded MPMC channel, timeouts and disconnection",
+            skip_in_run_all: None,
+            run: |args| {
+                bounded::bounded_channel_demo(
+                    args.threads,
+                    args.items,
+                    4,
+                    args.scaled(Duration::from_millis(1)),
+                )
+            },
+        },
+        // ch_6_arc
+        Demo {
+            name: "ch6::our_arc",
+            about: "our Arc and Weak, checked for leaks and double drops",
+            skip_in_run_all: None,
+            run: |args| our_arc::our_arc_demo(args.threads, args.items * 100),
+        },
+        // ch_9_locks
+        #[cfg(target_os = "linux")]
+        Demo {
+            name: "ch9::futex_mutex",
+            about: "our futex Mutex: syscall counters, mutex_use and poisoning",
+            skip_in_run_all: None,
+            run: |args| {
+                ch_9_locks::mutex::futex_mutex_demo(
+                    args.threads,
+                    args.scaled(Duration::from_millis(100)),
+                )
+            },
+        },
+        #[cfg(target_os = "linux")]
+        Demo {
+            name: "ch9::futex_condvar",
+            about: "our Condvar: skipped syscalls, timeouts and spurious wakeups",
+            skip_in_run_all: None,
+            run: |args| ch_9_locks::condvar::futex_condvar_demo(args.threads, args.items),
+        },
+        #[cfg(target_os = "linux")]
+        Demo {
+            name: "ch9::rwlock",
+            about:
+                "writer starvation with a reader-preferring RwLock, and our writer-preferring one",
+            skip_in_run_all: None,
+            run: |args| {
+                ch_9_locks::rwlock::writer_starvation(
+                    args.threads,
+                    args.scaled(Duration::from_millis(200)),
+                )
+            },
+        },
+        // verification
+        Demo {
+            name: "verification::explorer",
+            about: "every interleaving of small programs: the allocate_new_id_subtract overshoot and the old get_x_once",
+            skip_in_run_all: None,
+            run: |_| explorer::explorer_demo(2),
+        },
+        Demo {
+            name: "verification::weak_memory",
+            about: "every outcome C++20 and x86 allow for Relaxed, Release/Acquire and SeqCst programs",
+            skip_in_run_all: None,
+            run: |_| weak_memory::weak_memory_demo(),
+        },
+        Demo {
+            name: "verification::race_detector",
+            about: "vector clock race detection on RaceCell, for a Relaxed spin lock and a Relaxed handoff",
+            skip_in_run_all: None,
+            run: |args| race_detector::race_detector_demo(args.threads, args.items),
+        },
+    ]
+}
+
+// Most of the allocators panic once their process-wide counter gets to 1000, so no run asks for more than that.
+const MAX_IDS: usize = 1000;
+
+fn print_ids(args: &DemoArgs, allocate: fn() -> u32) {
+    let count = args.items.min(MAX_IDS);
+    let mut last = None;
+    for _ in 0..count {
+        last = Some(allocate());
+    }
+    println!("allocated {count} ids, last: {last:?}");
+}
+
+#[derive(Debug)]
+pub enum Command {
+    List,
+    Run(String, DemoArgs),
+    RunAll(DemoArgs),
+    Help,
+}
+
+#[derive(Debug)]
+pub struct UsageError(String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\n\n{USAGE}", self.0)
+    }
+}
+
+pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Command, UsageError> {
+    let Some(cmd) = args.next() else {
+        return Ok(Command::Help);
+    };
+    match cmd.as_str() {
+        "list" => Ok(Command::List),
+        "help" | "-h" | "--help" => Ok(Command::Help),
+        "run" => {
+            let name = args
+                .next()
+                .ok_or_else(|| UsageError("run: missing demo name".into()))?;
+            Ok(Command::Run(name, parse_options(args)?))
+        }
+        "run-all" => Ok(Command::RunAll(parse_options(args)?)),
+        cmd => Err(UsageError(format!("unknown command: {cmd:?}"))),
+    }
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> Result<DemoArgs, UsageError> {
+    let mut demo_args = DemoArgs::default();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| UsageError(format!("{flag}: missing value")))?;
+        let invalid = || UsageError(format!("{flag}: invalid value {value:?}"));
+        match flag.as_str() {
+            "--items" => demo_args.items = value.parse().map_err(|_| invalid())?,
+            "--threads" => {
+                demo_args.threads = value.parse().map_err(|_| invalid())?;
+                if demo_args.threads == 0 {
+                    return Err(invalid());
+                }
+            }
+            "--script" => demo_args.script = Some(PathBuf::from(value)),
+            "--clock" => {
+                demo_args.virtual_clock = match value.as_str() {
+                    "real" => false,
+                    "virtual" => true,
+                    _ => return Err(invalid()),
+                }
+            }
+            "--sleep-scale" => {
+                demo_args.sleep_scale = value.parse().map_err(|_| invalid())?;
+                if !(demo_args.sleep_scale >= 0.0 && demo_args.sleep_scale.is_finite()) {
+                    return Err(invalid());
+                }
+            }
+            _ => return Err(UsageError(format!("unknown option: {flag:?}"))),
+        }
+    }
+    Ok(demo_args)
+}
+
+pub fn execute(command: Command) -> Result<(), UsageError> {
+    let demos = demos();
+    match command {
+        Command::Help => println!("{USAGE}"),
+        Command::List => {
+            let width = demos.iter().map(|d| d.name.len()).max().unwrap_or(0);
+            for demo in &demos {
+                match demo.skip_in_run_all {
+                    Some(reason) => {
+                        println!(
+                            "{:width$}  {} [not in run-all: {reason}]",
+                            demo.name, demo.about
+                        )
+                    }
+                    None => println!("{:width$}  {}", demo.name, demo.about),
+                }
+            }
+        }
+        Command::Run(name, args) => {
+            let demo = demos
+                .iter()
+                .find(|d| d.name == name)
+                .ok_or_else(|| UsageError(format!("unknown demo: {name:?} (see `list`)")))?;
+            (demo.run)(&args);
+        }
+        Command::RunAll(args) => {
+            for demo in &demos {
+                if let Some(reason) = demo.skip_in_run_all {
+                    println!("=== skipping {} ({reason})", demo.name);
+                    continue;
+                }
+                println!("=== {}", demo.name);
+                (demo.run)(&args);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every demo's asserts have to hold however far the sleeps are scaled down, including the documented 0.01.
+    // One test, since the verification demos share global state and can't run alongside each other.
+    #[test]
+    fn run_all_scaled_down() {
+        for args in [
+            "--sleep-scale 0.01",
+            "--sleep-scale 0",
+            "--sleep-scale 0.01 --clock virtual",
+        ] {
+            let args = parse_options(args.split_whitespace().map(String::from)).unwrap();
+            execute(Command::RunAll(args)).unwrap();
+        }
+    }
+}
@@ -0,0 +1,55 @@
+/*
+  What the chapters' own lock-free types are built on, instead of std's UnsafeCell and atomics directly.
+  Normally these are std's. With `--features race_detector`, they're the race_detector's RaceCell and instrumented atomics,
+  so the detector can check the real types instead of copies of them. The same trick as `loom`.
+
+  std's UnsafeCell gives out a raw pointer with `get`, which doesn't say whether it's for reading or writing,
+  so both versions have RaceCell's `with` and `with_mut` instead.
+*/
+
+#[cfg(not(feature = "race_detector"))]
+pub mod cell {
+    // std's UnsafeCell, with RaceCell's API.
+    pub struct UnsafeCell<T>(std::cell::UnsafeCell<T>);
+
+    impl<T> UnsafeCell<T> {
+        pub const fn new(value: T) -> Self {
+            Self(std::cell::UnsafeCell::new(value))
+        }
+
+        pub fn with<R>(&self, f: impl FnOnce(*const T) -> R) -> R {
+            f(self.0.get())
+        }
+
+        pub fn with_mut<R>(&self, f: impl FnOnce(*mut T) -> R) -> R {
+            f(self.0.get())
+        }
+
+        pub fn get_mut(&mut self) -> &mut T {
+            self.0.get_mut()
+        }
+
+        pub fn into_inner(self) -> T {
+            self.0.into_inner()
+        }
+    }
+}
+
+#[cfg(feature = "race_detector")]
+pub mod cell {
+    pub use crate::verification::race_detector::RaceCell as UnsafeCell;
+}
+
+pub mod atomic {
+    pub use std::sync::atomic::Ordering;
+
+    #[cfg(not(feature = "race_detector"))]
+    pub use std::sync::atomic::{
+        fence, AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicU8, AtomicUsize,
+    };
+
+    #[cfg(feature = "race_detector")]
+    pub use crate::verification::race_detector::{
+        fence, AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicU8, AtomicUsize,
+    };
+}
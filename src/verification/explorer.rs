@@ -0,0 +1,1010 @@
+use crate::ch_2_atomics::{
+    fetch_add_example,
+    id_allocator::{AtomicInteger, IdAllocator, Strategy},
+    lazy_init::{self, CallOnce},
+};
+use std::{
+    cell::{RefCell, UnsafeCell},
+    fmt,
+    ops::{Deref, DerefMut},
+    panic::{self, AssertUnwindSafe, PanicHookInfo},
+    sync::{
+        atomic::{self, Ordering},
+        Arc, Condvar as StdCondvar, Mutex as StdMutex, MutexGuard as StdMutexGuard, PoisonError,
+    },
+    thread,
+};
+
+/*
+  A model checker for small programs: the races in ch_2_atomics only show up under rare schedules,
+  so instead of running a program many times and hoping, this runs it once under every schedule.
+
+  - The program uses the shim types below instead of std's: atomics, a Mutex, a Once, spawn and join.
+  - Every shim operation is a yield point. Each model thread runs on a real thread, but only the one holding
+    the turn runs, and at every yield point the scheduler decides who gets the turn next.
+  - Exploring is a depth first search over those decisions. Each execution replays the decisions of the previous
+    one up to the last one that has an untried alternative, takes that alternative, and goes on with defaults.
+    The program has to be deterministic apart from the schedule for that to work.
+    Code built on the shims can add decisions of its own with `choose`, and those get explored the same way.
+  - Bounded preemption: taking the turn away from a thread that could have continued is a preemption,
+    and an execution only gets `preemption_bound` of them. Switching because a thread blocked or finished is free.
+    Almost every real concurrency bug needs only one or two preemptions, and the bound keeps the number
+    of executions polynomial instead of exponential.
+  - An execution fails when a model thread panics (e.g. a failed assert), or when all threads are blocked.
+    The other threads are then unwound, and the failure comes with the trace of operations that led to it.
+
+  Every operation runs SeqCst, whatever ordering it's given: this explores interleavings, not reorderings.
+  Outside an exploration the shims behave like the plain std types.
+  Explorations swap out the panic hook to keep failed executions quiet, so they take turns:
+  one that starts while another is running (say, in a parallel test) waits for it to finish.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Runnable,
+    Blocked(Resource),
+    Finished,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resource {
+    // by address
+    Mutex(usize),
+    Thread(usize),
+}
+
+struct Execution {
+    // indexed by model thread id, t0 being the closure passed to explore
+    threads: Vec<State>,
+    active: usize,
+    preemptions: usize,
+    preemption_bound: usize,
+    // the decisions to replay, as indices into each decision's options
+    prefix: Vec<usize>,
+    // every decision made so far: (index taken, number of options)
+    decisions: Vec<(usize, usize)>,
+    trace: Vec<String>,
+    failure: Option<String>,
+}
+
+impl Execution {
+    // Picks the thread that runs next. `me` is the one giving up the turn: still runnable, blocked or finished.
+    fn schedule(&mut self, me: usize) {
+        let mut options = Vec::new();
+        let me_runnable = self.threads[me] == State::Runnable;
+        if me_runnable {
+            options.push(me);
+        }
+        if !me_runnable || self.preemptions < self.preemption_bound {
+            options.extend(
+                (0..self.threads.len()).filter(|&t| t != me && self.threads[t] == State::Runnable),
+            );
+        }
+        if options.is_empty() {
+            if self.threads.iter().any(|s| *s != State::Finished) {
+                self.failure = Some(format!("deadlock: {}", self.blocked()));
+            }
+            return;
+        }
+        self.active = options[self.decide(options.len())];
+        if me_runnable && self.active != me {
+            self.preemptions += 1;
+        }
+    }
+
+    // The next decision out of `options`: replayed from the prefix, or the first one.
+    fn decide(&mut self, options: usize) -> usize {
+        let index = self
+            .prefix
+            .get(self.decisions.len())
+            .copied()
+            .unwrap_or(0)
+            .min(options - 1);
+        self.decisions.push((index, options));
+        index
+    }
+
+    fn blocked(&self) -> String {
+        let blocked: Vec<String> = (0..self.threads.len())
+            .filter_map(|t| match self.threads[t] {
+                State::Blocked(Resource::Mutex(_)) => Some(format!("t{t} waits for a Mutex")),
+                State::Blocked(Resource::Thread(other)) => Some(format!("t{t} joins t{other}")),
+                _ => None,
+            })
+            .collect();
+        blocked.join(", ")
+    }
+
+    fn unblock(&mut self, resource: Resource) {
+        for state in &mut self.threads {
+            if *state == State::Blocked(resource) {
+                *state = State::Runnable;
+            }
+        }
+    }
+}
+
+struct Shared {
+    execution: StdMutex<Execution>,
+    // notified whenever the turn moves, a thread finishes, or the execution failed
+    turn: StdCondvar,
+}
+
+thread_local! {
+    // the execution a model thread belongs to, and its id. None outside an exploration.
+    static CURRENT: RefCell<Option<(Arc<Shared>, usize)>> = const { RefCell::new(None) };
+    // the last panic's message and location, set by the hook
+    static PANIC: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// The panic payload that unwinds the other threads once one of them failed.
+struct Abort;
+
+fn current() -> Option<(Arc<Shared>, usize)> {
+    CURRENT.with(|c| c.borrow().clone())
+}
+
+fn wait_for_turn(shared: &Shared, mut execution: StdMutexGuard<'_, Execution>, me: usize) {
+    while execution.active != me && execution.failure.is_none() {
+        execution = shared.turn.wait(execution).unwrap();
+    }
+    if execution.failure.is_some() {
+        drop(execution);
+        panic::resume_unwind(Box::new(Abort));
+    }
+}
+
+// The model thread id of the current thread: 0 for the closure passed to explore, and outside an exploration.
+pub fn current_thread() -> usize {
+    current().map_or(0, |(_, me)| me)
+}
+
+// A nondeterministic choice out of `options`, explored like the choice of the next thread.
+// For code built on top of the shims, like the weak memory simulator. Always 0 outside an exploration.
+pub fn choose(options: usize) -> usize {
+    assert!(options > 0, "nothing to choose from");
+    match current() {
+        Some((shared, _)) => shared.execution.lock().unwrap().decide(options),
+        None => 0,
+    }
+}
+
+// Before every shim operation: the scheduler may hand the turn to another thread here.
+pub(crate) fn yield_point() {
+    let Some((shared, me)) = current() else {
+        return;
+    };
+    // unwinding after a failure: just let the destructors run
+    if thread::panicking() {
+        return;
+    }
+    let mut execution = shared.execution.lock().unwrap();
+    execution.schedule(me);
+    shared.turn.notify_all();
+    wait_for_turn(&shared, execution, me);
+}
+
+// Blocks the current model thread until `resource` is released. False outside an exploration.
+fn block_on(resource: Resource) -> bool {
+    let Some((shared, me)) = current() else {
+        return false;
+    };
+    let mut execution = shared.execution.lock().unwrap();
+    execution.threads[me] = State::Blocked(resource);
+    execution.schedule(me);
+    shared.turn.notify_all();
+    wait_for_turn(&shared, execution, me);
+    true
+}
+
+fn unblock(resource: Resource) {
+    if let Some((shared, _)) = current() {
+        shared.execution.lock().unwrap().unblock(resource);
+    }
+}
+
+fn trace(entry: impl FnOnce() -> String) {
+    if let Some((shared, me)) = current() {
+        let mut execution = shared.execution.lock().unwrap();
+        // not the destructors running while the threads unwind
+        if execution.failure.is_none() {
+            let entry = entry();
+            execution.trace.push(format!("t{me}: {entry}"));
+        }
+    }
+}
+
+// A yield point, the operation, and its trace entry.
+fn step<R: fmt::Debug>(what: impl FnOnce() -> String, op: impl FnOnce() -> R) -> R {
+    yield_point();
+    let result = op();
+    trace(|| format!("{} -> {result:?}", what()));
+    result
+}
+
+// The body of every model thread.
+fn run_thread<T>(shared: Arc<Shared>, me: usize, f: impl FnOnce() -> T) -> Option<T> {
+    CURRENT.with(|c| *c.borrow_mut() = Some((shared.clone(), me)));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        wait_for_turn(&shared, shared.execution.lock().unwrap(), me);
+        f()
+    }));
+    CURRENT.with(|c| *c.borrow_mut() = None);
+
+    let mut execution = shared.execution.lock().unwrap();
+    if let Err(payload) = &result {
+        if !payload.is::<Abort>() && execution.failure.is_none() {
+            let message = PANIC
+                .with(|p| p.borrow_mut().take())
+                .unwrap_or_else(|| "panicked".to_string());
+            execution.trace.push(format!("t{me}: panicked"));
+            execution.failure = Some(format!("t{me} {message}"));
+        }
+    }
+    execution.threads[me] = State::Finished;
+    execution.unblock(Resource::Thread(me));
+    if execution.failure.is_none() {
+        execution.schedule(me);
+    }
+    shared.turn.notify_all();
+    result.ok()
+}
+
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let Some((shared, _)) = current() else {
+        return JoinHandle {
+            handle: thread::spawn(move || Some(f())),
+            id: None,
+        };
+    };
+    let id = {
+        let mut execution = shared.execution.lock().unwrap();
+        execution.threads.push(State::Runnable);
+        execution.threads.len() - 1
+    };
+    let handle = thread::spawn(move || run_thread(shared, id, f));
+    trace(|| format!("spawn t{id}"));
+    yield_point();
+    JoinHandle {
+        handle,
+        id: Some(id),
+    }
+}
+
+pub struct JoinHandle<T> {
+    handle: thread::JoinHandle<Option<T>>,
+    // the model thread id, None outside an exploration
+    id: Option<usize>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn join(self) -> thread::Result<T> {
+        if let (Some(id), Some((shared, _))) = (self.id, current()) {
+            yield_point();
+            while shared.execution.lock().unwrap().threads[id] != State::Finished {
+                block_on(Resource::Thread(id));
+            }
+            trace(|| format!("join t{id}"));
+        }
+        // a thread that panicked fails the whole execution, so it's never joined
+        self.handle
+            .join()
+            .map(|result| result.expect("joined an aborted thread"))
+    }
+}
+
+macro_rules! atomic_int {
+    ($($name:ident($std:ty, $value:ty)),*) => {$(
+        #[derive(Debug, Default)]
+        pub struct $name($std);
+
+        impl $name {
+            pub const fn new(v: $value) -> Self {
+                Self(<$std>::new(v))
+            }
+
+            pub fn load(&self, _: Ordering) -> $value {
+                step(|| concat!(stringify!($name), "::load").to_string(), || {
+                    self.0.load(Ordering::SeqCst)
+                })
+            }
+
+            pub fn store(&self, v: $value, _: Ordering) {
+                yield_point();
+                self.0.store(v, Ordering::SeqCst);
+                trace(|| format!(concat!(stringify!($name), "::store({})"), v));
+            }
+
+            pub fn swap(&self, v: $value, _: Ordering) -> $value {
+                step(|| format!(concat!(stringify!($name), "::swap({})"), v), || {
+                    self.0.swap(v, Ordering::SeqCst)
+                })
+            }
+
+            pub fn fetch_add(&self, v: $value, _: Ordering) -> $value {
+                step(|| format!(concat!(stringify!($name), "::fetch_add({})"), v), || {
+                    self.0.fetch_add(v, Ordering::SeqCst)
+                })
+            }
+
+            pub fn fetch_sub(&self, v: $value, _: Ordering) -> $value {
+                step(|| format!(concat!(stringify!($name), "::fetch_sub({})"), v), || {
+                    self.0.fetch_sub(v, Ordering::SeqCst)
+                })
+            }
+
+            pub fn compare_exchange(
+                &self,
+                current: $value,
+                new: $value,
+                _: Ordering,
+                _: Ordering,
+            ) -> Result<$value, $value> {
+                step(
+                    || format!(concat!(stringify!($name), "::compare_exchange({}, {})"), current, new),
+                    || self.0.compare_exchange(current, new, Ordering::SeqCst, Ordering::SeqCst),
+                )
+            }
+
+            // never fails spuriously here
+            pub fn compare_exchange_weak(
+                &self,
+                current: $value,
+                new: $value,
+                success: Ordering,
+                failure: Ordering,
+            ) -> Result<$value, $value> {
+                self.compare_exchange(current, new, success, failure)
+            }
+
+            // a load and a compare_exchange loop, like std's, so other threads can get in between
+            pub fn fetch_update(
+                &self,
+                set_order: Ordering,
+                fetch_order: Ordering,
+                mut f: impl FnMut($value) -> Option<$value>,
+            ) -> Result<$value, $value> {
+                let mut prev = self.load(fetch_order);
+                while let Some(next) = f(prev) {
+                    match self.compare_exchange_weak(prev, next, set_order, fetch_order) {
+                        Ok(v) => return Ok(v),
+                        Err(v) => prev = v,
+                    }
+                }
+                Err(prev)
+            }
+        }
+
+        // so IdAllocator runs on the explorer's atomics unchanged
+        impl AtomicInteger for $name {
+            type Value = $value;
+
+            fn new(v: $value) -> Self {
+                <$name>::new(v)
+            }
+            fn load(&self, order: Ordering) -> $value {
+                self.load(order)
+            }
+            fn store(&self, v: $value, order: Ordering) {
+                self.store(v, order)
+            }
+            fn fetch_add_one(&self, order: Ordering) -> $value {
+                self.fetch_add(1, order)
+            }
+            fn fetch_sub_one(&self, order: Ordering) -> $value {
+                self.fetch_sub(1, order)
+            }
+            fn compare_exchange_weak(
+                &self,
+                current: $value,
+                new: $value,
+                success: Ordering,
+                failure: Ordering,
+            ) -> Result<$value, $value> {
+                self.compare_exchange_weak(current, new, success, failure)
+            }
+            fn fetch_update(
+                &self,
+                set_order: Ordering,
+                fetch_order: Ordering,
+                f: impl FnMut($value) -> Option<$value>,
+            ) -> Result<$value, $value> {
+                self.fetch_update(set_order, fetch_order, f)
+            }
+            fn checked_add_one(v: $value) -> Option<$value> {
+                v.checked_add(1)
+            }
+        }
+    )*};
+}
+
+atomic_int!(
+    AtomicU8(atomic::AtomicU8, u8),
+    AtomicU16(atomic::AtomicU16, u16),
+    AtomicU32(atomic::AtomicU32, u32),
+    AtomicU64(atomic::AtomicU64, u64),
+    AtomicUsize(atomic::AtomicUsize, usize)
+);
+
+#[derive(Debug, Default)]
+pub struct AtomicBool(atomic::AtomicBool);
+
+impl AtomicBool {
+    pub const fn new(v: bool) -> Self {
+        Self(atomic::AtomicBool::new(v))
+    }
+
+    pub fn load(&self, _: Ordering) -> bool {
+        step(
+            || "AtomicBool::load".to_string(),
+            || self.0.load(Ordering::SeqCst),
+        )
+    }
+
+    pub fn store(&self, v: bool, _: Ordering) {
+        yield_point();
+        self.0.store(v, Ordering::SeqCst);
+        trace(|| format!("AtomicBool::store({v})"));
+    }
+
+    pub fn swap(&self, v: bool, _: Ordering) -> bool {
+        step(
+            || format!("AtomicBool::swap({v})"),
+            || self.0.swap(v, Ordering::SeqCst),
+        )
+    }
+
+    pub fn compare_exchange(
+        &self,
+        current: bool,
+        new: bool,
+        _: Ordering,
+        _: Ordering,
+    ) -> Result<bool, bool> {
+        step(
+            || format!("AtomicBool::compare_exchange({current}, {new})"),
+            || {
+                self.0
+                    .compare_exchange(current, new, Ordering::SeqCst, Ordering::SeqCst)
+            },
+        )
+    }
+}
+
+// A Mutex that blocks the model thread, so the scheduler never picks a thread that can't make progress.
+pub struct Mutex<T> {
+    locked: atomic::AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+// The guard gives out &T, so sharing the guard shares the T.
+unsafe impl<T: Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: atomic::AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    fn resource(&self) -> Resource {
+        Resource::Mutex(self as *const Self as usize)
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        loop {
+            yield_point();
+            if !self.locked.swap(true, Ordering::Acquire) {
+                trace(|| "Mutex::lock".to_string());
+                return MutexGuard { mutex: self };
+            }
+            trace(|| "Mutex::lock blocks".to_string());
+            // nothing else runs between the swap and blocking, so the unlock can't be missed
+            if !block_on(self.resource()) {
+                thread::yield_now();
+            }
+        }
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: the guard means we hold the lock
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        yield_point();
+        self.mutex.locked.store(false, Ordering::Release);
+        trace(|| "Mutex::unlock".to_string());
+        unblock(self.mutex.resource());
+    }
+}
+
+// std's Once, the way it works underneath: a done flag checked first, and a lock around the initialization.
+pub struct Once {
+    done: AtomicBool,
+    lock: Mutex<()>,
+}
+
+impl Once {
+    pub const fn new() -> Self {
+        Self {
+            done: AtomicBool::new(false),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn call_once(&self, f: impl FnOnce()) {
+        if self.done.load(Ordering::Acquire) {
+            return;
+        }
+        let _guard = self.lock.lock();
+        if !self.done.load(Ordering::Relaxed) {
+            f();
+            self.done.store(true, Ordering::Release);
+        }
+    }
+}
+
+impl Default for Once {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// so lazy_init's get_x_once runs on it unchanged
+impl CallOnce for Once {
+    fn call_once(&self, f: impl FnOnce()) {
+        self.call_once(f)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    pub preemption_bound: usize,
+    // gives up (without a failure) after this many executions
+    pub max_executions: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            preemption_bound: 2,
+            max_executions: 100_000,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Explored {
+    pub executions: usize,
+    // false if it stopped at max_executions
+    pub complete: bool,
+}
+
+#[derive(Debug)]
+pub struct Failure {
+    pub message: String,
+    // the decisions that lead to the failure, for replay
+    pub schedule: Vec<usize>,
+    pub trace: Vec<String>,
+    pub preemptions: usize,
+    // the failing one included
+    pub executions: usize,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.message)?;
+        writeln!(
+            f,
+            "found in execution {}, preemptions: {}, schedule: {:?}",
+            self.executions, self.preemptions, self.schedule
+        )?;
+        for entry in &self.trace {
+            writeln!(f, "  {entry}")?;
+        }
+        Ok(())
+    }
+}
+
+type Program = Arc<dyn Fn() + Send + Sync>;
+
+// One execution of the program, following `prefix` as far as it goes.
+fn execute(program: &Program, preemption_bound: usize, prefix: Vec<usize>) -> Execution {
+    let shared = Arc::new(Shared {
+        execution: StdMutex::new(Execution {
+            threads: vec![State::Runnable],
+            active: 0,
+            preemptions: 0,
+            preemption_bound,
+            prefix,
+            decisions: Vec::new(),
+            trace: Vec::new(),
+            failure: None,
+        }),
+        turn: StdCondvar::new(),
+    });
+    let (program, s) = (program.clone(), shared.clone());
+    thread::spawn(move || run_thread(s, 0, move || program()));
+
+    let mut execution = shared.execution.lock().unwrap();
+    while execution.threads.iter().any(|s| *s != State::Finished) {
+        execution = shared.turn.wait(execution).unwrap();
+    }
+    Execution {
+        threads: Vec::new(),
+        prefix: Vec::new(),
+        decisions: std::mem::take(&mut execution.decisions),
+        trace: std::mem::take(&mut execution.trace),
+        failure: execution.failure.take(),
+        ..*execution
+    }
+}
+
+// held for a whole exploration, so two of them don't swap the panic hook under each other
+static EXPLORING: StdMutex<()> = StdMutex::new(());
+
+// Runs `f` with a panic hook that stays quiet for model threads, and the previous hook restored after.
+fn with_quiet_panics<R>(f: impl FnOnce() -> R) -> R {
+    // a failed assert in an earlier caller poisons it, but the hook was restored before that
+    let _exploring = EXPLORING.lock().unwrap_or_else(PoisonError::into_inner);
+    type Hook = Box<dyn Fn(&PanicHookInfo<'_>) + Sync + Send>;
+    let previous: Arc<Hook> = Arc::new(panic::take_hook());
+    let fallback = previous.clone();
+    panic::set_hook(Box::new(move |info| {
+        if CURRENT.with(|c| c.borrow().is_some()) {
+            PANIC.with(|p| *p.borrow_mut() = Some(info.to_string()));
+        } else {
+            fallback(info);
+        }
+    }));
+    let result = f();
+    drop(panic::take_hook());
+    match Arc::try_unwrap(previous) {
+        Ok(previous) => panic::set_hook(previous),
+        Err(_) => unreachable!("our hook is gone"),
+    }
+    result
+}
+
+fn failure(execution: Execution, executions: usize) -> Option<Failure> {
+    Some(Failure {
+        message: execution.failure?,
+        schedule: execution
+            .decisions
+            .iter()
+            .map(|&(index, _)| index)
+            .collect(),
+        trace: execution.trace,
+        preemptions: execution.preemptions,
+        executions,
+    })
+}
+
+// Runs `f` under every schedule with at most `config.preemption_bound` preemptions, until one fails.
+pub fn explore(config: Config, f: impl Fn() + Send + Sync + 'static) -> Result<Explored, Failure> {
+    let program: Program = Arc::new(f);
+    with_quiet_panics(|| {
+        let mut prefix = Vec::new();
+        for executions in 1..=config.max_executions {
+            let mut execution = execute(&program, config.preemption_bound, prefix);
+            let mut decisions = std::mem::take(&mut execution.decisions);
+            if execution.failure.is_some() {
+                execution.decisions = decisions;
+                return Err(failure(execution, executions).unwrap());
+            }
+            // backtrack to the last decision with an alternative left
+            loop {
+                match decisions.pop() {
+                    Some((index, options)) if index + 1 < options => {
+                        prefix = decisions.iter().map(|&(index, _)| index).collect();
+                        prefix.push(index + 1);
+                        break;
+                    }
+                    Some(_) => {}
+                    None => {
+                        return Ok(Explored {
+                            executions,
+                            complete: true,
+                        })
+                    }
+                }
+            }
+        }
+        Ok(Explored {
+            executions: config.max_executions,
+            complete: false,
+        })
+    })
+}
+
+// Runs `f` once, under a schedule from a Failure.
+pub fn replay(schedule: &[usize], f: impl Fn() + Send + Sync + 'static) -> Result<(), Failure> {
+    let program: Program = Arc::new(f);
+    with_quiet_panics(|| {
+        let execution = execute(&program, usize::MAX, schedule.to_vec());
+        failure(execution, 1).map_or(Ok(()), Err)
+    })
+}
+
+/*
+  The two bugs the explorer is for.
+
+  allocate_new_id_subtract's counter overshoots the end between its fetch_add and its fetch_sub,
+  and with a narrow enough counter, a second overshooting thread wraps it to 0.
+  Here that's its body on a u8 with only id 254 left: t1 takes 254, t2 brings the counter to 255 and is preempted,
+  t3 wraps it to 0, and 0 is below the end, so it's handed out. Two ids from a range of one.
+  IdAllocator's FetchAddRollback is the same thing, and its other strategies don't have the problem.
+*/
+fn allocate_one_of_one<A: Send + Sync + 'static>(allocator: A, allocate: fn(&A) -> Option<u8>) {
+    let allocator = Arc::new(allocator);
+    let handles: Vec<_> = (0..3)
+        .map(|_| {
+            let allocator = allocator.clone();
+            spawn(move || allocate(&allocator))
+        })
+        .collect();
+    let ids: Vec<u8> = handles
+        .into_iter()
+        .filter_map(|h| h.join().unwrap())
+        .collect();
+    assert!(
+        ids.len() <= 1 && ids.iter().all(|&id| id == 254),
+        "allocated {ids:?} from 254..255"
+    );
+}
+
+fn subtract_on_overshoot() {
+    allocate_one_of_one(AtomicU8::new(254), |next_id| {
+        fetch_add_example::subtract_on_overshoot(next_id, 255)
+    });
+}
+
+fn allocate_with(strategy: Strategy) {
+    allocate_one_of_one(
+        IdAllocator::<AtomicU8>::new(254..255, strategy),
+        |allocator| allocator.try_allocate().ok(),
+    );
+}
+
+// get_x_once from lazy_init.rs, as it was before the fix: X is loaded before call_once.
+fn get_x_once_stale(start: &Once, x_cell: &AtomicU64) -> u64 {
+    let mut x = x_cell.load(Ordering::Relaxed);
+    start.call_once(|| {
+        x = 12;
+        x_cell.store(12, Ordering::Relaxed);
+    });
+    x
+}
+
+fn racing_get_x(get_x: fn(&Once, &AtomicU64) -> u64) {
+    let state = Arc::new((Once::new(), AtomicU64::new(0)));
+    let other = {
+        let state = state.clone();
+        spawn(move || get_x(&state.0, &state.1))
+    };
+    let mine = get_x(&state.0, &state.1);
+    assert_eq!((mine, other.join().unwrap()), (12, 12), "get_x returned 0");
+}
+
+// a lock order inversion is a schedule where both threads block
+fn lock_order_inversion() {
+    let locks = Arc::new((Mutex::new(()), Mutex::new(())));
+    let t = {
+        let locks = locks.clone();
+        spawn(move || {
+            let _b = locks.1.lock();
+            let _a = locks.0.lock();
+        })
+    };
+    {
+        let _a = locks.0.lock();
+        let _b = locks.1.lock();
+    }
+    t.join().unwrap();
+}
+
+fn report(name: &str, config: Config, f: fn()) {
+    match explore(config, f) {
+        Ok(explored) => println!(
+            "{name}: no failure in {} {} executions with up to {} preemptions",
+            if explored.complete {
+                "all"
+            } else {
+                "the first"
+            },
+            explored.executions,
+            config.preemption_bound
+        ),
+        Err(failure) => print!("{name}: {failure}"),
+    }
+}
+
+pub fn explorer_demo(preemption_bound: usize) {
+    let config = Config {
+        preemption_bound,
+        ..Config::default()
+    };
+    report("allocate_new_id_subtract", config, subtract_on_overshoot);
+    report("IdAllocator CompareExchange", config, || {
+        allocate_with(Strategy::CompareExchange)
+    });
+    report("IdAllocator FetchUpdate", config, || {
+        allocate_with(Strategy::FetchUpdate)
+    });
+    report("get_x_once, before the fix", config, || {
+        racing_get_x(get_x_once_stale)
+    });
+    report("get_x_once", config, || {
+        racing_get_x(lazy_init::get_x_once_with)
+    });
+    report("lock order inversion", config, lock_order_inversion);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the failure, after checking its schedule gets the same one again
+    fn expect_failure(config: Config, f: fn()) -> Failure {
+        let failure = explore(config, f).expect_err("no failure found");
+        assert!(failure.executions > 1, "failed without any preemption");
+        let again = replay(&failure.schedule, f).expect_err("replaying the failure");
+        assert_eq!(again.message, failure.message);
+        assert_eq!(again.trace, failure.trace);
+        failure
+    }
+
+    fn expect_success(config: Config, f: fn()) {
+        match explore(config, f) {
+            Ok(explored) => assert!(explored.complete),
+            Err(failure) => panic!("{failure}"),
+        }
+    }
+
+    #[test]
+    fn allocate_new_id_subtract_hands_out_a_wrapped_id() {
+        let failure = expect_failure(Config::default(), subtract_on_overshoot);
+        assert!(failure.message.contains("allocated"), "{failure}");
+        assert!(
+            failure.message.contains("[254, 0]") || failure.message.contains("[0, 254]"),
+            "{failure}"
+        );
+        assert!(failure.preemptions >= 1);
+        // t3's fetch_add saw the counter after t2 wrapped it
+        assert!(
+            failure
+                .trace
+                .iter()
+                .any(|e| e.ends_with("AtomicU8::fetch_add(1) -> 255")),
+            "{failure}"
+        );
+        assert!(failure
+            .trace
+            .iter()
+            .any(|e| e.ends_with("AtomicU8::fetch_add(1) -> 0")));
+    }
+
+    #[test]
+    fn allocate_new_id_subtract_is_fine_far_from_the_max() {
+        expect_success(Config::default(), || {
+            let next_id = Arc::new(AtomicU32::new(998));
+            let handles: Vec<_> = (0..3)
+                .map(|_| {
+                    let next_id = next_id.clone();
+                    spawn(move || fetch_add_example::subtract_on_overshoot(&*next_id, 1000))
+                })
+                .collect();
+            let mut ids: Vec<u32> = handles
+                .into_iter()
+                .filter_map(|h| h.join().unwrap())
+                .collect();
+            ids.sort();
+            assert_eq!(ids, [998, 999]);
+            assert_eq!(next_id.load(Ordering::Relaxed), 1000);
+        });
+    }
+
+    #[test]
+    fn id_allocator_strategies() {
+        let failure = expect_failure(Config::default(), || {
+            allocate_with(Strategy::FetchAddRollback)
+        });
+        assert!(failure.message.contains("allocated"), "{failure}");
+        expect_success(Config::default(), || {
+            allocate_with(Strategy::CompareExchange)
+        });
+        expect_success(Config::default(), || allocate_with(Strategy::FetchUpdate));
+    }
+
+    #[test]
+    fn get_x_once() {
+        expect_success(Config::default(), || {
+            racing_get_x(lazy_init::get_x_once_with)
+        });
+    }
+
+    #[test]
+    fn get_x_once_before_the_fix_returns_a_stale_0() {
+        let failure = expect_failure(Config::default(), || racing_get_x(get_x_once_stale));
+        assert!(failure.message.contains("get_x returned 0"), "{failure}");
+        // the loser loaded X before the winner stored it
+        let load = failure
+            .trace
+            .iter()
+            .position(|e| e.ends_with("AtomicU64::load -> 0"));
+        let store = failure
+            .trace
+            .iter()
+            .position(|e| e.ends_with("AtomicU64::store(12)"));
+        assert!(load < store, "{failure}");
+    }
+
+    #[test]
+    fn no_preemptions_no_failure() {
+        let config = Config {
+            preemption_bound: 0,
+            ..Config::default()
+        };
+        expect_success(config, subtract_on_overshoot);
+        expect_success(config, || racing_get_x(get_x_once_stale));
+    }
+
+    #[test]
+    fn deadlock_reported() {
+        let failure = expect_failure(Config::default(), lock_order_inversion);
+        assert_eq!(
+            failure.message,
+            "deadlock: t0 waits for a Mutex, t1 waits for a Mutex"
+        );
+    }
+
+    #[test]
+    fn gives_up_at_max_executions() {
+        let config = Config {
+            max_executions: 3,
+            ..Config::default()
+        };
+        let explored = explore(config, || racing_get_x(lazy_init::get_x_once_with)).unwrap();
+        assert_eq!(explored.executions, 3);
+        assert!(!explored.complete);
+    }
+
+    #[test]
+    fn replaying_a_passing_schedule() {
+        assert!(replay(&[], || racing_get_x(lazy_init::get_x_once_with)).is_ok());
+    }
+
+    #[test]
+    fn shims_work_outside_an_exploration() {
+        let once = Once::new();
+        let x = AtomicU64::new(0);
+        assert_eq!(lazy_init::get_x_once_with(&once, &x), 12);
+        assert_eq!(spawn(current_thread).join().unwrap(), 0);
+        assert_eq!(choose(3), 0);
+        *Mutex::new(1).lock() += 1;
+    }
+}
@@ -0,0 +1,3 @@
+pub mod explorer;
+pub mod race_detector;
+pub mod weak_memory;
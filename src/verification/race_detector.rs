@@ -0,0 +1,1148 @@
+use std::{
+    cell::{RefCell, UnsafeCell},
+    fmt,
+    ops::{Deref, DerefMut},
+    panic::Location,
+    sync::{
+        atomic::{self, Ordering},
+        Mutex as StdMutex, MutexGuard as StdMutexGuard,
+    },
+    thread,
+};
+
+// https://marabos.nl/atomics/basics.html#unsafecell
+// https://marabos.nl/atomics/memory-ordering.html#happens-before
+
+/*
+  A data race detector for code built on UnsafeCell, like a tiny ThreadSanitizer.
+
+  - Happens-before is tracked with vector clocks: every thread has a clock with one counter per thread,
+    and `a happens before b` exactly when b's thread had seen a's thread's counter at the time of a.
+  - The instrumented sync types carry clocks from one thread to another, along the same edges the memory model has:
+    - a Release store (or RMW) leaves the thread's clock on the atomic, and an Acquire load that reads it joins it in.
+      Relaxed ones carry nothing, and a plain store cuts the release sequence off, like in C++20.
+    - fences: a Relaxed load or RMW keeps what it read aside, and a later fence(Acquire) joins it in.
+      A fence(Release) takes a snapshot of the clock, and later Relaxed stores and RMWs hand that out.
+    - unlocking a Mutex leaves the clock on it, locking it joins it in.
+    - spawn hands the parent's clock to the child, join hands the child's back.
+  - RaceCell<T> is an UnsafeCell<T> that remembers its last write and the last read of every thread.
+    An access that conflicts with one of those (two writes, or a read and a write) from another thread,
+    without happening after it, is a data race. It's recorded with both threads and both access kinds,
+    and the program keeps going.
+  - `detect` runs a closure in a session of its own, and returns the races found in it.
+    Threads started with `spawn` join their parent's session, so parallel tests don't see each other's races.
+    `take_races` picks up the ones found outside any session.
+
+  Like ThreadSanitizer it only sees the races in the run it's watching, not the ones a different schedule would have.
+  But it doesn't need the schedule to go wrong either: a Relaxed unlock is a race on every run, even on x86.
+  The instrumented atomics also do their operation and their clock update under one lock, so they're never
+  weaker than SeqCst in practice. Races are found from the orderings the code asks for, not from what the hardware does.
+
+  Threads have to be started with `spawn` or `spawn_scoped` to inherit their parent's clock: one that isn't
+  starts with nothing, and everything its parent did before it looks like a race.
+  The checks only run with debug assertions on. Without them RaceCell is a plain UnsafeCell.
+
+  Thread ids are never reused, and every clock has a slot for every thread that has touched the detector so far.
+  So every clock operation gets slower with the total number of threads ever started, not the number running:
+  fine for a test or a demo, not for a long running program that keeps starting short lived threads.
+
+  With `--features race_detector`, crate::sync hands these out instead of std's UnsafeCell and atomics,
+  so the chapters' own types (SpinLock, LazyCell, RaceOnceBox, the channels, our_arc, ch_9_locks) run on them.
+*/
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct VectorClock(Vec<u64>);
+
+impl VectorClock {
+    fn get(&self, thread: usize) -> u64 {
+        self.0.get(thread).copied().unwrap_or(0)
+    }
+
+    fn set(&mut self, thread: usize, time: u64) {
+        if self.0.len() <= thread {
+            self.0.resize(thread + 1, 0);
+        }
+        self.0[thread] = time;
+    }
+
+    fn join(&mut self, other: &VectorClock) {
+        if self.0.len() < other.0.len() {
+            self.0.resize(other.0.len(), 0);
+        }
+        for (a, b) in self.0.iter_mut().zip(&other.0) {
+            *a = (*a).max(*b);
+        }
+    }
+}
+
+struct ThreadClock {
+    id: usize,
+    // the `detect` call the thread runs under, 0 for none
+    session: usize,
+    clock: VectorClock,
+    // what Relaxed loads and RMWs read, for a later fence(Acquire) to join in
+    fence_acquire: VectorClock,
+    // the clock at the last fence(Release), for later Relaxed stores and RMWs to hand out
+    fence_release: VectorClock,
+}
+
+impl ThreadClock {
+    // the current time on this thread
+    fn now(&self) -> u64 {
+        self.clock.get(self.id)
+    }
+
+    // what a release hands out. Everything after it is a new moment, so it's not covered.
+    fn release(&mut self) -> VectorClock {
+        let clock = self.clock.clone();
+        self.clock.set(self.id, self.now() + 1);
+        clock
+    }
+}
+
+// thread ids, in order of first use
+static NEXT_THREAD: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+static NEXT_SESSION: atomic::AtomicUsize = atomic::AtomicUsize::new(1);
+
+thread_local! {
+    static THREAD: RefCell<Option<ThreadClock>> = const { RefCell::new(None) };
+}
+
+fn register(inherited: VectorClock, session: usize) -> ThreadClock {
+    let id = NEXT_THREAD.fetch_add(1, Ordering::Relaxed);
+    let mut clock = inherited;
+    clock.set(id, 1);
+    ThreadClock {
+        id,
+        session,
+        clock,
+        fence_acquire: VectorClock::default(),
+        fence_release: VectorClock::default(),
+    }
+}
+
+fn with_thread<R>(f: impl FnOnce(&mut ThreadClock) -> R) -> R {
+    THREAD.with(|t| {
+        let mut t = t.borrow_mut();
+        f(t.get_or_insert_with(|| register(VectorClock::default(), 0)))
+    })
+}
+
+// Reading `clock` off an atomic: joins it in, or with Relaxed, keeps it for the next fence(Acquire).
+fn acquire(clock: &VectorClock, order: Ordering) {
+    with_thread(|t| {
+        if is_acquire(order) {
+            t.clock.join(clock);
+        } else {
+            t.fence_acquire.join(clock);
+        }
+    });
+}
+
+// What a store or RMW leaves on an atomic: our clock, or with Relaxed, the one from the last fence(Release).
+fn release(order: Ordering) -> VectorClock {
+    with_thread(|t| {
+        if is_release(order) {
+            t.release()
+        } else {
+            t.fence_release.clone()
+        }
+    })
+}
+
+pub fn fence(order: Ordering) {
+    atomic::fence(order);
+    with_thread(|t| {
+        if is_acquire(order) {
+            let acquired = std::mem::take(&mut t.fence_acquire);
+            t.clock.join(&acquired);
+        }
+        if is_release(order) {
+            t.fence_release = t.release();
+        }
+    });
+}
+
+fn is_acquire(order: Ordering) -> bool {
+    matches!(
+        order,
+        Ordering::Acquire | Ordering::AcqRel | Ordering::SeqCst
+    )
+}
+
+fn is_release(order: Ordering) -> bool {
+    matches!(
+        order,
+        Ordering::Release | Ordering::AcqRel | Ordering::SeqCst
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+impl fmt::Display for AccessKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessKind::Read => f.write_str("read"),
+            AccessKind::Write => f.write_str("write"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Access {
+    pub thread: usize,
+    pub kind: AccessKind,
+    pub location: &'static Location<'static>,
+    // the thread's own time, to compare against other threads' clocks
+    time: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Race {
+    pub earlier: Access,
+    pub later: Access,
+}
+
+impl fmt::Display for Race {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "data race: {} on thread {} at {}, and {} on thread {} at {}, not ordered by happens-before",
+            self.earlier.kind,
+            self.earlier.thread,
+            self.earlier.location,
+            self.later.kind,
+            self.later.thread,
+            self.later.location
+        )
+    }
+}
+
+// with the session they were found in
+static RACES: StdMutex<Vec<(usize, Race)>> = StdMutex::new(Vec::new());
+
+fn take_session(session: usize) -> Vec<Race> {
+    let mut races = RACES.lock().unwrap();
+    let (taken, rest) = std::mem::take(&mut *races)
+        .into_iter()
+        .partition(|&(s, _)| s == session);
+    *races = rest;
+    taken.into_iter().map(|(_, race)| race).collect()
+}
+
+// Every race found outside a `detect` session since the last call.
+pub fn take_races() -> Vec<Race> {
+    take_session(0)
+}
+
+// Runs `f` in a new session, and returns the races found by it and the threads it spawned.
+pub fn detect<R>(f: impl FnOnce() -> R) -> (R, Vec<Race>) {
+    let session = NEXT_SESSION.fetch_add(1, Ordering::Relaxed);
+    let outer = with_thread(|t| std::mem::replace(&mut t.session, session));
+    let result = f();
+    with_thread(|t| t.session = outer);
+    (result, take_session(session))
+}
+
+#[derive(Default)]
+struct Shadow {
+    write: Option<Access>,
+    // the last read of every thread since the last write
+    reads: Vec<Access>,
+}
+
+pub struct RaceCell<T> {
+    value: UnsafeCell<T>,
+    shadow: StdMutex<Shadow>,
+}
+
+impl<T> RaceCell<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            value: UnsafeCell::new(value),
+            shadow: StdMutex::new(Shadow {
+                write: None,
+                reads: Vec::new(),
+            }),
+        }
+    }
+
+    // Checks `kind` against the earlier accesses, and records it.
+    #[track_caller]
+    fn access(&self, kind: AccessKind) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        let location = Location::caller();
+        let mut shadow = self.shadow.lock().unwrap();
+        let mut races = Vec::new();
+        let access = with_thread(|t| {
+            let access = Access {
+                thread: t.id,
+                kind,
+                location,
+                time: t.now(),
+            };
+            // an earlier access is fine if it's ours, or we've seen it happen
+            let unordered = |earlier: &Access| {
+                earlier.thread != t.id && t.clock.get(earlier.thread) < earlier.time
+            };
+            let conflicting = match kind {
+                AccessKind::Read => shadow.write.iter().collect::<Vec<_>>(),
+                AccessKind::Write => shadow.write.iter().chain(&shadow.reads).collect(),
+            };
+            for &earlier in conflicting {
+                if unordered(&earlier) {
+                    races.push((
+                        t.session,
+                        Race {
+                            earlier,
+                            later: access,
+                        },
+                    ));
+                }
+            }
+            access
+        });
+        match kind {
+            AccessKind::Read => {
+                shadow.reads.retain(|r| r.thread != access.thread);
+                shadow.reads.push(access);
+            }
+            AccessKind::Write => {
+                shadow.write = Some(access);
+                shadow.reads.clear();
+            }
+        }
+        RACES.lock().unwrap().extend(races);
+    }
+
+    // UnsafeCell::get, split in two so the detector knows which kind of access it is.
+    #[track_caller]
+    pub fn with<R>(&self, f: impl FnOnce(*const T) -> R) -> R {
+        self.access(AccessKind::Read);
+        f(self.value.get())
+    }
+
+    #[track_caller]
+    pub fn with_mut<R>(&self, f: impl FnOnce(*mut T) -> R) -> R {
+        self.access(AccessKind::Write);
+        f(self.value.get())
+    }
+
+    // &mut self: nobody else can have access, so there's nothing to check
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+macro_rules! atomic {
+    ($($name:ident($value:ty)),*) => {$(
+        // The std atomic, plus the clock of the last release sequence.
+        #[derive(Default)]
+        pub struct $name {
+            value: atomic::$name,
+            clock: StdMutex<VectorClock>,
+        }
+
+        impl $name {
+            pub const fn new(v: $value) -> Self {
+                Self {
+                    value: atomic::$name::new(v),
+                    clock: StdMutex::new(VectorClock(Vec::new())),
+                }
+            }
+
+            pub fn load(&self, order: Ordering) -> $value {
+                let clock = self.clock.lock().unwrap();
+                let v = self.value.load(order);
+                acquire(&clock, order);
+                v
+            }
+
+            pub fn store(&self, v: $value, order: Ordering) {
+                let mut clock = self.clock.lock().unwrap();
+                self.value.store(v, order);
+                *clock = release(order);
+            }
+
+            // An RMW continues the release sequence it read from, and adds its own clock if it's a release.
+            fn rmw(
+                &self,
+                success: Ordering,
+                failure: Ordering,
+                op: impl FnOnce(&atomic::$name) -> Result<$value, $value>,
+            ) -> Result<$value, $value> {
+                let mut clock = self.clock.lock().unwrap();
+                let result = op(&self.value);
+                let order = if result.is_ok() { success } else { failure };
+                acquire(&clock, order);
+                if result.is_ok() {
+                    clock.join(&release(order));
+                }
+                result
+            }
+
+            // &mut self: nobody else can have access, so there's nothing to synchronize with
+            pub fn get_mut(&mut self) -> &mut $value {
+                self.value.get_mut()
+            }
+
+            pub fn into_inner(self) -> $value {
+                self.value.into_inner()
+            }
+
+            // for the futex syscalls, which only need the address
+            pub const fn as_ptr(&self) -> *mut $value {
+                self.value.as_ptr()
+            }
+
+            pub fn swap(&self, v: $value, order: Ordering) -> $value {
+                self.rmw(order, order, |a| Ok(a.swap(v, order))).unwrap()
+            }
+
+            pub fn compare_exchange(
+                &self,
+                current: $value,
+                new: $value,
+                success: Ordering,
+                failure: Ordering,
+            ) -> Result<$value, $value> {
+                self.rmw(success, failure, |a| a.compare_exchange(current, new, success, failure))
+            }
+
+            pub fn compare_exchange_weak(
+                &self,
+                current: $value,
+                new: $value,
+                success: Ordering,
+                failure: Ordering,
+            ) -> Result<$value, $value> {
+                self.rmw(success, failure, |a| {
+                    a.compare_exchange_weak(current, new, success, failure)
+                })
+            }
+        }
+    )*};
+}
+
+macro_rules! atomic_int {
+    ($($name:ident($value:ty)),*) => {$(
+        impl $name {
+            pub fn fetch_add(&self, v: $value, order: Ordering) -> $value {
+                self.rmw(order, order, |a| Ok(a.fetch_add(v, order))).unwrap()
+            }
+
+            pub fn fetch_sub(&self, v: $value, order: Ordering) -> $value {
+                self.rmw(order, order, |a| Ok(a.fetch_sub(v, order))).unwrap()
+            }
+
+            pub fn fetch_and(&self, v: $value, order: Ordering) -> $value {
+                self.rmw(order, order, |a| Ok(a.fetch_and(v, order))).unwrap()
+            }
+
+            pub fn fetch_or(&self, v: $value, order: Ordering) -> $value {
+                self.rmw(order, order, |a| Ok(a.fetch_or(v, order))).unwrap()
+            }
+        }
+    )*};
+}
+
+atomic!(
+    AtomicBool(bool),
+    AtomicU8(u8),
+    AtomicU32(u32),
+    AtomicU64(u64),
+    AtomicUsize(usize)
+);
+atomic_int!(
+    AtomicU8(u8),
+    AtomicU32(u32),
+    AtomicU64(u64),
+    AtomicUsize(usize)
+);
+
+// AtomicPtr can't go through the macros, for its T.
+pub struct AtomicPtr<T> {
+    value: atomic::AtomicPtr<T>,
+    clock: StdMutex<VectorClock>,
+}
+
+impl<T> AtomicPtr<T> {
+    pub const fn new(p: *mut T) -> Self {
+        Self {
+            value: atomic::AtomicPtr::new(p),
+            clock: StdMutex::new(VectorClock(Vec::new())),
+        }
+    }
+
+    pub fn load(&self, order: Ordering) -> *mut T {
+        let clock = self.clock.lock().unwrap();
+        let p = self.value.load(order);
+        acquire(&clock, order);
+        p
+    }
+
+    pub fn store(&self, p: *mut T, order: Ordering) {
+        let mut clock = self.clock.lock().unwrap();
+        self.value.store(p, order);
+        *clock = release(order);
+    }
+
+    pub fn compare_exchange(
+        &self,
+        current: *mut T,
+        new: *mut T,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<*mut T, *mut T> {
+        let mut clock = self.clock.lock().unwrap();
+        let result = self.value.compare_exchange(current, new, success, failure);
+        acquire(&clock, if result.is_ok() { success } else { failure });
+        if result.is_ok() {
+            clock.join(&release(success));
+        }
+        result
+    }
+
+    pub fn get_mut(&mut self) -> &mut *mut T {
+        self.value.get_mut()
+    }
+}
+
+// std's Mutex, with the clock of the last unlock.
+#[derive(Default)]
+pub struct Mutex<T> {
+    inner: StdMutex<T>,
+    clock: StdMutex<VectorClock>,
+}
+
+pub struct MutexGuard<'a, T> {
+    // an Option so Drop can unlock it after leaving the clock behind
+    guard: Option<StdMutexGuard<'a, T>>,
+    clock: &'a StdMutex<VectorClock>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: StdMutex::new(value),
+            clock: StdMutex::new(VectorClock(Vec::new())),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let guard = self.inner.lock().unwrap();
+        acquire(&self.clock.lock().unwrap(), Ordering::Acquire);
+        MutexGuard {
+            guard: Some(guard),
+            clock: &self.clock,
+        }
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        *self.clock.lock().unwrap() = release(Ordering::Release);
+        self.guard.take();
+    }
+}
+
+// The new thread starts out knowing everything the spawning thread did so far, in the same session.
+fn fork() -> (VectorClock, usize) {
+    with_thread(|t| (t.release(), t.session))
+}
+
+fn start((clock, session): (VectorClock, usize)) {
+    THREAD.with(|t| *t.borrow_mut() = Some(register(clock, session)));
+}
+
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let clock = fork();
+    JoinHandle(thread::spawn(move || {
+        start(clock);
+        let result = f();
+        (result, release(Ordering::Release))
+    }))
+}
+
+pub struct JoinHandle<T>(thread::JoinHandle<(T, VectorClock)>);
+
+impl<T> JoinHandle<T> {
+    pub fn join(self) -> thread::Result<T> {
+        let (result, clock) = self.0.join()?;
+        acquire(&clock, Ordering::Acquire);
+        Ok(result)
+    }
+}
+
+pub fn spawn_scoped<'scope, F, T>(
+    scope: &'scope thread::Scope<'scope, '_>,
+    f: F,
+) -> ScopedJoinHandle<'scope, T>
+where
+    F: FnOnce() -> T + Send + 'scope,
+    T: Send + 'scope,
+{
+    let clock = fork();
+    ScopedJoinHandle(scope.spawn(move || {
+        start(clock);
+        let result = f();
+        (result, release(Ordering::Release))
+    }))
+}
+
+// Has to be joined explicitly: the implicit join at the end of the scope doesn't pass the clock back.
+pub struct ScopedJoinHandle<'scope, T>(thread::ScopedJoinHandle<'scope, (T, VectorClock)>);
+
+impl<T> ScopedJoinHandle<'_, T> {
+    pub fn join(self) -> thread::Result<T> {
+        let (result, clock) = self.0.join()?;
+        acquire(&clock, Ordering::Acquire);
+        Ok(result)
+    }
+}
+
+/*
+  ch_4_spin_lock's SpinLock, on RaceCell and the instrumented AtomicBool,
+  with the orderings as parameters, to see what goes wrong without Acquire and Release.
+  The real one runs on them too with `--features race_detector`, see `chapters`.
+*/
+struct SpinLock<T> {
+    locked: AtomicBool,
+    value: RaceCell<T>,
+    lock_order: Ordering,
+    unlock_order: Ordering,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    fn new(value: T, lock_order: Ordering, unlock_order: Ordering) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: RaceCell::new(value),
+            lock_order,
+            unlock_order,
+        }
+    }
+
+    fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        while self.locked.swap(true, self.lock_order) {
+            std::hint::spin_loop();
+        }
+        // Safety: the swap above gives us exclusive access. With the wrong orderings, not exclusive enough.
+        let result = self.value.with_mut(|v| f(unsafe { &mut *v }));
+        self.locked.store(false, self.unlock_order);
+        result
+    }
+}
+
+// Every thread counts under the lock, and joining makes the total visible. Returns the total.
+fn count_under_spin_lock(
+    threads: usize,
+    iterations: usize,
+    lock: Ordering,
+    unlock: Ordering,
+) -> usize {
+    let counter = SpinLock::new(0, lock, unlock);
+    thread::scope(|s| {
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                spawn_scoped(s, || {
+                    for _ in 0..iterations {
+                        counter.with_lock(|n| *n += 1);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    });
+    counter.with_lock(|n| *n)
+}
+
+// The oneshot channel's handoff: write the message, then set the ready flag. The receiver waits for the flag, then reads.
+fn handoff(ready: Ordering, check: Ordering) -> u64 {
+    let message = RaceCell::new(0);
+    let flag = AtomicBool::new(false);
+    struct Shared<'a>(&'a RaceCell<u64>, &'a AtomicBool);
+    // Safety: the flag makes sure only one thread touches the message at a time
+    unsafe impl Sync for Shared<'_> {}
+    let shared = Shared(&message, &flag);
+    thread::scope(|s| {
+        let shared = &shared;
+        spawn_scoped(s, move || {
+            shared.0.with_mut(|m| unsafe { *m = 42 });
+            shared.1.store(true, ready);
+        });
+        while !shared.1.load(check) {
+            std::hint::spin_loop();
+        }
+        shared.0.with(|m| unsafe { *m })
+    })
+}
+
+// A Mutex orders everything done under it, whichever thread gets it first,
+// and after joining, everything the thread did happened before.
+fn count_under_mutex() -> i32 {
+    let lock = Mutex::new(());
+    let cell = RaceCell::new(0);
+    struct Shared<'a>(&'a Mutex<()>, &'a RaceCell<i32>);
+    // Safety: the cell is only touched while holding the lock
+    unsafe impl Sync for Shared<'_> {}
+    let shared = Shared(&lock, &cell);
+    thread::scope(|s| {
+        let shared = &shared;
+        let t = spawn_scoped(s, move || {
+            let _guard = shared.0.lock();
+            shared.1.with_mut(|c| unsafe { *c += 1 });
+        });
+        {
+            let _guard = shared.0.lock();
+            shared.1.with_mut(|c| unsafe { *c += 1 });
+        }
+        t.join().unwrap();
+    });
+    cell.with(|c| unsafe { *c })
+}
+
+/*
+  The chapters' own types, which crate::sync builds on RaceCell and the instrumented atomics with this feature,
+  each under its own `detect`. None of these should ever race.
+*/
+#[cfg(feature = "race_detector")]
+fn chapters(threads: usize, iterations: usize) -> Vec<(&'static str, Vec<Race>)> {
+    use crate::{
+        ch_2_atomics::{
+            lazy_cell::{Blocking, LazyCell, Racy},
+            race_once_box::RaceOnceBox,
+        },
+        ch_4_spin_lock::SpinLock,
+        ch_5_channels::{bounded, oneshot},
+        ch_6_arc::our_arc,
+    };
+
+    // `f` on every thread at once, joined so whatever they did is visible afterwards
+    fn on_threads<F: Fn() + Sync>(threads: usize, f: F) {
+        thread::scope(|s| {
+            let handles: Vec<_> = (0..threads).map(|_| spawn_scoped(s, &f)).collect();
+            for handle in handles {
+                handle.join().unwrap();
+            }
+        });
+    }
+
+    let mut found = Vec::new();
+
+    found.push(("ch_4_spin_lock::SpinLock", {
+        let lock = SpinLock::new(0);
+        detect(|| {
+            on_threads(threads, || {
+                for _ in 0..iterations {
+                    *lock.lock() += 1;
+                }
+            });
+            *lock.lock()
+        })
+        .1
+    }));
+
+    // every thread gets the value, whoever wrote it
+    found.push(("ch_2_atomics::lazy_cell::LazyCell<Blocking>", {
+        let cell = LazyCell::<Vec<u64>, Blocking>::new();
+        detect(|| on_threads(threads, || assert_eq!(cell.get_or_init(|| vec![12])[0], 12))).1
+    }));
+    found.push(("ch_2_atomics::lazy_cell::LazyCell<Racy>", {
+        let cell = LazyCell::<Vec<u64>, Racy>::new();
+        detect(|| on_threads(threads, || assert_eq!(cell.get_or_init(|| vec![12])[0], 12))).1
+    }));
+    // the value itself isn't a RaceCell, but the box is read on every thread and dropped on the last one
+    found.push(("ch_2_atomics::race_once_box::RaceOnceBox", {
+        let cell = RaceOnceBox::new();
+        detect(|| on_threads(threads, || assert_eq!(cell.get_or_init(|| vec![12])[0], 12))).1
+    }));
+
+    #[cfg(target_os = "linux")]
+    {
+        use crate::ch_9_locks::{mutex::Mutex, rwlock::RwLock};
+
+        found.push(("ch_9_locks::mutex::Mutex", {
+            let mutex = Mutex::new(0);
+            detect(|| {
+                on_threads(threads, || {
+                    for _ in 0..iterations {
+                        *mutex.lock().unwrap() += 1;
+                    }
+                })
+            })
+            .1
+        }));
+
+        found.push(("ch_9_locks::rwlock::RwLock", {
+            let rwlock = RwLock::new(0);
+            detect(|| {
+                on_threads(threads, || {
+                    for _ in 0..iterations {
+                        // readers and writers both touch the value
+                        let before = *rwlock.read();
+                        let mut n = rwlock.write();
+                        *n = before.max(*n) + 1;
+                    }
+                })
+            })
+            .1
+        }));
+    }
+
+    // the message is written on one thread and read on another
+    found.push(("ch_5_channels::oneshot", {
+        detect(|| {
+            let (sender, receiver) = oneshot::channel();
+            let t = spawn(move || sender.send(vec![1, 2, 3]));
+            let received = receiver.recv();
+            t.join().unwrap();
+            received
+        })
+        .1
+    }));
+
+    // every slot is written by a sender and read by a receiver, lap after lap
+    found.push(("ch_5_channels::bounded", {
+        detect(|| {
+            let (sender, receiver) = bounded::channel(2);
+            thread::scope(|s| {
+                let producers: Vec<_> = (0..threads)
+                    .map(|_| {
+                        let sender = sender.clone();
+                        spawn_scoped(s, move || {
+                            for i in 0..iterations {
+                                sender.send(vec![i]).unwrap();
+                            }
+                        })
+                    })
+                    .collect();
+                drop(sender);
+                let consumers: Vec<_> = (0..threads)
+                    .map(|_| {
+                        let receiver = receiver.clone();
+                        spawn_scoped(s, move || while receiver.recv().is_ok() {})
+                    })
+                    .collect();
+                for handle in producers.into_iter().chain(consumers) {
+                    handle.join().unwrap();
+                }
+            });
+        })
+        .1
+    }));
+
+    // every thread reads through its own clone, and whichever drops the last one drops the value,
+    // after the fence(Acquire) that orders it after all of those reads
+    found.push(("ch_6_arc::our_arc::Arc", {
+        detect(|| {
+            let shared = our_arc::Arc::new(vec![0; 16]);
+            thread::scope(|s| {
+                let handles: Vec<_> = (0..threads)
+                    .map(|_| {
+                        let mine = shared.clone();
+                        spawn_scoped(s, move || {
+                            for _ in 0..iterations {
+                                let clone = mine.clone();
+                                assert_eq!(clone.len(), 16);
+                            }
+                        })
+                    })
+                    .collect();
+                drop(shared);
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+            });
+        })
+        .1
+    }));
+
+    found
+}
+
+fn report(name: &str, races: &[Race]) {
+    match races.first() {
+        None => println!("{name}: no races"),
+        Some(race) => println!("{name}: {} found, the first: {race}", races.len()),
+    }
+}
+
+pub fn race_detector_demo(threads: usize, iterations: usize) {
+    if !cfg!(debug_assertions) {
+        println!("RaceCell only checks with debug assertions on");
+        return;
+    }
+    let threads = threads.max(2);
+
+    // the swap still makes the Relaxed one mutually exclusive, so the count is right on every run. It's still a race.
+    for (name, lock, unlock) in [
+        (
+            "SpinLock, Acquire/Release",
+            Ordering::Acquire,
+            Ordering::Release,
+        ),
+        ("SpinLock, Relaxed", Ordering::Relaxed, Ordering::Relaxed),
+    ] {
+        let (count, races) = detect(|| count_under_spin_lock(threads, iterations, lock, unlock));
+        report(&format!("{name}, counted to {count}"), &races);
+    }
+
+    // Release on one side isn't enough
+    for (name, ready, check) in [
+        (
+            "handoff, Release/Acquire",
+            Ordering::Release,
+            Ordering::Acquire,
+        ),
+        (
+            "handoff, Release/Relaxed",
+            Ordering::Release,
+            Ordering::Relaxed,
+        ),
+    ] {
+        let (message, races) = detect(|| handoff(ready, check));
+        report(&format!("{name}, received {message}"), &races);
+    }
+
+    let (count, races) = detect(count_under_mutex);
+    report(&format!("Mutex, counted to {count}"), &races);
+
+    #[cfg(feature = "race_detector")]
+    for (name, races) in chapters(threads, iterations) {
+        report(name, &races);
+    }
+    #[cfg(not(feature = "race_detector"))]
+    println!("the chapters' own types only run on RaceCell with --features race_detector");
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use super::*;
+
+    fn no_races(races: &[Race]) {
+        assert!(
+            races.is_empty(),
+            "{} races, the first: {}",
+            races.len(),
+            races[0]
+        );
+    }
+
+    // the one race a broken handoff has: the write of the message, and the read on the other thread
+    fn one_handoff_race(races: &[Race]) {
+        assert_eq!(races.len(), 1, "{races:?}");
+        assert_eq!(
+            (races[0].earlier.kind, races[0].later.kind),
+            (AccessKind::Write, AccessKind::Read)
+        );
+        assert_ne!(races[0].earlier.thread, races[0].later.thread);
+    }
+
+    #[test]
+    fn vector_clocks() {
+        let mut a = VectorClock::default();
+        a.set(2, 5);
+        assert_eq!((a.get(0), a.get(2), a.get(7)), (0, 5, 0));
+        let mut b = VectorClock::default();
+        b.set(0, 3);
+        b.set(2, 1);
+        b.set(4, 1);
+        a.join(&b);
+        assert_eq!(a, VectorClock(vec![3, 0, 5, 0, 1]));
+        // joining something older changes nothing
+        let before = a.clone();
+        a.join(&VectorClock(vec![1, 0, 2]));
+        assert_eq!(a, before);
+    }
+
+    #[test]
+    fn acquire_release_spin_lock() {
+        let (count, races) =
+            detect(|| count_under_spin_lock(4, 200, Ordering::Acquire, Ordering::Release));
+        assert_eq!(count, 800);
+        no_races(&races);
+    }
+
+    #[test]
+    fn relaxed_spin_lock_races() {
+        let (count, races) =
+            detect(|| count_under_spin_lock(4, 200, Ordering::Relaxed, Ordering::Relaxed));
+        // the swap still makes it mutually exclusive
+        assert_eq!(count, 800);
+        assert!(!races.is_empty());
+        assert!(races
+            .iter()
+            .all(|r| r.earlier.kind == AccessKind::Write && r.later.kind == AccessKind::Write));
+        assert!(races.iter().all(|r| r.earlier.thread != r.later.thread));
+    }
+
+    #[test]
+    fn handoffs() {
+        let (message, races) = detect(|| handoff(Ordering::Release, Ordering::Acquire));
+        assert_eq!(message, 42);
+        no_races(&races);
+        // Release on one side isn't enough
+        for (ready, check) in [
+            (Ordering::Release, Ordering::Relaxed),
+            (Ordering::Relaxed, Ordering::Acquire),
+        ] {
+            let (message, races) = detect(|| handoff(ready, check));
+            assert_eq!(message, 42);
+            one_handoff_race(&races);
+        }
+    }
+
+    // handoff with Relaxed flag accesses, and a fence on either side or both
+    fn fenced_handoff(release_fence: bool, acquire_fence: bool) -> Vec<Race> {
+        let message = RaceCell::new(0);
+        let flag = AtomicBool::new(false);
+        struct Shared<'a>(&'a RaceCell<u64>, &'a AtomicBool);
+        unsafe impl Sync for Shared<'_> {}
+        let shared = Shared(&message, &flag);
+        detect(|| {
+            thread::scope(|s| {
+                let shared = &shared;
+                spawn_scoped(s, move || {
+                    shared.0.with_mut(|m| unsafe { *m = 42 });
+                    if release_fence {
+                        fence(Ordering::Release);
+                    }
+                    shared.1.store(true, Ordering::Relaxed);
+                });
+                while !shared.1.load(Ordering::Relaxed) {
+                    std::hint::spin_loop();
+                }
+                if acquire_fence {
+                    fence(Ordering::Acquire);
+                }
+                assert_eq!(shared.0.with(|m| unsafe { *m }), 42);
+            })
+        })
+        .1
+    }
+
+    #[test]
+    fn fence_pairs() {
+        no_races(&fenced_handoff(true, true));
+        one_handoff_race(&fenced_handoff(true, false));
+        one_handoff_race(&fenced_handoff(false, true));
+        one_handoff_race(&fenced_handoff(false, false));
+    }
+
+    #[test]
+    fn a_store_after_the_fence_release_is_not_covered() {
+        // the fence(Release) only hands out what came before it
+        let message = RaceCell::new(0);
+        let flag = AtomicBool::new(false);
+        struct Shared<'a>(&'a RaceCell<u64>, &'a AtomicBool);
+        unsafe impl Sync for Shared<'_> {}
+        let shared = Shared(&message, &flag);
+        let (_, races) = detect(|| {
+            thread::scope(|s| {
+                let shared = &shared;
+                spawn_scoped(s, move || {
+                    fence(Ordering::Release);
+                    shared.0.with_mut(|m| unsafe { *m = 42 });
+                    shared.1.store(true, Ordering::Relaxed);
+                });
+                while !shared.1.load(Ordering::Relaxed) {
+                    std::hint::spin_loop();
+                }
+                fence(Ordering::Acquire);
+                shared.0.with(|m| unsafe { *m })
+            })
+        });
+        one_handoff_race(&races);
+    }
+
+    #[test]
+    fn mutex_and_join() {
+        let (count, races) = detect(count_under_mutex);
+        assert_eq!(count, 2);
+        no_races(&races);
+    }
+
+    #[test]
+    fn threads_not_started_by_spawn_know_nothing() {
+        let cell = RaceCell::new(0);
+        struct Shared<'a>(&'a RaceCell<i32>);
+        unsafe impl Sync for Shared<'_> {}
+        let shared = Shared(&cell);
+        let (_, races) = detect(|| {
+            shared.0.with_mut(|c| unsafe { *c = 1 });
+            let shared = &shared;
+            thread::scope(|s| {
+                // this one inherits our clock and session
+                spawn_scoped(s, move || shared.0.with(|c| unsafe { *c }))
+                    .join()
+                    .unwrap();
+            });
+        });
+        no_races(&races);
+
+        // and a plain std thread neither, so its race lands outside any session
+        let (_, races) = detect(|| {
+            let shared = &shared;
+            thread::scope(|s| {
+                s.spawn(move || shared.0.with(|c| unsafe { *c }));
+            })
+        });
+        no_races(&races);
+        let outside = take_races();
+        assert!(outside
+            .iter()
+            .any(|r| r.earlier.kind == AccessKind::Write && r.later.kind == AccessKind::Read));
+    }
+
+    #[test]
+    fn sessions_keep_their_races_apart() {
+        let (inner, outer) = detect(|| {
+            let racy = detect(|| handoff(Ordering::Relaxed, Ordering::Relaxed)).1;
+            handoff(Ordering::Release, Ordering::Acquire);
+            racy
+        });
+        one_handoff_race(&inner);
+        no_races(&outer);
+    }
+
+    #[cfg(feature = "race_detector")]
+    #[test]
+    fn chapters_never_race() {
+        for (name, races) in chapters(4, 100) {
+            assert!(races.is_empty(), "{name}: {}", races[0]);
+        }
+    }
+}
@@ -0,0 +1,825 @@
+use super::explorer::{self, Config};
+use std::{
+    collections::{BTreeSet, VecDeque},
+    fmt::Debug,
+    sync::{atomic::Ordering, Arc, Mutex as StdMutex, PoisonError},
+    thread,
+};
+
+// https://marabos.nl/atomics/memory-ordering.html
+// https://marabos.nl/atomics/hardware.html#reordering
+
+/*
+  The litmus tests in ch_3_memory_ordering only show what this machine does, and x86 never shows most of
+  what Relaxed allows. This enumerates what's allowed instead: small programs run against simulated atomics,
+  on top of the explorer, which tries every interleaving and every choice the memory model leaves open.
+
+  Two models:
+  - X86: one main memory, and a FIFO store buffer per thread. Stores go into the buffer, and drain into memory
+    at any moment, oldest first. Loads see their own thread's newest buffered store, or memory.
+    Orderings don't matter, except that a SeqCst store is an xchg, which (like every RMW) drains the buffer first.
+    So x86 only ever reorders a store with a later load.
+  - Cpp20: the view based model. Every location keeps all the values ever stored to it as messages,
+    ordered by timestamp (that's its modification order). Every thread has a view: per location, the
+    timestamp of the latest message it knows about. It can read any message at or after its view, and stores
+    go anywhere after it, as long as that doesn't come between an RMW and the message it read.
+    - A Release store gives its message the thread's whole view, and an Acquire load that reads it takes that view over.
+      That's the happens-before relation, as data. A Relaxed one only carries its own location.
+    - An RMW passes the view of the message it read on, so it continues a release sequence.
+    - SeqCst accesses also sync with one global view. That's a bit stronger than C++20 when SeqCst and
+      weaker accesses to the same location are mixed, and exactly right when they aren't.
+    Like most operational models it has no promises, so it can't produce load buffering (LB), which C++ allows for Relaxed.
+
+  Spawning and joining a thread synchronize, like the real thing.
+  The simulated atomics are only usable inside `outcomes`. There's one simulated memory,
+  so like explorations, simulations take turns: one started while another runs waits for it.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    X86,
+    Cpp20,
+}
+
+// per location, a timestamp. Missing ones are 0, the initial message.
+type View = Vec<u64>;
+
+fn join(view: &mut View, other: &View) {
+    if view.len() < other.len() {
+        view.resize(other.len(), 0);
+    }
+    for (a, b) in view.iter_mut().zip(other) {
+        *a = (*a).max(*b);
+    }
+}
+
+fn at(view: &View, location: usize) -> u64 {
+    view.get(location).copied().unwrap_or(0)
+}
+
+fn set(view: &mut View, location: usize, timestamp: u64) {
+    if view.len() <= location {
+        view.resize(location + 1, 0);
+    }
+    view[location] = timestamp;
+}
+
+// room between two messages, so a store can always be put between two others
+const GAP: u64 = 1 << 32;
+
+struct Message {
+    timestamp: u64,
+    value: u64,
+    view: View,
+    // written by an RMW that read the message right before it, so nothing can be put between the two
+    rmw: bool,
+}
+
+#[derive(Default)]
+struct ThreadState {
+    view: View,
+    buffer: VecDeque<(usize, u64)>,
+}
+
+struct Memory {
+    model: Model,
+    // X86: the main memory
+    values: Vec<u64>,
+    // Cpp20: every location's messages, by timestamp
+    histories: Vec<Vec<Message>>,
+    // the global view of the SeqCst accesses
+    sc: View,
+    threads: Vec<ThreadState>,
+}
+
+fn is_acquire(order: Ordering) -> bool {
+    matches!(
+        order,
+        Ordering::Acquire | Ordering::AcqRel | Ordering::SeqCst
+    )
+}
+
+fn is_release(order: Ordering) -> bool {
+    matches!(
+        order,
+        Ordering::Release | Ordering::AcqRel | Ordering::SeqCst
+    )
+}
+
+impl Memory {
+    fn new(model: Model) -> Self {
+        Self {
+            model,
+            values: Vec::new(),
+            histories: Vec::new(),
+            sc: View::new(),
+            threads: Vec::new(),
+        }
+    }
+
+    fn thread(&mut self, t: usize) -> &mut ThreadState {
+        if self.threads.len() <= t {
+            self.threads.resize_with(t + 1, ThreadState::default);
+        }
+        &mut self.threads[t]
+    }
+
+    fn location(&mut self, value: u64) -> usize {
+        self.values.push(value);
+        self.histories.push(vec![Message {
+            timestamp: 0,
+            value,
+            view: View::new(),
+            rmw: false,
+        }]);
+        self.values.len() - 1
+    }
+
+    // X86: the store buffers drain whenever they like, so before anything reads memory, any of them may have.
+    fn drain_some(&mut self) {
+        loop {
+            let buffered: Vec<usize> = (0..self.threads.len())
+                .filter(|&t| !self.threads[t].buffer.is_empty())
+                .collect();
+            match explorer::choose(buffered.len() + 1) {
+                0 => return,
+                i => self.drain_one(buffered[i - 1]),
+            }
+        }
+    }
+
+    fn drain_one(&mut self, t: usize) {
+        if let Some((location, value)) = self.thread(t).buffer.pop_front() {
+            self.values[location] = value;
+        }
+    }
+
+    fn drain_all(&mut self, t: usize) {
+        while !self.thread(t).buffer.is_empty() {
+            self.drain_one(t);
+        }
+    }
+
+    fn sc_before(&mut self, t: usize, order: Ordering) {
+        if order == Ordering::SeqCst {
+            let sc = self.sc.clone();
+            join(&mut self.thread(t).view, &sc);
+        }
+    }
+
+    fn sc_after(&mut self, t: usize, order: Ordering) {
+        if order == Ordering::SeqCst {
+            let view = self.thread(t).view.clone();
+            join(&mut self.sc, &view);
+        }
+    }
+
+    // Reads message `i`: the view moves up to it, and an acquire takes over the message's view.
+    fn read(&mut self, t: usize, location: usize, i: usize, order: Ordering) -> u64 {
+        let message = &self.histories[location][i];
+        let (timestamp, value, view) = (
+            message.timestamp,
+            message.value,
+            is_acquire(order).then(|| message.view.clone()),
+        );
+        let thread = self.thread(t);
+        set(&mut thread.view, location, timestamp);
+        if let Some(view) = view {
+            join(&mut thread.view, &view);
+        }
+        value
+    }
+
+    // Puts a new message right after message `i`. `carried` is the view an RMW passes on.
+    fn write(
+        &mut self,
+        t: usize,
+        location: usize,
+        i: usize,
+        value: u64,
+        order: Ordering,
+        carried: Option<View>,
+    ) {
+        let history = &self.histories[location];
+        let after = history[i].timestamp;
+        let before = history.get(i + 1).map_or(after + 2 * GAP, |m| m.timestamp);
+        let timestamp = after + (before - after) / 2;
+        assert!(timestamp > after, "out of timestamps");
+
+        let thread = self.thread(t);
+        set(&mut thread.view, location, timestamp);
+        let mut view = if is_release(order) {
+            thread.view.clone()
+        } else {
+            let mut own = View::new();
+            set(&mut own, location, timestamp);
+            own
+        };
+        let rmw = carried.is_some();
+        if let Some(carried) = carried {
+            join(&mut view, &carried);
+        }
+        self.histories[location].insert(
+            i + 1,
+            Message {
+                timestamp,
+                value,
+                view,
+                rmw,
+            },
+        );
+    }
+
+    // the messages `t` may read
+    fn readable(&mut self, t: usize, location: usize) -> Vec<usize> {
+        let seen = at(&self.thread(t).view, location);
+        let history = &self.histories[location];
+        (0..history.len())
+            .filter(|&i| history[i].timestamp >= seen)
+            .collect()
+    }
+
+    // whether a store can go right after message `i`
+    fn open(&self, location: usize, i: usize) -> bool {
+        self.histories[location]
+            .get(i + 1)
+            .is_none_or(|next| !next.rmw)
+    }
+
+    fn load(&mut self, t: usize, location: usize, order: Ordering) -> u64 {
+        match self.model {
+            Model::X86 => {
+                self.drain_some();
+                let buffered = self
+                    .thread(t)
+                    .buffer
+                    .iter()
+                    .rev()
+                    .find(|(l, _)| *l == location);
+                match buffered {
+                    Some(&(_, value)) => value,
+                    None => self.values[location],
+                }
+            }
+            Model::Cpp20 => {
+                self.sc_before(t, order);
+                let readable = self.readable(t, location);
+                let i = readable[explorer::choose(readable.len())];
+                let value = self.read(t, location, i, order);
+                self.sc_after(t, order);
+                value
+            }
+        }
+    }
+
+    fn store(&mut self, t: usize, location: usize, value: u64, order: Ordering) {
+        match self.model {
+            Model::X86 => {
+                self.thread(t).buffer.push_back((location, value));
+                // an xchg
+                if order == Ordering::SeqCst {
+                    self.drain_all(t);
+                }
+            }
+            Model::Cpp20 => {
+                self.sc_before(t, order);
+                let open: Vec<usize> = self
+                    .readable(t, location)
+                    .into_iter()
+                    .filter(|&i| self.open(location, i))
+                    .collect();
+                let i = open[explorer::choose(open.len())];
+                self.write(t, location, i, value, order, None);
+                self.sc_after(t, order);
+            }
+        }
+    }
+
+    // One read-modify-write: `f` gives the new value, or None to fail (and then it's just a load).
+    fn rmw(
+        &mut self,
+        t: usize,
+        location: usize,
+        success: Ordering,
+        failure: Ordering,
+        f: impl Fn(u64) -> Option<u64>,
+    ) -> Result<u64, u64> {
+        match self.model {
+            Model::X86 => {
+                // a locked instruction: the buffer drains first
+                self.drain_some();
+                self.drain_all(t);
+                let value = self.values[location];
+                let new = f(value).ok_or(value)?;
+                self.values[location] = new;
+                Ok(value)
+            }
+            Model::Cpp20 => {
+                let sc = if success == Ordering::SeqCst {
+                    success
+                } else {
+                    failure
+                };
+                self.sc_before(t, sc);
+                // a successful one can only read a message that nothing has been put right after yet
+                let readable: Vec<usize> = self
+                    .readable(t, location)
+                    .into_iter()
+                    .filter(|&i| {
+                        f(self.histories[location][i].value).is_none() || self.open(location, i)
+                    })
+                    .collect();
+                let i = readable[explorer::choose(readable.len())];
+                let read = &self.histories[location][i];
+                let result = match f(read.value) {
+                    Some(new) => {
+                        let carried = read.view.clone();
+                        let value = self.read(t, location, i, success);
+                        self.write(t, location, i, new, success, Some(carried));
+                        Ok(value)
+                    }
+                    None => Err(self.read(t, location, i, failure)),
+                };
+                self.sc_after(t, sc);
+                result
+            }
+        }
+    }
+
+    // what a new thread starts with: everything its parent did
+    fn spawn(&mut self, parent: usize) -> View {
+        self.drain_all(parent);
+        self.thread(parent).view.clone()
+    }
+
+    fn start(&mut self, t: usize, view: View) {
+        *self.thread(t) = ThreadState {
+            view,
+            buffer: VecDeque::new(),
+        };
+    }
+
+    // what joining a thread gives the joiner
+    fn exit(&mut self, t: usize) -> View {
+        self.drain_all(t);
+        self.thread(t).view.clone()
+    }
+}
+
+static MEMORY: StdMutex<Option<Memory>> = StdMutex::new(None);
+// held for a whole simulation, so a second one can't replace or clear MEMORY under it
+static SIMULATING: StdMutex<()> = StdMutex::new(());
+
+fn with_memory<R>(f: impl FnOnce(&mut Memory, usize) -> R) -> R {
+    let mut memory = MEMORY.lock().unwrap();
+    if memory.is_none() {
+        // without poisoning MEMORY for the next simulation
+        drop(memory);
+        panic!("simulated atomics only work inside weak_memory::outcomes");
+    }
+    f(memory.as_mut().unwrap(), explorer::current_thread())
+}
+
+// An operation on the simulated memory, at its own yield point.
+fn step<R>(f: impl FnOnce(&mut Memory, usize) -> R) -> R {
+    explorer::yield_point();
+    with_memory(f)
+}
+
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let view = with_memory(|memory, me| memory.spawn(me));
+    JoinHandle(explorer::spawn(move || {
+        with_memory(|memory, me| memory.start(me, view));
+        let result = f();
+        (result, with_memory(|memory, me| memory.exit(me)))
+    }))
+}
+
+pub struct JoinHandle<T>(explorer::JoinHandle<(T, View)>);
+
+impl<T> JoinHandle<T> {
+    pub fn join(self) -> thread::Result<T> {
+        let (result, view) = self.0.join()?;
+        with_memory(|memory, me| join(&mut memory.thread(me).view, &view));
+        Ok(result)
+    }
+}
+
+macro_rules! atomic_int {
+    ($($name:ident($value:ty)),*) => {$(
+        pub struct $name {
+            location: usize,
+        }
+
+        impl $name {
+            pub fn new(v: $value) -> Self {
+                Self {
+                    location: with_memory(|memory, _| memory.location(v as u64)),
+                }
+            }
+
+            pub fn load(&self, order: Ordering) -> $value {
+                step(|memory, t| memory.load(t, self.location, order)) as $value
+            }
+
+            pub fn store(&self, v: $value, order: Ordering) {
+                step(|memory, t| memory.store(t, self.location, v as u64, order))
+            }
+
+            fn rmw(
+                &self,
+                success: Ordering,
+                failure: Ordering,
+                f: impl Fn($value) -> Option<$value>,
+            ) -> Result<$value, $value> {
+                step(|memory, t| {
+                    memory.rmw(t, self.location, success, failure, |v| {
+                        f(v as $value).map(|new| new as u64)
+                    })
+                })
+                .map(|v| v as $value)
+                .map_err(|v| v as $value)
+            }
+
+            pub fn swap(&self, v: $value, order: Ordering) -> $value {
+                self.rmw(order, Ordering::Relaxed, |_| Some(v)).unwrap()
+            }
+
+            pub fn fetch_add(&self, v: $value, order: Ordering) -> $value {
+                self.rmw(order, Ordering::Relaxed, |old| Some(old.wrapping_add(v)))
+                    .unwrap()
+            }
+
+            pub fn fetch_sub(&self, v: $value, order: Ordering) -> $value {
+                self.rmw(order, Ordering::Relaxed, |old| Some(old.wrapping_sub(v)))
+                    .unwrap()
+            }
+
+            pub fn compare_exchange(
+                &self,
+                current: $value,
+                new: $value,
+                success: Ordering,
+                failure: Ordering,
+            ) -> Result<$value, $value> {
+                self.rmw(success, failure, |v| (v == current).then_some(new))
+            }
+
+            // never fails spuriously here
+            pub fn compare_exchange_weak(
+                &self,
+                current: $value,
+                new: $value,
+                success: Ordering,
+                failure: Ordering,
+            ) -> Result<$value, $value> {
+                self.compare_exchange(current, new, success, failure)
+            }
+
+            // a load and a compare_exchange loop, like std's
+            pub fn fetch_update(
+                &self,
+                set_order: Ordering,
+                fetch_order: Ordering,
+                mut f: impl FnMut($value) -> Option<$value>,
+            ) -> Result<$value, $value> {
+                let mut prev = self.load(fetch_order);
+                while let Some(next) = f(prev) {
+                    match self.compare_exchange_weak(prev, next, set_order, fetch_order) {
+                        Ok(v) => return Ok(v),
+                        Err(v) => prev = v,
+                    }
+                }
+                Err(prev)
+            }
+        }
+    )*};
+}
+
+atomic_int!(AtomicU32(u32), AtomicU64(u64), AtomicUsize(usize));
+
+pub struct AtomicBool {
+    location: usize,
+}
+
+impl AtomicBool {
+    pub fn new(v: bool) -> Self {
+        Self {
+            location: with_memory(|memory, _| memory.location(v.into())),
+        }
+    }
+
+    pub fn load(&self, order: Ordering) -> bool {
+        step(|memory, t| memory.load(t, self.location, order)) != 0
+    }
+
+    pub fn store(&self, v: bool, order: Ordering) {
+        step(|memory, t| memory.store(t, self.location, v.into(), order))
+    }
+
+    pub fn swap(&self, v: bool, order: Ordering) -> bool {
+        step(|memory, t| {
+            memory.rmw(t, self.location, order, Ordering::Relaxed, |_| {
+                Some(v.into())
+            })
+        })
+        .unwrap()
+            != 0
+    }
+}
+
+// Every result `program` can return under `model`.
+// Except for one gap in Cpp20: it never has a load read a store that comes after it in its own thread's program order,
+// so load buffering (two Relaxed loads each seeing the other thread's later store) is missing,
+// although C++ allows it. weak_memory_demo's LB program keeps that visible.
+pub fn outcomes<R>(model: Model, program: impl Fn() -> R + Send + Sync + 'static) -> BTreeSet<R>
+where
+    R: Ord + Debug + Send + 'static,
+{
+    // a failed simulation poisons it, but it cleared MEMORY first
+    let _simulating = SIMULATING.lock().unwrap_or_else(PoisonError::into_inner);
+    let seen = Arc::new(StdMutex::new(BTreeSet::new()));
+    let results = seen.clone();
+    let config = Config {
+        preemption_bound: usize::MAX,
+        max_executions: 1_000_000,
+    };
+    let explored = explorer::explore(config, move || {
+        *MEMORY.lock().unwrap() = Some(Memory::new(model));
+        let result = program();
+        results.lock().unwrap().insert(result);
+    });
+    *MEMORY.lock().unwrap() = None;
+    let explored = explored.unwrap_or_else(|failure| panic!("{failure}"));
+    assert!(explored.complete, "too many executions to try them all");
+    Arc::try_unwrap(seen).unwrap().into_inner().unwrap()
+}
+
+/*
+  The programs: the atomics from compare_exchange.rs and load_and_store.rs, on simulated atomics.
+*/
+
+// progress_reporting: a worker stores an item's result and counts it, the main thread checks the count, then the result.
+// (done, result) = (1, 0) means it saw the item counted, but not its result.
+fn progress(count: Ordering, check: Ordering) -> (usize, u64) {
+    let state = Arc::new((AtomicU64::new(0), AtomicUsize::new(0)));
+    let worker = {
+        let state = state.clone();
+        spawn(move || {
+            state.0.store(42, Ordering::Relaxed);
+            state.1.fetch_add(1, count);
+        })
+    };
+    let done = state.1.load(check);
+    let result = state.0.load(Ordering::Relaxed);
+    worker.join().unwrap();
+    (done, result)
+}
+
+// Two stop flags, Dekker style: each thread raises its own, then checks the other's.
+// (false, false) means neither saw the other's, so both went ahead.
+fn raise_and_check(order: Ordering) -> (bool, bool) {
+    let flags = Arc::new((AtomicBool::new(false), AtomicBool::new(false)));
+    let other = {
+        let flags = flags.clone();
+        spawn(move || {
+            flags.1.store(true, order);
+            flags.0.load(order)
+        })
+    };
+    flags.0.store(true, order);
+    let mine = flags.1.load(order);
+    (mine, other.join().unwrap())
+}
+
+// Load buffering: each thread loads one flag, then raises the other.
+// (true, true) means both loads saw the other thread's store, which C++ allows for Relaxed (ARM and POWER can do it),
+// but the Cpp20 model can't produce.
+fn load_buffering() -> (bool, bool) {
+    let flags = Arc::new((AtomicBool::new(false), AtomicBool::new(false)));
+    let other = {
+        let flags = flags.clone();
+        spawn(move || {
+            let seen = flags.1.load(Ordering::Relaxed);
+            flags.0.store(true, Ordering::Relaxed);
+            seen
+        })
+    };
+    let mine = flags.0.load(Ordering::Relaxed);
+    flags.1.store(true, Ordering::Relaxed);
+    (mine, other.join().unwrap())
+}
+
+// increment_compare_exchange, from two threads at once
+fn increment_compare_exchange(a: &AtomicU32) {
+    let mut current = a.load(Ordering::Relaxed);
+    loop {
+        let new = current + 1;
+        match a.compare_exchange(current, new, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return,
+            Err(v) => current = v,
+        }
+    }
+}
+
+fn increment_twice() -> u32 {
+    let a = Arc::new(AtomicU32::new(0));
+    let other = {
+        let a = a.clone();
+        spawn(move || increment_compare_exchange(&a))
+    };
+    increment_compare_exchange(&a);
+    other.join().unwrap();
+    a.load(Ordering::Relaxed)
+}
+
+// lazy_one_time_key_initialization, with a fixed key per thread instead of a random one
+fn lazy_one_time_key_initialization(key: &AtomicU64, new_key: u64) -> u64 {
+    let k = key.load(Ordering::Relaxed);
+    if k == 0 {
+        match key.compare_exchange(0, new_key, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => new_key,
+            Err(k) => k,
+        }
+    } else {
+        k
+    }
+}
+
+fn racing_keys() -> (u64, u64) {
+    let key = Arc::new(AtomicU64::new(0));
+    let other = {
+        let key = key.clone();
+        spawn(move || lazy_one_time_key_initialization(&key, 2))
+    };
+    let mine = lazy_one_time_key_initialization(&key, 1);
+    (mine, other.join().unwrap())
+}
+
+// allocate_new_id_fetch_update, with room for two ids
+fn two_ids() -> (u32, u32) {
+    let next_id = Arc::new(AtomicU32::new(0));
+    let allocate = |next_id: &AtomicU32| {
+        next_id
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n < 2).then_some(n + 1)
+            })
+            .expect("too many IDs")
+    };
+    let other = {
+        let next_id = next_id.clone();
+        spawn(move || allocate(&next_id))
+    };
+    (allocate(&next_id), other.join().unwrap())
+}
+
+// Both models' outcomes
+fn both<R>(program: fn() -> R) -> (BTreeSet<R>, BTreeSet<R>)
+where
+    R: Ord + Debug + Send + 'static,
+{
+    (
+        outcomes(Model::Cpp20, program),
+        outcomes(Model::X86, program),
+    )
+}
+
+// side by side. x86 is stronger, so it should never produce anything C++ doesn't allow.
+fn compare<R>(name: &str, program: fn() -> R)
+where
+    R: Ord + Debug + Send + 'static,
+{
+    let (cpp, x86) = both(program);
+    println!("--- {name}");
+    for outcome in cpp.union(&x86) {
+        let mark = match (cpp.contains(outcome), x86.contains(outcome)) {
+            (true, true) => "C++20, x86",
+            (true, false) => "C++20 only",
+            _ => "x86 only, WHICH C++20 DOESN'T ALLOW",
+        };
+        println!("  {outcome:?}  {mark}");
+    }
+}
+
+pub fn weak_memory_demo() {
+    compare("progress, Relaxed counter", || {
+        progress(Ordering::Relaxed, Ordering::Relaxed)
+    });
+    compare("progress, Release/Acquire counter", || {
+        progress(Ordering::Release, Ordering::Acquire)
+    });
+    compare("raise and check, Relaxed", || {
+        raise_and_check(Ordering::Relaxed)
+    });
+    compare("raise and check, SeqCst", || {
+        raise_and_check(Ordering::SeqCst)
+    });
+
+    compare("load buffering, Relaxed", load_buffering);
+    println!("  (true, true)  allowed by C++20, but not produced by this model");
+
+    // atomicity doesn't depend on the ordering: Relaxed is enough for all three
+    compare("increment_compare_exchange twice", increment_twice);
+    compare("lazy_one_time_key_initialization twice", racing_keys);
+    compare("allocate_new_id_fetch_update twice", two_ids);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // both models' outcomes, after checking x86 stays within C++20
+    fn checked_both<R>(program: fn() -> R) -> (BTreeSet<R>, BTreeSet<R>)
+    where
+        R: Ord + Debug + Send + 'static,
+    {
+        let (cpp, x86) = both(program);
+        assert!(
+            x86.is_subset(&cpp),
+            "x86 did something C++ doesn't allow: {x86:?} {cpp:?}"
+        );
+        (cpp, x86)
+    }
+
+    #[test]
+    fn relaxed_counter_can_come_before_the_result() {
+        let (cpp, x86) = checked_both(|| progress(Ordering::Relaxed, Ordering::Relaxed));
+        assert_eq!(cpp, BTreeSet::from([(0, 0), (0, 42), (1, 0), (1, 42)]));
+        // x86 never reorders two stores, or two loads
+        assert!(!x86.contains(&(1, 0)));
+    }
+
+    #[test]
+    fn release_acquire_counter_brings_the_result_along() {
+        let (cpp, _) = checked_both(|| progress(Ordering::Release, Ordering::Acquire));
+        assert!(!cpp.contains(&(1, 0)));
+        assert!(cpp.contains(&(1, 42)));
+    }
+
+    #[test]
+    fn store_buffering() {
+        // even x86 can have both loads pass the stores in the buffers
+        let (cpp, x86) = checked_both(|| raise_and_check(Ordering::Relaxed));
+        assert!(cpp.contains(&(false, false)) && x86.contains(&(false, false)));
+        let (_, x86) = checked_both(|| raise_and_check(Ordering::Release));
+        assert!(
+            x86.contains(&(false, false)),
+            "only SeqCst stores drain the buffer"
+        );
+        let (cpp, x86) = checked_both(|| raise_and_check(Ordering::SeqCst));
+        assert!(!cpp.contains(&(false, false)) && !x86.contains(&(false, false)));
+    }
+
+    #[test]
+    fn no_load_buffering() {
+        let (cpp, x86) = checked_both(load_buffering);
+        // the known gap: (true, true) is allowed by C++, but missing here. If this starts failing, the model has promises now.
+        assert!(!cpp.contains(&(true, true)));
+        assert_eq!(
+            cpp,
+            BTreeSet::from([(false, false), (false, true), (true, false)])
+        );
+        assert_eq!(x86, cpp);
+    }
+
+    #[test]
+    fn read_modify_writes_are_atomic_even_relaxed() {
+        assert_eq!(checked_both(increment_twice).0, BTreeSet::from([2]));
+        assert_eq!(
+            checked_both(racing_keys).0,
+            BTreeSet::from([(1, 1), (2, 2)])
+        );
+        assert_eq!(checked_both(two_ids).0, BTreeSet::from([(0, 1), (1, 0)]));
+    }
+
+    #[test]
+    fn spawn_and_join_synchronize() {
+        let outcomes = outcomes(Model::Cpp20, || {
+            let a = Arc::new(AtomicU32::new(0));
+            a.store(1, Ordering::Relaxed);
+            let t = {
+                let a = a.clone();
+                spawn(move || {
+                    let seen = a.load(Ordering::Relaxed);
+                    a.store(2, Ordering::Relaxed);
+                    seen
+                })
+            };
+            let seen = t.join().unwrap();
+            (seen, a.load(Ordering::Relaxed))
+        });
+        assert_eq!(outcomes, BTreeSet::from([(1, 2)]));
+    }
+
+    #[test]
+    #[should_panic(expected = "simulated atomics only work inside weak_memory::outcomes")]
+    fn outside_a_simulation() {
+        // waits for any simulation that's running, so MEMORY is surely empty
+        let _simulating = SIMULATING.lock().unwrap_or_else(PoisonError::into_inner);
+        AtomicU32::new(0);
+    }
+}